@@ -0,0 +1,68 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use drcov_rs::{Module, Trace};
+
+/// Create `count` adjacent 64KB modules starting at 0x10000.
+fn modules(count: u64) -> Vec<Module> {
+    (0..count)
+        .map(|i| {
+            let base = 0x10000 * (i + 1);
+            Module::new(format!("module{i}.so"), base, base + 0x10000)
+        })
+        .collect()
+}
+
+/// Create a trace over 16 modules with `blocks` entries spread across them.
+fn trace_with_blocks(blocks: u64) -> Trace {
+    let mut trace = Trace::new(&modules(16));
+    for i in 0..blocks {
+        trace.add(0x10000 + (i * 0x1234) % (16 * 0x10000), 16);
+    }
+    trace
+}
+
+fn add(c: &mut Criterion) {
+    let mut group = c.benchmark_group("add");
+    for count in [2, 256] {
+        let modules = modules(count);
+        // The address in the last module is the worst case for the lookup.
+        let address = modules.last().unwrap().base() + 0x100;
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(count),
+            &address,
+            |b, &address| {
+                let mut trace = Trace::new(&modules);
+                b.iter(|| trace.add(black_box(address), 16));
+            },
+        );
+    }
+    group.finish();
+}
+
+fn write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write");
+    group.sample_size(10);
+    for blocks in [1_000, 1_000_000] {
+        let trace = trace_with_blocks(blocks);
+
+        group.bench_with_input(BenchmarkId::from_parameter(blocks), &trace, |b, trace| {
+            b.iter(|| trace.write(&mut std::io::sink()).unwrap());
+        });
+    }
+    group.finish();
+}
+
+fn dedup(c: &mut Criterion) {
+    let trace = trace_with_blocks(100_000);
+
+    c.bench_function("dedup", |b| {
+        b.iter_batched(
+            || trace.clone(),
+            |mut trace| trace.dedup(),
+            criterion::BatchSize::LargeInput,
+        );
+    });
+}
+
+criterion_group!(benches, add, write, dedup);
+criterion_main!(benches);
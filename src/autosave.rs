@@ -0,0 +1,122 @@
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::Trace;
+
+/// A background thread that periodically saves a shared [`Trace`], created by [`Trace::autosave_every`].
+///
+/// Dropping the handle stops the thread and saves a final snapshot, ignoring
+/// errors; use [`AutosaveHandle::stop`] to observe the result.
+#[derive(Debug)]
+pub struct AutosaveHandle {
+    trace: Arc<Mutex<Trace>>,
+    path: PathBuf,
+    /// Wakes the thread to stop it, or None once it has been stopped.
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Trace {
+    /// Save a snapshot of the shared trace to `path` every `interval` on a background thread.
+    ///
+    /// Each snapshot is written to a temporary file next to `path` and then renamed
+    /// over it, so the file always holds a complete trace. The trace is only locked
+    /// while the snapshot is serialized. Errors from periodic saves are ignored.
+    pub fn autosave_every(
+        trace: Arc<Mutex<Trace>>,
+        path: impl Into<PathBuf>,
+        interval: Duration,
+    ) -> AutosaveHandle {
+        let path = path.into();
+        let (stop, wakeup) = mpsc::channel();
+
+        let thread = {
+            let trace = Arc::clone(&trace);
+            let path = path.clone();
+            std::thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = wakeup.recv_timeout(interval) {
+                    let _ = save_snapshot(&trace, &path);
+                }
+            })
+        };
+
+        AutosaveHandle {
+            trace,
+            path,
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl AutosaveHandle {
+    /// Stop the background thread and save a final snapshot.
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        // Dropping the sender wakes the thread as well, but sending stops it sooner.
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        save_snapshot(&self.trace, &self.path)
+    }
+}
+
+impl Drop for AutosaveHandle {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            // There is no way to report the error from here.
+            let _ = self.shutdown();
+        }
+    }
+}
+
+/// Atomically replace the file at `path` with the current contents of the trace.
+fn save_snapshot(trace: &Mutex<Trace>, path: &Path) -> Result<(), Error> {
+    let mut bytes = Vec::new();
+    {
+        // Coverage recorded before a panic is still worth saving.
+        let trace = trace.lock().unwrap_or_else(PoisonError::into_inner);
+        trace.write(&mut bytes)?;
+    }
+
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    std::fs::write(&temp, bytes)?;
+    std::fs::rename(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    #[test]
+    fn autosave_snapshots() {
+        let path = std::env::temp_dir().join(format!("drcov-autosave-{}.log", std::process::id()));
+        let trace = Arc::new(Mutex::new(Trace::new(&[Module::new(
+            "abcd", 0x1000, 0x2000,
+        )])));
+        trace.lock().unwrap().add(0x1010, 4);
+
+        let handle = Trace::autosave_every(Arc::clone(&trace), &path, Duration::from_millis(10));
+        while !path.exists() {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(Trace::load(&path).unwrap().entries().len(), 1);
+
+        trace.lock().unwrap().add(0x1020, 4);
+        handle.stop().unwrap();
+        assert_eq!(Trace::load(&path).unwrap().entries().len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+use std::panic::RefUnwindSafe;
+use std::sync::Arc;
+
+use crate::{dropped, AddError, Trace};
+
+/// Encodes the offset of a block within its module and its memory bank as a single offset.
+///
+/// Returns None if the pair cannot be encoded. Set with [`Trace::set_bank_encoder`].
+#[derive(Clone)]
+pub(crate) struct BankEncoder(Arc<dyn Fn(u64, u32) -> Option<u32> + Send + Sync + RefUnwindSafe>);
+
+impl Default for BankEncoder {
+    fn default() -> BankEncoder {
+        BankEncoder(Arc::new(bank_high_bits))
+    }
+}
+
+impl fmt::Debug for BankEncoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BankEncoder(..)")
+    }
+}
+
+/// The default bank encoder of [`Trace::add_banked`], storing the bank in the top 8 bits of the offset.
+///
+/// The lower 24 bits hold the offset from the module base, so offsets must be
+/// below 16 MiB and banks below 256.
+pub fn bank_high_bits(offset: u64, bank: u32) -> Option<u32> {
+    let offset = u32::try_from(offset)
+        .ok()
+        .filter(|&offset| offset < 1 << 24)?;
+    let bank = u8::try_from(bank).ok()?;
+    Some(u32::from(bank) << 24 | offset)
+}
+
+impl Trace {
+    /// Set how [`Trace::add_banked`] folds the memory bank into block offsets.
+    ///
+    /// The encoder receives the offset from the module base and the bank, and
+    /// returns None if they cannot be encoded. It may capture state, such as the
+    /// size of a bank, and is shared by clones of the trace. The default is
+    /// [`bank_high_bits`].
+    pub fn set_bank_encoder(
+        &mut self,
+        encoder: impl Fn(u64, u32) -> Option<u32> + Send + Sync + RefUnwindSafe + 'static,
+    ) {
+        self.bank_encoder = BankEncoder(Arc::new(encoder));
+    }
+
+    /// Add a new coverage entry for code in banked or overlay memory, where an address
+    /// refers to different code depending on the active `bank`.
+    ///
+    /// The block is recorded in the module containing the address, at the offset
+    /// returned by the bank encoder for its offset from the module base and `bank`.
+    /// The offsets are therefore in the namespace of the encoder rather than the
+    /// module image and may lie beyond the module's size, so consumers must decode
+    /// them, and [`Trace::verify`] reports them as out of bounds. Exclusions, the
+    /// active modules and the zero size policy apply as in [`Trace::try_add`]. This
+    /// function never panics, regardless of its input.
+    pub fn add_banked(&mut self, address: u64, size: usize, bank: u32) -> Result<(), AddError> {
+        if self.is_excluded(address) {
+            return Ok(());
+        }
+        let Some(mod_id) = self.active_module(address)? else {
+            return Ok(());
+        };
+        let start = (self.bank_encoder.0)(address - self.modules[mod_id].base, bank)
+            .ok_or_else(|| dropped(AddError::OffsetTooLarge { address }))?;
+        let size = u16::try_from(size).map_err(|_| dropped(AddError::SizeTooLarge { size }))?;
+
+        self.push_raw(mod_id, start, size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    #[test]
+    fn banked_blocks() {
+        let mut trace = Trace::new(&[Module::new("flash", 0x8000, 0xc000)]);
+        trace.add_banked(0x8010, 4, 0).unwrap();
+        trace.add_banked(0x8010, 4, 3).unwrap();
+        assert_eq!(
+            trace.add_banked(0x8010, 4, 256),
+            Err(AddError::OffsetTooLarge { address: 0x8010 })
+        );
+
+        let bank_size = 0x4000;
+        trace.set_bank_encoder(move |offset, bank| {
+            Some(bank * bank_size + u32::try_from(offset).ok()?)
+        });
+        trace.add_banked(0x8020, 4, 2).unwrap();
+        trace.set_active_modules(&[]);
+        trace.add_banked(0x8030, 4, 2).unwrap();
+
+        let starts = trace
+            .entries()
+            .iter()
+            .map(|e| e.start())
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [0x10, 0x0300_0010, 0x8020]);
+    }
+}
@@ -0,0 +1,288 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
+use crate::{BlockEntry, DrcovError, Module, Trace};
+
+/// Magic bytes at the start of the binary cache format.
+const BINARY_MAGIC: &[u8; 4] = b"DRCB";
+
+/// Version of the binary cache format.
+///
+/// Version 1 stored neither the optional module fields nor hit counts; it is still read.
+const BINARY_VERSION: u32 = 2;
+
+/// Module flag set for a later segment of the preceding module.
+const CONTINUATION: u8 = 1;
+/// Module flag set if a display name follows.
+const DISPLAY_NAME: u8 = 1 << 1;
+/// Module flag set if a build id follows.
+const BUILD_ID: u8 = 1 << 2;
+/// Module flag set if an analysis base follows.
+const ANALYSIS_BASE: u8 = 1 << 3;
+/// Module flag set if a load order follows.
+const LOAD_ORDER: u8 = 1 << 4;
+
+/// Trace flag set if a hit count follows the entries.
+const HIT_COUNTS: u8 = 1;
+
+impl Trace {
+    /// Output the modules and entries in a compact binary format.
+    ///
+    /// This is a cache format specific to this crate and is not readable by other
+    /// drcov tools. It is smaller and faster to parse than [`Trace::write`] output.
+    /// Only the modules, entries and hit counts are stored; the flavor, version,
+    /// block tags and recording and output settings are not.
+    ///
+    /// All integers are little-endian and byte strings are a `u32` length followed by
+    /// the bytes. The layout is the magic `DRCB`, the format version as a `u32`, the
+    /// module count as a `u32`, and then for each module the UTF-8 name, the base,
+    /// end, image offset and entry point as `u64`s, the checksum as a `u32` (0 if
+    /// unknown) and a flags byte. Flag bit 0 is set for a later segment of the
+    /// preceding module, and bits 1 to 4 are set if the display name, build id,
+    /// analysis base and load order follow, in that order, as a UTF-8 string, a byte
+    /// string and two `u64`s. The modules are followed by a trace flags byte, the
+    /// block count as a `u64` and the packed entries in the same layout as the drcov
+    /// BB table. If bit 0 of the trace flags is set, the hit count of each entry
+    /// follows as a `u32`.
+    pub fn write_binary(&self, writer: &mut impl Write) -> Result<(), Error> {
+        let too_large = || Error::new(ErrorKind::InvalidData, "Trace too large for binary format");
+
+        writer.write_all(BINARY_MAGIC)?;
+        writer.write_all(&BINARY_VERSION.to_le_bytes())?;
+        let count = u32::try_from(self.modules.len()).map_err(|_| too_large())?;
+        writer.write_all(&count.to_le_bytes())?;
+
+        for module in &self.modules {
+            write_bytes(writer, module.name.as_bytes())?;
+            for value in [module.base, module.end, module.offset, module.entry] {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            writer.write_all(&module.checksum.unwrap_or(0).to_le_bytes())?;
+
+            let flags = [
+                (module.continuation, CONTINUATION),
+                (module.display_name.is_some(), DISPLAY_NAME),
+                (module.build_id.is_some(), BUILD_ID),
+                (module.analysis_base.is_some(), ANALYSIS_BASE),
+                (module.load_order.is_some(), LOAD_ORDER),
+            ]
+            .into_iter()
+            .filter(|&(set, _)| set)
+            .fold(0, |flags, (_, flag)| flags | flag);
+            writer.write_all(&[flags])?;
+            if let Some(display_name) = &module.display_name {
+                write_bytes(writer, display_name.as_bytes())?;
+            }
+            if let Some(build_id) = &module.build_id {
+                write_bytes(writer, build_id)?;
+            }
+            for value in [module.analysis_base, module.load_order]
+                .into_iter()
+                .flatten()
+            {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+
+        let counts = self.hit_counts();
+        writer.write_all(&[if counts.is_some() { HIT_COUNTS } else { 0 }])?;
+        writer.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for entry in &self.entries {
+            entry.write(writer)?;
+        }
+        for count in counts.unwrap_or_default() {
+            writer.write_all(&count.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a trace written by [`Trace::write_binary`].
+    pub fn read_binary(reader: &mut impl Read) -> Result<Trace, DrcovError> {
+        let malformed = |reason: &str| DrcovError::Malformed(reason.to_owned());
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != BINARY_MAGIC {
+            return Err(malformed("invalid binary trace magic"));
+        }
+        let version = read_u32(reader)?;
+        if !(1..=BINARY_VERSION).contains(&version) {
+            return Err(malformed("unsupported binary trace version"));
+        }
+
+        let count = read_u32(reader)?;
+        let mut modules = Vec::new();
+        for _ in 0..count {
+            let name = String::from_utf8(read_bytes(reader)?)
+                .map_err(|_| malformed("module name is not UTF-8"))?;
+
+            let base = read_u64(reader)?;
+            let end = read_u64(reader)?;
+            let mut module = Module::try_new(name, base, end)
+                .map_err(|err| DrcovError::Malformed(err.to_string()))?;
+            module.offset = read_u64(reader)?;
+            module.entry = read_u64(reader)?;
+            module.checksum = Some(read_u32(reader)?).filter(|&checksum| checksum != 0);
+
+            let flags = read_u8(reader)?;
+            module.continuation = flags & CONTINUATION != 0;
+            if flags & DISPLAY_NAME != 0 {
+                let display_name = String::from_utf8(read_bytes(reader)?)
+                    .map_err(|_| malformed("module display name is not UTF-8"))?;
+                module.display_name = Some(display_name.into());
+            }
+            if flags & BUILD_ID != 0 {
+                module.build_id = Some(read_bytes(reader)?);
+            }
+            if flags & ANALYSIS_BASE != 0 {
+                module.analysis_base = Some(read_u64(reader)?);
+            }
+            if flags & LOAD_ORDER != 0 {
+                module.load_order = Some(read_u64(reader)?);
+            }
+            modules.push(module);
+        }
+
+        let mut trace = Trace::new(&modules);
+        let flags = if version == 1 { 0 } else { read_u8(reader)? };
+        let blocks = read_u64(reader)?;
+        let mut entries = Vec::new();
+        let mut buf = [0; BlockEntry::SIZE];
+        for _ in 0..blocks {
+            reader.read_exact(&mut buf)?;
+            entries.push(BlockEntry::from_bytes(&buf));
+        }
+
+        let counts = if flags & HIT_COUNTS != 0 {
+            trace.track_hit_counts();
+            let counts = entries
+                .iter()
+                .map(|_| read_u32(reader))
+                .collect::<Result<Vec<_>, _>>()?;
+            Some(counts)
+        } else {
+            None
+        };
+        trace
+            .push_entries(&entries, counts.as_deref())
+            .map_err(|_| malformed("entry refers to an unknown module id"))?;
+
+        Ok(trace)
+    }
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> Result<(), Error> {
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Trace too large for binary format"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>, Error> {
+    let len = u64::from(read_u32(reader)?);
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+fn read_u8(reader: &mut impl Read) -> Result<u8, Error> {
+    let mut buf = [0];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, Error> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, Error> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_round_trip() {
+        let mut modules = vec![Module::new("abcd", 0x1000, 0x2000)
+            .with_checksum(0x1234)
+            .with_entry(0x1100)];
+        modules.extend(Module::with_segments(
+            "split",
+            0x8000,
+            &[0..0x100, 0x200..0x300],
+        ));
+        let mut trace = Trace::new(&modules);
+        trace.add(0x1204, 3);
+        trace.add(0x8210, 12);
+
+        let mut out = Vec::new();
+        trace.write_binary(&mut out).unwrap();
+        let read = Trace::read_binary(&mut out.as_slice()).unwrap();
+
+        assert_eq!(read.modules(), trace.modules());
+        assert_eq!(read.entries(), trace.entries());
+
+        out.truncate(out.len() - 1);
+        assert!(Trace::read_binary(&mut out.as_slice()).is_err());
+    }
+
+    #[test]
+    fn binary_keeps_module_details_and_hit_counts() {
+        let modules = [
+            Module::new("/usr/lib/libc.so.6", 0x1000, 0x2000)
+                .with_display_name("libc")
+                .with_build_id([0xde, 0xad, 0xbe, 0xef])
+                .with_analysis_base(0x40_0000)
+                .with_load_order(3),
+            Module::new("abcd", 0x4000, 0x5000).with_load_order(0),
+        ];
+        let mut trace = Trace::new(&modules);
+        trace.track_hit_counts();
+        for address in [0x1010, 0x4020, 0x1010, 0x1010] {
+            trace.add(address, 4);
+        }
+
+        let mut out = Vec::new();
+        trace.write_binary(&mut out).unwrap();
+        let read = Trace::read_binary(&mut out.as_slice()).unwrap();
+
+        let module = &read.modules()[0];
+        assert_eq!(module.display_name(), "libc");
+        assert_eq!(module.build_id(), Some([0xde, 0xad, 0xbe, 0xef].as_slice()));
+        assert_eq!(module.analysis_base(), Some(0x40_0000));
+        assert_eq!(module.load_order(), Some(3));
+        assert_eq!(read.modules()[1].load_order(), Some(0));
+        assert_eq!(read.modules()[1].build_id(), None);
+        assert_eq!(read.modules(), trace.modules());
+        assert_eq!(read.entries(), trace.entries());
+        assert_eq!(read.hit_counts(), Some([3, 1].as_slice()));
+
+        let mut out = Vec::new();
+        Trace::new(&modules).write_binary(&mut out).unwrap();
+        let read = Trace::read_binary(&mut out.as_slice()).unwrap();
+        assert_eq!(read.hit_counts(), None);
+    }
+
+    #[test]
+    fn binary_reads_version_one() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        trace.add(0x1010, 4);
+        let mut out = Vec::new();
+        trace.write_binary(&mut out).unwrap();
+
+        // Version 1 has no trace flags byte after the single module.
+        out[4..8].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(out.remove(57), 0);
+        let read = Trace::read_binary(&mut out.as_slice()).unwrap();
+        assert_eq!(read.entries(), trace.entries());
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+/// A vector whose oldest items can be removed in amortized constant time.
+///
+/// Removed items stay in place until they make up half of the storage, and are
+/// then dropped in a single pass, so a buffer that keeps a fixed number of recent
+/// items holds at most twice that many. The live items dereference to a slice.
+#[derive(Clone)]
+pub(crate) struct Buffer<T> {
+    items: Vec<T>,
+    /// The number of removed items at the front of `items`.
+    head: usize,
+}
+
+impl<T> Buffer<T> {
+    /// Append an item at the back.
+    pub(crate) fn push(&mut self, item: T) {
+        self.items.push(item);
+    }
+
+    /// Remove and return the newest item.
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        match self.is_empty() {
+            true => None,
+            false => self.items.pop(),
+        }
+    }
+
+    /// Remove all items.
+    pub(crate) fn clear(&mut self) {
+        self.items.clear();
+        self.head = 0;
+    }
+
+    /// Remove up to `count` of the oldest items.
+    pub(crate) fn remove_front(&mut self, count: usize) {
+        self.head += count.min(self.len());
+        if self.head * 2 >= self.items.len() {
+            self.compact();
+        }
+    }
+
+    /// Keep only the items for which `keep` returns true, in order.
+    pub(crate) fn retain(&mut self, keep: impl FnMut(&T) -> bool) {
+        self.compact();
+        self.items.retain(keep);
+    }
+
+    /// Keep only the items for which `keep` returns true, in order, allowing it to modify them.
+    pub(crate) fn retain_mut(&mut self, keep: impl FnMut(&mut T) -> bool) {
+        self.compact();
+        self.items.retain_mut(keep);
+    }
+
+    /// Returns the live items as a slice.
+    pub(crate) fn as_slice(&self) -> &[T] {
+        self
+    }
+
+    /// Returns the number of items the storage can hold, including removed items.
+    pub(crate) fn capacity(&self) -> usize {
+        self.items.capacity()
+    }
+
+    /// Returns the live items as a vector.
+    pub(crate) fn into_vec(mut self) -> Vec<T> {
+        self.compact();
+        self.items
+    }
+
+    /// Drop the removed items from the storage.
+    fn compact(&mut self) {
+        self.items.drain(..self.head);
+        self.head = 0;
+    }
+}
+
+impl<T: PartialEq> Buffer<T> {
+    /// Remove consecutive repeated items.
+    pub(crate) fn dedup(&mut self) {
+        self.compact();
+        self.items.dedup();
+    }
+}
+
+impl<T> Default for Buffer<T> {
+    fn default() -> Buffer<T> {
+        Buffer::from(Vec::new())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Buffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T> Deref for Buffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.items[self.head..]
+    }
+}
+
+impl<T> DerefMut for Buffer<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.items[self.head..]
+    }
+}
+
+impl<T> From<Vec<T>> for Buffer<T> {
+    fn from(items: Vec<T>) -> Buffer<T> {
+        Buffer { items, head: 0 }
+    }
+}
+
+impl<T> Extend<T> for Buffer<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.items.extend(iter);
+    }
+}
+
+impl<T> FromIterator<T> for Buffer<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Buffer<T> {
+        Buffer::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+impl<T> IntoIterator for Buffer<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_vec().into_iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Buffer<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a mut Buffer<T> {
+    type Item = &'a mut T;
+    type IntoIter = std::slice::IterMut<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+
+use crate::{BlockEntry, Identity, Module, Trace};
+
+/// The fields that identify a module in the canonical form of a trace.
+type ModuleKey<'a> = (u64, u64, &'a str, u64, Option<u32>, u64);
+
+impl Trace {
+    /// Put the trace into its canonical form.
+    ///
+    /// Modules are sorted by address and renumbered, and the entries are sorted by
+    /// module id, offset and size with duplicates removed. Hit counts of merged
+    /// entries are summed. Segments stay linked only if they remain directly after
+    /// the preceding segment of their image, as by [`Trace::remap_module_ids`].
+    pub fn canonicalize(&mut self) {
+        let mapping = self
+            .module_order()
+            .into_iter()
+            .enumerate()
+            .map(|(new, old)| (old, new))
+            .collect::<Vec<_>>();
+        self.remap_module_ids(&mapping)
+            .expect("module order is a permutation");
+
+        let key = |entry: &BlockEntry| (entry.mod_id, entry.start, entry.size);
+        match self.hits.take() {
+            None => {
+                self.entries.sort_unstable_by_key(key);
+                self.entries.dedup();
+            }
+            Some(hits) => {
+                let mut entries = std::mem::take(&mut self.entries)
+                    .into_iter()
+                    .zip(hits.counts)
+                    .collect::<Vec<_>>();
+                entries.sort_unstable_by_key(|(entry, _)| key(entry));
+
+                self.hits = Some(Default::default());
+                for (entry, count) in entries {
+                    self.record(entry, count);
+                }
+            }
+        }
+    }
+
+    /// Returns the drcov encoding of the canonical form of this trace.
+    ///
+    /// The trace is canonicalized as by [`Trace::canonicalize`] and written as by
+    /// [`Trace::write`], without modifying `self`. Traces with equal modules,
+    /// blocks and settings give byte-identical output, regardless of recording
+    /// order, hashing or platform, so use this function when output must be
+    /// reproducible across runs.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut canonical = self.clone();
+        canonical.canonicalize();
+
+        let mut bytes = Vec::new();
+        canonical
+            .write(&mut bytes)
+            .expect("writing to a vector cannot fail");
+        bytes
+    }
+
+    /// Returns a stable 64-bit fingerprint of the distinct covered blocks.
+    ///
+    /// Each block is identified by the build id or name of its module, the image
+    /// offset of the module and the block offset and size, so the fingerprint does
+    /// not depend on module bases, module ids or recording order. Unlike the [`Hash`]
+    /// implementation it does not sort the blocks and is the same across program
+    /// runs and crate versions. Distinct coverage fingerprints equal only by chance.
+    pub fn fingerprint(&self) -> u64 {
+        let distinct = self.entries.iter().collect::<HashSet<_>>();
+        distinct.into_iter().fold(0u64, |fingerprint, entry| {
+            let (identity, offset) = self.modules[usize::from(entry.mod_id)].identity();
+            let mut hash = Fnv::default();
+            match identity {
+                Identity::BuildId(build_id) => hash.write(&[0]).write(build_id),
+                Identity::Name(name) => hash.write(&[1]).write(name.as_bytes()),
+            };
+            hash.write(&[0xff])
+                .write(&offset.to_le_bytes())
+                .write(&entry.start.to_le_bytes())
+                .write(&entry.size.to_le_bytes());
+            fingerprint.wrapping_add(hash.finish())
+        })
+    }
+
+    /// Returns a stable 64-bit id of the module table, written by [`Trace::write_slim`].
+    ///
+    /// The id covers the name, address range, image offset, entry point, segment
+    /// link and checksum of every module, in module id order. Traces with the same
+    /// module table share an id, which names the manifest holding that table.
+    pub fn manifest_id(&self) -> u64 {
+        manifest_id(&self.modules)
+    }
+
+    /// Returns the module ids in canonical order.
+    fn module_order(&self) -> Vec<usize> {
+        let mut order = (0..self.modules.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&id| module_key(&self.modules[id]));
+        order
+    }
+
+    /// Returns the sorted module keys and the sorted distinct blocks, relabelled to canonical module ids.
+    fn canonical(&self) -> (Vec<ModuleKey<'_>>, Vec<(u16, u32, u16)>) {
+        let order = self.module_order();
+
+        let mut new_ids = vec![0; order.len()];
+        for (new, &old) in order.iter().enumerate() {
+            new_ids[old] = new as u16;
+        }
+        let mut blocks = self
+            .entries
+            .iter()
+            .map(|e| (new_ids[usize::from(e.mod_id)], e.start, e.size))
+            .collect::<Vec<_>>();
+        blocks.sort_unstable();
+        blocks.dedup();
+
+        let modules = order
+            .into_iter()
+            .map(|id| module_key(&self.modules[id]))
+            .collect();
+        (modules, blocks)
+    }
+}
+
+fn module_key(module: &Module) -> ModuleKey<'_> {
+    (
+        module.base,
+        module.end,
+        module.name(),
+        module.offset,
+        module.checksum,
+        module.entry,
+    )
+}
+
+/// Returns the manifest id of a module table, as by [`Trace::manifest_id`].
+pub(crate) fn manifest_id(modules: &[Module]) -> u64 {
+    let mut hash = Fnv::default();
+    for module in modules {
+        hash.write(module.name.as_bytes())
+            .write(&[0xff, u8::from(module.continuation)])
+            .write(&module.base.to_le_bytes())
+            .write(&module.end.to_le_bytes())
+            .write(&module.offset.to_le_bytes())
+            .write(&module.entry.to_le_bytes())
+            .write(&module.checksum.unwrap_or(0).to_le_bytes());
+    }
+    hash.finish()
+}
+
+/// A 64-bit FNV-1a hash with a final avalanche step, used for stable fingerprints.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Fnv {
+        Fnv(0xcbf29ce484222325)
+    }
+}
+
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) -> &mut Fnv {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x100000001b3);
+        }
+        self
+    }
+
+    fn finish(&self) -> u64 {
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Traces are equal if they have the same modules and the same distinct blocks.
+///
+/// The comparison is independent of module and recording order, and ignores hit
+/// counts, segment links and output settings such as the flavor. It canonicalizes
+/// both traces, which takes O(n log n) time.
+impl PartialEq for Trace {
+    fn eq(&self, other: &Trace) -> bool {
+        self.canonical() == other.canonical()
+    }
+}
+
+impl Eq for Trace {}
+
+/// Hashes the canonical form of the trace, consistent with its [`PartialEq`] implementation.
+///
+/// This canonicalizes the trace, which takes O(n log n) time.
+impl Hash for Trace {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.canonical().hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_regardless_of_order() {
+        let target = Module::new("target.exe", 0x4000, 0x5000);
+        let libc = Module::new("libc.so", 0x8000, 0x9000);
+
+        let mut first = Trace::new(&[target.clone(), libc.clone()]);
+        first.add(0x4010, 4);
+        first.add(0x8020, 8);
+        first.add(0x4010, 4);
+
+        let mut second = Trace::new(&[libc, target]);
+        second.track_hit_counts();
+        second.add(0x8020, 8);
+        second.add(0x4010, 4);
+        assert!(first == second);
+
+        let traces = HashSet::from([first.clone(), second.clone()]);
+        assert_eq!(traces.len(), 1);
+
+        second.canonicalize();
+        assert_eq!(second.modules()[0].name(), "target.exe");
+        let entries = second
+            .entries()
+            .iter()
+            .map(|e| (e.mod_id(), e.start()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [(0, 0x10), (1, 0x20)]);
+        assert!(first == second);
+
+        assert_eq!(first.canonical_bytes(), second.canonical_bytes());
+        let mut written = Vec::new();
+        second.write(&mut written).unwrap();
+        assert_eq!(first.canonical_bytes(), written);
+
+        second.add(0x4014, 4);
+        assert!(first != second);
+    }
+
+    #[test]
+    fn fingerprint_ignores_order_and_base() {
+        let mut first = Trace::new(&[
+            Module::new("target.exe", 0x4000, 0x5000),
+            Module::new("libc.so", 0x8000, 0x9000),
+        ]);
+        first.add(0x4010, 4);
+        first.add(0x8020, 8);
+        first.add(0x4010, 4);
+
+        let mut second = Trace::new(&[
+            Module::new("libc.so", 0x18000, 0x19000),
+            Module::new("target.exe", 0x14000, 0x15000),
+        ]);
+        second.add(0x18020, 8);
+        second.add(0x14010, 4);
+        assert_eq!(first.fingerprint(), second.fingerprint());
+        assert_eq!(first.fingerprint(), 0xf125_9669_cd3b_b3df);
+
+        second.add(0x14014, 4);
+        assert_ne!(first.fingerprint(), second.fingerprint());
+        assert_eq!(Trace::new(&[]).fingerprint(), 0);
+    }
+}
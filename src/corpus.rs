@@ -0,0 +1,164 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
+use std::ops::Range;
+
+use crate::diff::BlockKey;
+use crate::{index, Identity, Module, Trace};
+
+/// How many traces of a corpus contain each block, built by [`CorpusAnalysis::from_traces`].
+///
+/// Blocks are identified as by [`Trace::diff`], by module build id or name, image
+/// offset, block offset and size, so the traces may load their modules at
+/// different bases. Blocks that few traces reach point at rare paths, and the
+/// traces that contain them are candidates to keep when minimizing a corpus.
+#[derive(Clone, Debug)]
+pub struct CorpusAnalysis<'a> {
+    /// The number of traces containing each block, with the block as first seen.
+    blocks: HashMap<BlockKey<'a>, (BlockFrequency<'a>, usize)>,
+    /// Per module identity, the offset ranges covered by each trace containing the
+    /// module, sorted and with overlaps merged.
+    covered: HashMap<(Identity<'a>, u64), Vec<Vec<Range<u64>>>>,
+    /// The traces of the corpus, used to resolve addresses.
+    traces: &'a [Trace],
+}
+
+/// A block of a corpus and the number of traces containing it, returned by [`CorpusAnalysis::rarest_blocks`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockFrequency<'a> {
+    /// The module of the block in the first trace containing it.
+    pub module: &'a Module,
+    /// The offset of the block from the module base.
+    pub start: u32,
+    /// The size of the block in bytes.
+    pub size: u16,
+    /// The number of traces containing the block.
+    pub traces: usize,
+}
+
+impl<'a> CorpusAnalysis<'a> {
+    /// Count, for each distinct block of `traces`, the number of traces that contain it.
+    pub fn from_traces(traces: &'a [Trace]) -> CorpusAnalysis<'a> {
+        let mut blocks = HashMap::<_, (BlockFrequency, usize)>::new();
+        let mut covered = HashMap::<_, Vec<_>>::new();
+        for trace in traces {
+            let mut seen = HashSet::new();
+            let mut ranges = HashMap::<_, Vec<_>>::new();
+            for entry in &trace.entries {
+                let Some(module) = trace.module_for_entry(entry) else {
+                    continue;
+                };
+                let start = u64::from(entry.start);
+                ranges
+                    .entry(module.identity())
+                    .or_default()
+                    .push(start..start + u64::from(entry.size));
+
+                let key = (module.identity(), entry.start, entry.size);
+                if !seen.insert(key) {
+                    continue;
+                }
+                let order = blocks.len();
+                match blocks.entry(key) {
+                    Entry::Occupied(mut block) => block.get_mut().0.traces += 1,
+                    Entry::Vacant(block) => {
+                        let frequency = BlockFrequency {
+                            module,
+                            start: entry.start,
+                            size: entry.size,
+                            traces: 1,
+                        };
+                        block.insert((frequency, order));
+                    }
+                }
+            }
+            for (module, ranges) in ranges {
+                covered
+                    .entry(module)
+                    .or_default()
+                    .push(index::merge_ranges(ranges));
+            }
+        }
+
+        CorpusAnalysis {
+            blocks,
+            covered,
+            traces,
+        }
+    }
+
+    /// Returns the `n` blocks contained in the fewest traces, rarest first.
+    ///
+    /// Blocks contained in the same number of traces are returned in the order they
+    /// were first seen in the corpus.
+    pub fn rarest_blocks(&self, n: usize) -> Vec<BlockFrequency<'a>> {
+        let mut blocks = self.blocks.values().collect::<Vec<_>>();
+        blocks.sort_unstable_by_key(|(block, order)| (block.traces, *order));
+        blocks
+            .into_iter()
+            .take(n)
+            .map(|(block, _)| *block)
+            .collect()
+    }
+
+    /// Returns the number of traces in which the code at `address` is covered by a recorded block.
+    ///
+    /// The address is resolved once, against the modules of the first trace that
+    /// maps it, and the code is then identified in every trace by its module
+    /// identity and offset, as for [`CorpusAnalysis::rarest_blocks`]. Returns 0 if
+    /// no trace maps the address.
+    pub fn block_frequency(&self, address: u64) -> usize {
+        let Some(module) = self
+            .traces
+            .iter()
+            .find_map(|trace| trace.get_module(address))
+        else {
+            return 0;
+        };
+        let offset = address - module.base;
+
+        self.covered.get(&module.identity()).map_or(0, |traces| {
+            traces
+                .iter()
+                .filter(|ranges| {
+                    let index = ranges.partition_point(|range| range.start <= offset);
+                    index > 0 && offset < ranges[index - 1].end
+                })
+                .count()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corpus_block_frequencies() {
+        let mut first = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        first.add(0x1010, 4);
+        first.add(0x1020, 4);
+        first.add(0x1010, 4);
+        let mut second = Trace::new(&[Module::new("abcd", 0x5000, 0x6000)]);
+        second.add(0x5010, 4);
+        second.add(0x5030, 4);
+        let mut third = first.clone();
+        third.add(0x1040, 4);
+
+        let traces = [first, second, third];
+        let corpus = CorpusAnalysis::from_traces(&traces);
+        let rarest = corpus
+            .rarest_blocks(3)
+            .iter()
+            .map(|block| (block.start, block.traces))
+            .collect::<Vec<_>>();
+        assert_eq!(rarest, [(0x30, 1), (0x40, 1), (0x20, 2)]);
+        assert_eq!(corpus.rarest_blocks(10).len(), 4);
+
+        assert_eq!(corpus.block_frequency(0x1010), 3);
+        assert_eq!(corpus.block_frequency(0x5012), 3);
+        assert_eq!(corpus.block_frequency(0x1020), 2);
+        assert_eq!(corpus.block_frequency(0x1030), 1);
+        assert_eq!(corpus.block_frequency(0x1034), 0);
+        assert_eq!(corpus.block_frequency(0x9000), 0);
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+
+use crate::{DrcovError, Identity, Trace};
+
+/// A numeric summary of the difference between a trace and a baseline, created by [`Trace::difference_stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiffStats {
+    /// The number of distinct blocks covered by the trace but not by the baseline.
+    pub new_blocks: usize,
+    /// The number of distinct blocks covered by the baseline but not by the trace.
+    pub removed_blocks: usize,
+    /// The number of new blocks in each module of the trace, indexed by module id.
+    pub new_blocks_per_module: Vec<usize>,
+    /// The total size in bytes of the new blocks.
+    pub new_bytes: u64,
+}
+
+/// The difference between the module tables of two traces, created by [`Trace::module_diff`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ModuleDiff {
+    /// Ids of the modules of the other trace that this trace does not have.
+    pub added: Vec<usize>,
+    /// Ids of the modules of this trace that the other trace does not have.
+    pub removed: Vec<usize>,
+    /// Pairs of module ids in this and the other trace for modules that were loaded at different bases.
+    pub rebased: Vec<(usize, usize)>,
+}
+
+/// A block identified independently of its module's id and base address.
+pub(crate) type BlockKey<'a> = ((Identity<'a>, u64), u32, u16);
+
+impl Trace {
+    /// Returns a trace containing the blocks of this trace that are not covered by `baseline`.
+    ///
+    /// Blocks are matched by module build id or name, block offset and size, so the
+    /// two traces may load their modules at different bases. The result keeps this
+    /// trace's module table and lists each new block once, in the order it was first
+    /// recorded.
+    pub fn diff(&self, baseline: &Trace) -> Trace {
+        let covered = baseline.block_keys();
+
+        let mut new = HashSet::new();
+        let entries = self
+            .entries
+            .iter()
+            .filter(|e| {
+                self.module_for_entry(e).is_some_and(|m| {
+                    let key = (m.identity(), e.start, e.size);
+                    !covered.contains(&key) && new.insert(key)
+                })
+            })
+            .copied()
+            .collect();
+
+        self.with_entries(entries)
+    }
+
+    /// Summarize the difference between this trace and `baseline` without building a diff trace.
+    ///
+    /// Blocks are matched as by [`Trace::diff`], and the new blocks are exactly the
+    /// entries of `self.diff(baseline)`.
+    pub fn difference_stats(&self, baseline: &Trace) -> DiffStats {
+        let covered = baseline.block_keys();
+        let current = self.block_keys();
+
+        let mut stats = DiffStats {
+            new_blocks_per_module: vec![0; self.modules.len()],
+            removed_blocks: covered.difference(&current).count(),
+            ..DiffStats::default()
+        };
+        let mut new = HashSet::new();
+        for entry in &self.entries {
+            let Some(module) = self.module_for_entry(entry) else {
+                continue;
+            };
+            let key = (module.identity(), entry.start, entry.size);
+            if !covered.contains(&key) && new.insert(key) {
+                stats.new_blocks += 1;
+                stats.new_blocks_per_module[usize::from(entry.mod_id)] += 1;
+                stats.new_bytes += u64::from(entry.size);
+            }
+        }
+        stats
+    }
+
+    /// Compare the module tables of this trace and `other`, ignoring their blocks.
+    ///
+    /// Modules are matched by build id or name and image offset, as by
+    /// [`Trace::diff`]. If several modules match, they are paired in module id
+    /// order. All lists are sorted by module id.
+    pub fn module_diff(&self, other: &Trace) -> ModuleDiff {
+        let mut unmatched = HashMap::<_, VecDeque<usize>>::new();
+        for (id, module) in other.modules.iter().enumerate() {
+            unmatched
+                .entry(module.identity())
+                .or_default()
+                .push_back(id);
+        }
+
+        let mut diff = ModuleDiff::default();
+        for (id, module) in self.modules.iter().enumerate() {
+            let matched = unmatched
+                .get_mut(&module.identity())
+                .and_then(VecDeque::pop_front);
+            match matched {
+                Some(other_id) if other.modules[other_id].base != module.base => {
+                    diff.rebased.push((id, other_id))
+                }
+                Some(_) => {}
+                None => diff.removed.push(id),
+            }
+        }
+        diff.added = unmatched.into_values().flatten().collect();
+        diff.added.sort_unstable();
+        diff
+    }
+
+    /// Returns the distinct blocks of this trace, keyed by module identity, offset and size.
+    fn block_keys(&self) -> HashSet<BlockKey<'_>> {
+        self.entries
+            .iter()
+            .filter_map(|e| Some((self.module_for_entry(e)?.identity(), e.start, e.size)))
+            .collect()
+    }
+
+    /// Returns the blocks of this trace that are not covered by the baseline trace saved at `baseline_path`.
+    ///
+    /// See [`Trace::diff`] for how blocks are matched.
+    pub fn new_coverage_vs(&self, baseline_path: impl AsRef<Path>) -> Result<Trace, DrcovError> {
+        Ok(self.diff(&Trace::load(baseline_path)?))
+    }
+
+    /// Returns true if this trace covers any block that the baseline trace saved at `baseline_path` does not.
+    pub fn has_new_coverage_vs(&self, baseline_path: impl AsRef<Path>) -> Result<bool, DrcovError> {
+        Ok(!self.new_coverage_vs(baseline_path)?.entries.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    #[test]
+    fn diff_matches_by_module_name() {
+        let mut baseline = Trace::new(&[Module::new("target", 0x1000, 0x2000)]);
+        baseline.add(0x1010, 4);
+
+        // The same module loaded at a different base.
+        let mut run = Trace::new(&[Module::new("target", 0x8000, 0x9000)]);
+        run.add(0x8010, 4);
+        run.add(0x8020, 4);
+        run.add(0x8020, 4);
+
+        let delta = run.diff(&baseline);
+        assert_eq!(delta.modules(), run.modules());
+        assert_eq!(delta.entries().len(), 1);
+        assert_eq!(delta.entries()[0].start(), 0x20);
+    }
+
+    #[test]
+    fn difference_stats() {
+        let mut baseline = Trace::new(&[Module::new("target", 0x1000, 0x2000)]);
+        baseline.add(0x1010, 4);
+        baseline.add(0x1080, 2);
+
+        let mut run = Trace::new(&[
+            Module::new("libc.so", 0x5000, 0x6000),
+            Module::new("target", 0x8000, 0x9000),
+        ]);
+        run.add(0x8010, 4);
+        run.add(0x8020, 8);
+        run.add(0x8020, 8);
+        run.add(0x5000, 6);
+
+        let stats = run.difference_stats(&baseline);
+        assert_eq!(
+            stats,
+            DiffStats {
+                new_blocks: 2,
+                removed_blocks: 1,
+                new_blocks_per_module: vec![1, 1],
+                new_bytes: 14,
+            }
+        );
+        assert_eq!(stats.new_blocks, run.diff(&baseline).entries().len());
+        assert_eq!(run.difference_stats(&run).new_blocks, 0);
+    }
+
+    #[test]
+    fn module_diff_reports_layout_changes() {
+        let baseline = Trace::new(&[
+            Module::new("target", 0x1000, 0x2000),
+            Module::new("libc.so", 0x5000, 0x6000),
+            Module::new("libm.so", 0x8000, 0x9000),
+        ]);
+        let run = Trace::new(&[
+            Module::new("libssl.so", 0xa000, 0xb000),
+            Module::new("libc.so", 0x7000, 0x8000),
+            Module::new("target", 0x1000, 0x2000),
+        ]);
+
+        assert_eq!(
+            baseline.module_diff(&run),
+            ModuleDiff {
+                added: vec![0],
+                removed: vec![2],
+                rebased: vec![(1, 1)],
+            }
+        );
+        assert_eq!(run.module_diff(&run), ModuleDiff::default());
+    }
+
+    #[test]
+    fn new_coverage_vs_baseline_file() {
+        let modules = [Module::new("target", 0x1000, 0x2000)];
+        let mut baseline = Trace::new(&modules);
+        baseline.add(0x1010, 4);
+
+        let path = std::env::temp_dir().join(format!("drcov-baseline-{}.log", std::process::id()));
+        baseline.save(&path).unwrap();
+
+        let mut run = Trace::new(&modules);
+        run.add(0x1010, 4);
+        assert!(!run.has_new_coverage_vs(&path).unwrap());
+
+        run.add(0x1040, 8);
+        let delta = run.new_coverage_vs(&path).unwrap();
+        assert_eq!(delta.entries().len(), 1);
+        assert!(run.has_new_coverage_vs(&path).unwrap());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
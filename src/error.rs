@@ -0,0 +1,262 @@
+/// Errors returned when recording a coverage entry fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddError {
+    /// No module contains the given address.
+    NoModule {
+        /// The address that was being recorded.
+        address: u64,
+    },
+    /// There is no module with the given id, or the id cannot be stored in an entry.
+    InvalidModuleId {
+        /// The requested module id.
+        mod_id: usize,
+    },
+    /// The address is not within the requested module.
+    NotInModule {
+        /// The address that was being recorded.
+        address: u64,
+        /// The requested module id.
+        mod_id: usize,
+    },
+    /// The offset of the address from its module base does not fit in a `u32`.
+    OffsetTooLarge {
+        /// The address that was being recorded.
+        address: u64,
+    },
+    /// The module relative offset is not within the requested module.
+    OffsetOutOfRange {
+        /// The requested offset from the module base.
+        offset: u32,
+        /// The requested module id.
+        mod_id: usize,
+    },
+    /// The block has a size of zero, which the trace is set to reject.
+    ZeroSize {
+        /// The offset of the block from the module base.
+        offset: u32,
+        /// The module id of the block.
+        mod_id: usize,
+    },
+    /// The block size does not fit in a `u16`.
+    SizeTooLarge {
+        /// The requested block size.
+        size: usize,
+    },
+    /// The trace already stores its maximum number of entries.
+    LimitReached {
+        /// The maximum number of entries.
+        max: usize,
+    },
+}
+
+impl std::fmt::Display for AddError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            AddError::NoModule { address } => {
+                write!(f, "No module found that contains address {address:#x}")
+            }
+            AddError::InvalidModuleId { mod_id } => write!(f, "Invalid module id {mod_id}"),
+            AddError::NotInModule { address, mod_id } => {
+                write!(f, "Address {address:#x} is not in module {mod_id}")
+            }
+            AddError::OffsetTooLarge { address } => {
+                write!(
+                    f,
+                    "Offset of address {address:#x} is too large (u32::MAX < offset)"
+                )
+            }
+            AddError::OffsetOutOfRange { offset, mod_id } => {
+                write!(f, "Offset {offset:#x} is outside module {mod_id}")
+            }
+            AddError::ZeroSize { offset, mod_id } => {
+                write!(
+                    f,
+                    "Block at offset {offset:#x} in module {mod_id} has a size of zero"
+                )
+            }
+            AddError::SizeTooLarge { size } => {
+                write!(f, "Entry size {size} is too large (u16::MAX < entry)")
+            }
+            AddError::LimitReached { max } => {
+                write!(f, "Trace already stores the maximum of {max} entries")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AddError {}
+
+/// Errors returned when creating or moving a [`Module`](crate::Module) with an invalid address range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModuleError {
+    /// The end address is not after the base address.
+    InvalidRange {
+        /// The requested base address.
+        base: u64,
+        /// The requested end address.
+        end: u64,
+    },
+    /// The module is larger than `u32::MAX` bytes.
+    TooLarge {
+        /// The requested base address.
+        base: u64,
+        /// The requested end address.
+        end: u64,
+    },
+    /// Shifting the module by the given delta moves it outside the address space.
+    RebaseOverflow {
+        /// The base address of the module.
+        base: u64,
+        /// The end address of the module.
+        end: u64,
+        /// The requested shift.
+        delta: i64,
+    },
+}
+
+impl std::fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ModuleError::InvalidRange { base, end } => {
+                write!(f, "Module base {base:#x} must be before end {end:#x}")
+            }
+            ModuleError::TooLarge { base, end } => {
+                write!(f, "Module {base:#x}-{end:#x} is larger than u32::MAX bytes")
+            }
+            ModuleError::RebaseOverflow { base, end, delta } => {
+                write!(
+                    f,
+                    "Module {base:#x}-{end:#x} cannot be shifted by {delta:#x}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ModuleError {}
+
+/// Errors returned when a module id mapping passed to
+/// [`Trace::remap_module_ids`](crate::Trace::remap_module_ids) is not a bijection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MappingError {
+    /// The mapping does not have exactly one pair for every module of the trace.
+    WrongLength {
+        /// The number of modules in the trace.
+        expected: usize,
+        /// The number of pairs in the mapping.
+        actual: usize,
+    },
+    /// An old or new id of the mapping is not a module id of the trace.
+    InvalidModuleId {
+        /// The out of range module id.
+        mod_id: usize,
+    },
+    /// A module id appears more than once as an old or as a new id.
+    DuplicateModuleId {
+        /// The repeated module id.
+        mod_id: usize,
+    },
+}
+
+impl std::fmt::Display for MappingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            MappingError::WrongLength { expected, actual } => {
+                write!(f, "Expected a mapping for {expected} modules, got {actual}")
+            }
+            MappingError::InvalidModuleId { mod_id } => write!(f, "Invalid module id {mod_id}"),
+            MappingError::DuplicateModuleId { mod_id } => {
+                write!(f, "Module id {mod_id} is mapped more than once")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MappingError {}
+
+/// Errors returned when a module table passed to
+/// [`Module::build_table`](crate::Module::build_table) is invalid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableError {
+    /// The address range of a module is invalid.
+    InvalidModule {
+        /// The index of the module in the input.
+        index: usize,
+        /// The reason the module is invalid.
+        error: ModuleError,
+    },
+    /// The address ranges of two modules overlap.
+    Overlap {
+        /// The index in the input of the module with the lower base address.
+        first: usize,
+        /// The index in the input of the other module.
+        second: usize,
+    },
+}
+
+impl std::fmt::Display for TableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            TableError::InvalidModule { index, error } => write!(f, "Module {index}: {error}"),
+            TableError::Overlap { first, second } => {
+                write!(f, "Modules {first} and {second} overlap")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TableError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TableError::InvalidModule { error, .. } => Some(error),
+            TableError::Overlap { .. } => None,
+        }
+    }
+}
+
+/// Errors returned when reading a trace fails.
+#[derive(Debug)]
+pub enum DrcovError {
+    /// An I/O error occurred while reading the trace.
+    Io(std::io::Error),
+    /// The input is not a valid drcov trace.
+    Malformed(String),
+    /// An entry of the BB table refers to a module id that is not in the module table.
+    DanglingModuleId {
+        /// Index of the entry in the BB table.
+        entry_index: usize,
+        /// The module id stored in the entry.
+        mod_id: u16,
+    },
+}
+
+impl std::fmt::Display for DrcovError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DrcovError::Io(err) => write!(f, "I/O error: {err}"),
+            DrcovError::Malformed(reason) => write!(f, "Malformed drcov trace: {reason}"),
+            DrcovError::DanglingModuleId {
+                entry_index,
+                mod_id,
+            } => write!(
+                f,
+                "Malformed drcov trace: entry {entry_index} refers to unknown module id {mod_id}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DrcovError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DrcovError::Io(err) => Some(err),
+            DrcovError::Malformed(_) | DrcovError::DanglingModuleId { .. } => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DrcovError {
+    fn from(err: std::io::Error) -> Self {
+        DrcovError::Io(err)
+    }
+}
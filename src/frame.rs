@@ -0,0 +1,272 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
+use crate::{BlockEntry, DrcovError, Trace};
+
+/// Magic bytes at the start of every frame.
+const FRAME_MAGIC: &[u8; 4] = b"DRCF";
+
+/// The flag set on frames whose payload is gzip compressed.
+const FLAG_GZIP: u8 = 1;
+
+/// The flag set on frames whose payload carries hit counts after the entries.
+const FLAG_HITS: u8 = 2;
+
+/// How the payload of a frame written by [`Trace::drain_frame_with`] is encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrameCodec {
+    /// The packed entries are stored as is.
+    #[default]
+    None,
+    /// The packed entries are gzip compressed.
+    #[cfg(feature = "gzip")]
+    Gzip,
+}
+
+impl Trace {
+    /// Write the entries recorded since the last drain as a single uncompressed frame and remove them from the trace.
+    ///
+    /// This is the same as [`Trace::drain_frame_with`] with [`FrameCodec::None`].
+    pub fn drain_frame(&mut self, writer: &mut impl Write) -> Result<usize, Error> {
+        self.drain_frame_with(writer, FrameCodec::None)
+    }
+
+    /// Write the entries recorded since the last drain as a single frame and remove them from the trace.
+    ///
+    /// A frame is the magic `DRCF`, a flags byte, the number of entries and the
+    /// length of the payload in bytes as little-endian `u32`s, and then the
+    /// payload. The payload holds the packed entries in the same layout as the BB
+    /// table, followed by a little-endian `u32` hit count per entry if hit
+    /// counting is enabled, encoded with `codec`. The module table is not
+    /// included, so the receiving side must already hold a trace with the same
+    /// modules.
+    ///
+    /// Returns the number of entries written.
+    pub fn drain_frame_with(
+        &mut self,
+        writer: &mut impl Write,
+        codec: FrameCodec,
+    ) -> Result<usize, Error> {
+        let count = u32::try_from(self.entries.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Too many entries for one frame"))?;
+
+        let mut flags = 0;
+        let mut payload = self.bb_table_bytes();
+        if let Some(counts) = self.hit_counts() {
+            flags |= FLAG_HITS;
+            payload.extend(counts.iter().flat_map(|count| count.to_le_bytes()));
+        }
+        match codec {
+            FrameCodec::None => {}
+            #[cfg(feature = "gzip")]
+            FrameCodec::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&payload)?;
+                flags |= FLAG_GZIP;
+                payload = encoder.finish()?;
+            }
+        }
+        let len = u32::try_from(payload.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Frame payload is too large"))?;
+
+        writer.write_all(FRAME_MAGIC)?;
+        writer.write_all(&[flags])?;
+        writer.write_all(&count.to_le_bytes())?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&payload)?;
+
+        let count = self.entries.len();
+        self.clear_entries();
+        Ok(count)
+    }
+
+    /// Read a single frame written by [`Trace::drain_frame_with`] and append its entries to the trace.
+    ///
+    /// Hit counts in the frame are added to the counts of the trace if it counts
+    /// hits, and ignored otherwise. Gzip compressed frames are only supported with
+    /// the `gzip` feature. Returns the number of entries in the frame.
+    pub fn apply_frame(&mut self, reader: &mut impl Read) -> Result<usize, Error> {
+        let mut header = [0; 13];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != FRAME_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid frame magic"));
+        }
+
+        let flags = header[4];
+        let count = u32::from_le_bytes([header[5], header[6], header[7], header[8]]) as usize;
+        let len = u32::from_le_bytes([header[9], header[10], header[11], header[12]]);
+
+        // Read through `take` so a corrupt length cannot trigger a huge up-front allocation.
+        let mut payload = Vec::new();
+        reader.take(u64::from(len)).read_to_end(&mut payload)?;
+        if payload.len() != len as usize {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Truncated frame"));
+        }
+
+        if flags & !(FLAG_GZIP | FLAG_HITS) != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "Unknown frame flags"));
+        }
+        let entry_size = match flags & FLAG_HITS {
+            0 => BlockEntry::SIZE,
+            _ => BlockEntry::SIZE + 4,
+        };
+        let expected = count.checked_mul(entry_size).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                DrcovError::Malformed(format!("frame entry count {count} is too large")),
+            )
+        })?;
+
+        let bytes = match flags & FLAG_GZIP {
+            0 => payload,
+            #[cfg(feature = "gzip")]
+            _ => {
+                let mut bytes = Vec::new();
+                flate2::read::GzDecoder::new(payload.as_slice())
+                    .take(expected as u64 + 1)
+                    .read_to_end(&mut bytes)?;
+                bytes
+            }
+            #[cfg(not(feature = "gzip"))]
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "Compressed frames require the gzip feature",
+                ))
+            }
+        };
+        if bytes.len() != expected {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Frame payload does not match its entry count",
+            ));
+        }
+
+        let (entries, counts) = bytes.split_at(count * BlockEntry::SIZE);
+        let entries = entries
+            .chunks_exact(BlockEntry::SIZE)
+            .map(BlockEntry::from_bytes)
+            .collect::<Vec<_>>();
+        let counts = counts
+            .chunks_exact(4)
+            .map(|count| u32::from_le_bytes([count[0], count[1], count[2], count[3]]))
+            .collect::<Vec<_>>();
+        let counts = (flags & FLAG_HITS != 0).then_some(counts.as_slice());
+
+        self.push_entries(&entries, counts)?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    #[test]
+    fn frames_forward_new_entries() {
+        let modules = [Module::new("abcd.so", 0x1000, 0x2000)];
+        let mut producer = Trace::new(&modules);
+        let mut collector = Trace::new(&modules);
+        let mut stream = Vec::new();
+
+        producer.add(0x1010, 4);
+        producer.add(0x1020, 8);
+        assert_eq!(producer.drain_frame(&mut stream).unwrap(), 2);
+        producer.add(0x1030, 2);
+        assert_eq!(producer.drain_frame(&mut stream).unwrap(), 1);
+        assert_eq!(producer.drain_frame(&mut stream).unwrap(), 0);
+        assert!(producer.entries.is_empty());
+
+        let mut reader = stream.as_slice();
+        assert_eq!(collector.apply_frame(&mut reader).unwrap(), 2);
+        assert_eq!(collector.apply_frame(&mut reader).unwrap(), 1);
+        assert_eq!(collector.apply_frame(&mut reader).unwrap(), 0);
+        assert!(reader.is_empty());
+
+        let mut expected = Trace::new(&modules);
+        expected.add(0x1010, 4);
+        expected.add(0x1020, 8);
+        expected.add(0x1030, 2);
+        assert_eq!(collector.bb_table_bytes(), expected.bb_table_bytes());
+    }
+
+    #[test]
+    fn apply_frame_respects_max_entries() {
+        let modules = [Module::new("abcd.so", 0x1000, 0x2000)];
+        let mut producer = Trace::new(&modules);
+        let mut collector = Trace::with_max_entries(&modules, 2);
+        let mut stream = Vec::new();
+
+        producer.add(0x1010, 4);
+        producer.add(0x1020, 8);
+        producer.add(0x1030, 2);
+        producer.drain_frame(&mut stream).unwrap();
+
+        assert!(collector.apply_frame(&mut stream.as_slice()).is_err());
+        assert_eq!(collector.entries().len(), 2);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn compressed_frames() {
+        let modules = [Module::new("abcd.so", 0x1000, 0x2000)];
+        let mut producer = Trace::new(&modules);
+        let mut collector = Trace::new(&modules);
+        let mut stream = Vec::new();
+
+        for offset in 0..64 {
+            producer.add(0x1000 + offset * 4, 4);
+        }
+        let expected = producer.bb_table_bytes();
+        producer
+            .drain_frame_with(&mut stream, FrameCodec::Gzip)
+            .unwrap();
+        assert_eq!(stream[4], FLAG_GZIP);
+        assert!(stream.len() < expected.len());
+
+        assert_eq!(collector.apply_frame(&mut stream.as_slice()).unwrap(), 64);
+        assert_eq!(collector.bb_table_bytes(), expected);
+    }
+
+    #[test]
+    fn frames_carry_hit_counts() {
+        let modules = [Module::new("abcd.so", 0x1000, 0x2000)];
+        let mut producer = Trace::new(&modules);
+        let mut collector = Trace::new(&modules);
+        producer.track_hit_counts();
+        collector.track_hit_counts();
+        let mut stream = Vec::new();
+
+        producer.add(0x1010, 4);
+        producer.add(0x1010, 4);
+        producer.add(0x1020, 8);
+        producer.drain_frame(&mut stream).unwrap();
+        producer.add(0x1010, 4);
+        producer.drain_frame(&mut stream).unwrap();
+
+        let mut reader = stream.as_slice();
+        assert_eq!(collector.apply_frame(&mut reader).unwrap(), 2);
+        assert_eq!(collector.apply_frame(&mut reader).unwrap(), 1);
+        assert_eq!(collector.hit_counts(), Some([3, 1].as_slice()));
+    }
+
+    #[test]
+    fn apply_frame_rejects_mismatched_count() {
+        let mut trace = Trace::new(&[Module::new("abcd.so", 0x1000, 0x2000)]);
+        let mut frame = FRAME_MAGIC.to_vec();
+        frame.push(FLAG_HITS);
+        frame.extend(u32::MAX.to_le_bytes());
+        frame.extend(0u32.to_le_bytes());
+
+        assert!(trace.apply_frame(&mut frame.as_slice()).is_err());
+    }
+
+    #[test]
+    fn apply_frame_rejects_bad_magic() {
+        let mut trace = Trace::new(&[Module::new("abcd.so", 0x1000, 0x2000)]);
+        let frame = [b'X', b'X', b'X', b'X', 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        assert!(trace.apply_frame(&mut frame.as_slice()).is_err());
+    }
+}
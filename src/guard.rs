@@ -0,0 +1,90 @@
+use std::io::Error;
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+
+use crate::Trace;
+
+/// A guard that saves a [`Trace`] to a file when it is dropped, created by [`Trace::auto_save`].
+///
+/// The trace is also saved when the guard is dropped while unwinding from a panic,
+/// so coverage is kept for crashing inputs. Errors while saving on drop are
+/// ignored; use [`TraceGuard::finish`] to save and observe the result.
+#[derive(Debug)]
+pub struct TraceGuard<'a> {
+    trace: &'a mut Trace,
+    /// Where to save the trace, or None once it has been saved by `finish`.
+    path: Option<PathBuf>,
+}
+
+impl Trace {
+    /// Returns a guard that saves this trace to the given path when it is dropped.
+    ///
+    /// The guard dereferences to the trace, so coverage can be recorded through it.
+    pub fn auto_save(&mut self, path: impl Into<PathBuf>) -> TraceGuard<'_> {
+        TraceGuard {
+            trace: self,
+            path: Some(path.into()),
+        }
+    }
+}
+
+impl TraceGuard<'_> {
+    /// Save the trace now and return the result instead of saving on drop.
+    pub fn finish(mut self) -> Result<(), Error> {
+        match self.path.take() {
+            Some(path) => self.trace.save(path),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Deref for TraceGuard<'_> {
+    type Target = Trace;
+
+    fn deref(&self) -> &Trace {
+        self.trace
+    }
+}
+
+impl DerefMut for TraceGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Trace {
+        self.trace
+    }
+}
+
+impl Drop for TraceGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            // There is no way to report the error from here.
+            let _ = self.trace.save(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    #[test]
+    fn saves_on_panic() {
+        let path = std::env::temp_dir().join(format!("drcov-guard-{}.log", std::process::id()));
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut guard = trace.auto_save(&path);
+            guard.add(0x1010, 4);
+            panic!("crashing input");
+        }));
+        assert!(result.is_err());
+
+        let saved = Trace::load(&path).unwrap();
+        assert_eq!(saved.entries(), trace.entries());
+        std::fs::remove_file(&path).unwrap();
+
+        let guard = trace.auto_save(&path);
+        guard.finish().unwrap();
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
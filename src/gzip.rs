@@ -0,0 +1,89 @@
+use std::io::{BufWriter, Error, ErrorKind, Write};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::Trace;
+
+impl Trace {
+    /// Output the coverage information in drcov format, gzip compressed at the default level.
+    pub fn write_compressed(&self, writer: &mut impl Write) -> Result<(), Error> {
+        self.write_compressed_with(writer, Compression::default().level())
+    }
+
+    /// Output the coverage information in drcov format, gzip compressed at the given level.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - The writer that receives the compressed trace. Use
+    ///   [`Trace::write_compressed_buffered`] to control the size of the writes it sees.
+    /// * `level` - The compression level, from 0 (no compression) to 9 (best compression).
+    ///   Other levels are rejected with [`ErrorKind::InvalidInput`].
+    pub fn write_compressed_with(&self, writer: &mut impl Write, level: u32) -> Result<(), Error> {
+        let mut encoder = GzEncoder::new(writer, compression(level)?);
+        self.write(&mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    /// Output the coverage information in drcov format, gzip compressed at the given level,
+    /// buffering the compressed output.
+    ///
+    /// The compressed bytes are collected in a buffer of `buffer_size` bytes, so
+    /// `writer` sees few large writes, which suits files and sockets. The level is
+    /// checked as by [`Trace::write_compressed_with`].
+    pub fn write_compressed_buffered(
+        &self,
+        writer: &mut impl Write,
+        level: u32,
+        buffer_size: usize,
+    ) -> Result<(), Error> {
+        let mut buffered = BufWriter::with_capacity(buffer_size, writer);
+        self.write_compressed_with(&mut buffered, level)?;
+        buffered.flush()
+    }
+}
+
+/// Returns the compression for a level from 0 to 9.
+fn compression(level: u32) -> Result<Compression, Error> {
+    match level {
+        0..=9 => Ok(Compression::new(level)),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid gzip compression level {level}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+    use flate2::read::GzDecoder;
+
+    #[test]
+    fn compressed_round_trip() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        trace.add(0x1204, 3);
+
+        for level in [0, 1, 9] {
+            let mut out = Vec::new();
+            trace.write_compressed_with(&mut out, level).unwrap();
+
+            let parsed = Trace::from_reader(GzDecoder::new(out.as_slice())).unwrap();
+            assert_eq!(parsed.entries(), trace.entries());
+        }
+
+        let mut buffered = Vec::new();
+        trace
+            .write_compressed_buffered(&mut buffered, 6, 16)
+            .unwrap();
+        let parsed = Trace::from_reader(GzDecoder::new(buffered.as_slice())).unwrap();
+        assert_eq!(parsed.entries(), trace.entries());
+
+        let err = trace
+            .write_compressed_with(&mut Vec::new(), 10)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
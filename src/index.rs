@@ -0,0 +1,302 @@
+use std::collections::{BTreeSet, HashSet};
+use std::ops::Range;
+
+use crate::{BlockEntry, Module, Trace};
+
+/// A read-only [`Trace`] with precomputed indices for fast coverage queries.
+///
+/// Created with [`Trace::index`]. Use [`IndexedTrace::into_trace`] to get the
+/// mutable trace back.
+#[derive(Clone, Debug)]
+pub struct IndexedTrace {
+    trace: Trace,
+    /// The distinct recorded blocks.
+    blocks: HashSet<BlockEntry>,
+    /// Per module, the distinct block start offsets in ascending order.
+    starts: Vec<Vec<u32>>,
+    /// Per module, the distinct blocks sorted by start offset.
+    sorted: Vec<Vec<BlockEntry>>,
+    /// Per module, the covered offset ranges, sorted and with overlaps merged.
+    covered: Vec<Vec<Range<u64>>>,
+    /// The module containing each address, for binary search lookups.
+    lookup: ModuleLookup,
+}
+
+/// The module containing each address, as found by [`Trace::try_add`] when all modules are active.
+///
+/// The address space is split at every module base and end, and each part holds the
+/// lowest id of the modules overlapping it, so lookups are a binary search even if
+/// modules overlap.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ModuleLookup {
+    /// The start address of each part, in ascending order.
+    starts: Vec<u64>,
+    /// The module containing each part, parallel to `starts`.
+    ids: Vec<Option<usize>>,
+}
+
+impl ModuleLookup {
+    /// Build the lookup table for the given modules.
+    pub(crate) fn new(modules: &[Module]) -> ModuleLookup {
+        let mut events = Vec::with_capacity(modules.len() * 2);
+        for (id, module) in modules.iter().enumerate() {
+            events.push((module.base, true, id));
+            events.push((module.end, false, id));
+        }
+        events.sort_unstable_by_key(|&(address, _, _)| address);
+
+        let mut lookup = ModuleLookup::default();
+        let mut open = BTreeSet::new();
+        let mut events = events.into_iter().peekable();
+        while let Some((address, start, id)) = events.next() {
+            match start {
+                true => open.insert(id),
+                false => open.remove(&id),
+            };
+            if events.peek().is_some_and(|&(next, _, _)| next == address) {
+                continue;
+            }
+            let first = open.first().copied();
+            if lookup.ids.last() != Some(&first) {
+                lookup.starts.push(address);
+                lookup.ids.push(first);
+            }
+        }
+        lookup
+    }
+
+    /// Returns the lowest id of the modules containing the address.
+    pub(crate) fn find(&self, address: u64) -> Option<usize> {
+        let index = self.starts.partition_point(|&start| start <= address);
+        *self.ids.get(index.checked_sub(1)?)?
+    }
+}
+
+impl Trace {
+    /// Build the indices used for fast coverage queries, consuming the trace.
+    pub fn index(self) -> IndexedTrace {
+        let blocks = self.entries.iter().copied().collect::<HashSet<_>>();
+
+        let mut starts = vec![Vec::new(); self.modules.len()];
+        let mut ranges = vec![Vec::new(); self.modules.len()];
+        for entry in &blocks {
+            let start = u64::from(entry.start);
+            starts[usize::from(entry.mod_id)].push(entry.start);
+            ranges[usize::from(entry.mod_id)].push(start..start + u64::from(entry.size));
+        }
+        for starts in &mut starts {
+            starts.sort_unstable();
+            starts.dedup();
+        }
+        let mut sorted = vec![Vec::new(); self.modules.len()];
+        for entry in &blocks {
+            sorted[usize::from(entry.mod_id)].push(*entry);
+        }
+        for sorted in &mut sorted {
+            sorted.sort_unstable_by_key(|entry| (entry.start, entry.size));
+        }
+
+        IndexedTrace {
+            lookup: ModuleLookup::new(&self.modules),
+            trace: self,
+            blocks,
+            starts,
+            sorted,
+            covered: ranges.into_iter().map(merge_ranges).collect(),
+        }
+    }
+}
+
+impl IndexedTrace {
+    /// Returns a reference to the underlying [`Trace`].
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    /// Discard the indices and return the underlying [`Trace`].
+    pub fn into_trace(self) -> Trace {
+        self.trace
+    }
+
+    /// Returns true if the given address is covered by any recorded block.
+    ///
+    /// This is a binary search over the merged block ranges of the containing module.
+    pub fn is_covered(&self, address: u64) -> bool {
+        let Some((mod_id, offset)) = self.locate(address) else {
+            return false;
+        };
+
+        let covered = &self.covered[mod_id];
+        let index = covered.partition_point(|range| range.end <= offset);
+        covered
+            .get(index)
+            .is_some_and(|range| range.contains(&offset))
+    }
+
+    /// Returns true if a block with exactly this start address and size was recorded.
+    pub fn contains_block(&self, address: u64, size: u16) -> bool {
+        let Some((mod_id, offset)) = self.locate(address) else {
+            return false;
+        };
+        let Ok(start) = u32::try_from(offset) else {
+            return false;
+        };
+
+        self.blocks.contains(&BlockEntry {
+            start,
+            size,
+            mod_id: mod_id as u16,
+        })
+    }
+
+    /// Returns the distinct recorded blocks that cover the given address, sorted by start offset.
+    ///
+    /// This is a binary search over the blocks of the containing module, as blocks
+    /// are at most `u16::MAX` bytes long.
+    pub fn blocks_hitting(&self, address: u64) -> Vec<BlockEntry> {
+        let Some((mod_id, offset)) = self.locate(address) else {
+            return Vec::new();
+        };
+
+        let sorted = &self.sorted[mod_id];
+        let first = offset.saturating_sub(u64::from(u16::MAX));
+        let from = sorted.partition_point(|entry| u64::from(entry.start) < first);
+        let to = sorted.partition_point(|entry| u64::from(entry.start) <= offset);
+        sorted[from..to]
+            .iter()
+            .filter(|entry| offset < u64::from(entry.start) + u64::from(entry.size))
+            .copied()
+            .collect()
+    }
+
+    /// Returns the distinct start addresses of the blocks recorded in the module `mod_id`, in ascending order of offset.
+    ///
+    /// Addresses past the end of the address space wrap around, as by
+    /// [`CoveredBlock::address`](crate::CoveredBlock::address).
+    pub fn addresses_in_module(&self, mod_id: usize) -> impl Iterator<Item = u64> + '_ {
+        let base = self.trace.modules.get(mod_id).map_or(0, |m| m.base);
+        let starts = self.starts.get(mod_id).map_or(&[][..], Vec::as_slice);
+
+        starts
+            .iter()
+            .map(move |&start| base.wrapping_add(u64::from(start)))
+    }
+
+    /// Returns the id of the module containing the address and the offset within it.
+    fn locate(&self, address: u64) -> Option<(usize, u64)> {
+        let mod_id = self.lookup.find(address)?;
+
+        Some((mod_id, address - self.trace.modules[mod_id].base))
+    }
+}
+
+/// Sort the ranges and merge those that overlap or touch.
+pub(crate) fn merge_ranges(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_unstable_by_key(|range| range.start);
+
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    #[test]
+    fn indexed_queries() {
+        let mut trace = Trace::new(&[
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("libc.so", 0x5000, 0x6000),
+        ]);
+        trace.add(0x1030, 4);
+        trace.add(0x1010, 8);
+        trace.add(0x1014, 8);
+        trace.add(0x1010, 8);
+
+        let index = trace.index();
+
+        assert!(index.is_covered(0x1010));
+        assert!(index.is_covered(0x101b));
+        assert!(!index.is_covered(0x101c));
+        assert!(index.is_covered(0x1033));
+        assert!(!index.is_covered(0x5000));
+        assert!(!index.is_covered(0xdead));
+
+        assert!(index.contains_block(0x1014, 8));
+        assert!(!index.contains_block(0x1014, 4));
+
+        assert_eq!(
+            index.addresses_in_module(0).collect::<Vec<_>>(),
+            [0x1010, 0x1014, 0x1030]
+        );
+        assert_eq!(index.addresses_in_module(1).count(), 0);
+        assert_eq!(index.addresses_in_module(2).count(), 0);
+
+        let hitting = index
+            .blocks_hitting(0x1016)
+            .iter()
+            .map(|e| (e.start(), e.size()))
+            .collect::<Vec<_>>();
+        assert_eq!(hitting, [(0x10, 8), (0x14, 8)]);
+        assert_eq!(
+            index.blocks_hitting(0x101b),
+            index.trace().blocks_hitting(0x101b)
+        );
+        assert!(index.blocks_hitting(0x1020).is_empty());
+        assert_eq!(index.into_trace().entries().len(), 4);
+    }
+
+    #[test]
+    fn addresses_past_the_address_space_wrap() {
+        let mut trace = Trace::new(&[Module::new(
+            "top",
+            0xffff_ffff_ffff_f000,
+            0xffff_ffff_ffff_ffff,
+        )]);
+        trace.push_raw(0, 0x10, 4).unwrap();
+        trace.push_raw(0, 0x10_0000, 4).unwrap();
+
+        let index = trace.index();
+        assert_eq!(
+            index.addresses_in_module(0).collect::<Vec<_>>(),
+            [0xffff_ffff_ffff_f010, 0xf_f000]
+        );
+    }
+
+    #[test]
+    fn contains_block_rejects_offsets_beyond_u32() {
+        let mut module = Module::new("huge", 0x1000, 0x2000);
+        module.end = 0x2_0000_1000;
+        let mut trace = Trace::new(&[module]);
+        trace.push_raw(0, 0x10, 4).unwrap();
+
+        let index = trace.index();
+        assert!(index.contains_block(0x1010, 4));
+        assert!(!index.contains_block(0x1_0000_1010, 4));
+    }
+
+    #[test]
+    fn module_lookup_matches_trace() {
+        let modules = [
+            Module::new("abcd", 0x4000, 0x6000),
+            Module::new("libc.so", 0x1000, 0x5000),
+            Module::new("libm.so", 0x2000, 0x8000),
+        ];
+        let lookup = ModuleLookup::new(&modules);
+        let trace = Trace::new(&modules);
+        for address in (0..0x9000).step_by(0x800) {
+            let expected = trace
+                .get_module(address)
+                .map(|module| modules.iter().position(|m| m == module).unwrap());
+            assert_eq!(lookup.find(address), expected, "{address:#x}");
+        }
+        assert_eq!(lookup.find(u64::MAX), None);
+    }
+}
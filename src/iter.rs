@@ -0,0 +1,232 @@
+use crate::{BlockEntry, Module, Trace};
+
+/// A recorded basic block resolved to its absolute address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CoveredBlock {
+    address: u64,
+    size: u16,
+    mod_id: u16,
+}
+
+impl CoveredBlock {
+    /// Resolve an entry against the base address of its module.
+    fn new(entry: &BlockEntry, base: u64) -> CoveredBlock {
+        CoveredBlock {
+            // Offsets are not checked against the module size, so they may pass the end of the address space.
+            address: base.wrapping_add(u64::from(entry.start)),
+            size: entry.size,
+            mod_id: entry.mod_id,
+        }
+    }
+
+    /// Returns the absolute start address of the basic block.
+    ///
+    /// The address wraps around if the offset of the block reaches past the end of
+    /// the address space, which only happens for offsets beyond the end of their
+    /// module, as recorded by [`Trace::push_raw`] or read from a file.
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    /// Returns the size of the basic block in bytes.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the id of the module where the basic block is located.
+    pub fn mod_id(&self) -> u16 {
+        self.mod_id
+    }
+}
+
+impl Trace {
+    /// Iterate over the recorded basic blocks in recording order, resolved to absolute addresses.
+    pub fn blocks(&self) -> impl Iterator<Item = CoveredBlock> + '_ {
+        self.entries
+            .iter()
+            .map(|entry| CoveredBlock::new(entry, self.modules[usize::from(entry.mod_id)].base))
+    }
+
+    /// Returns the recorded block at the given position in recording order, resolved to its absolute address.
+    ///
+    /// Returns None if `index` is not less than [`Trace::len`].
+    pub fn entry_at(&self, index: usize) -> Option<CoveredBlock> {
+        let entry = self.entries.get(index)?;
+        Some(CoveredBlock::new(
+            entry,
+            self.modules[usize::from(entry.mod_id)].base,
+        ))
+    }
+
+    /// Returns the number of recorded entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no entries are recorded.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// An iterator over the basic blocks of a consumed [`Trace`], created by [`Trace::into_iter`].
+#[derive(Clone, Debug)]
+pub struct IntoIter {
+    entries: std::vec::IntoIter<BlockEntry>,
+    /// Base address of each module, indexed by module id.
+    bases: Vec<u64>,
+}
+
+impl Iterator for IntoIter {
+    type Item = CoveredBlock;
+
+    fn next(&mut self) -> Option<CoveredBlock> {
+        let entry = self.entries.next()?;
+        Some(CoveredBlock::new(
+            &entry,
+            self.bases[usize::from(entry.mod_id)],
+        ))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl ExactSizeIterator for IntoIter {}
+
+impl IntoIterator for Trace {
+    type Item = CoveredBlock;
+    type IntoIter = IntoIter;
+
+    /// Consume the trace, yielding its basic blocks in recording order.
+    fn into_iter(self) -> IntoIter {
+        IntoIter {
+            bases: self.modules.iter().map(|m| m.base).collect(),
+            entries: self.entries.into_iter(),
+        }
+    }
+}
+
+impl Trace {
+    /// Create a trace from the blocks reported by a callback driven tracer.
+    ///
+    /// `f` is called once with an `emit` function, which records a block from its
+    /// start address and size each time it is called, as by [`Trace::try_add`].
+    /// Blocks that cannot be recorded are dropped, so `emit` never panics.
+    pub fn from_callback(modules: &[Module], f: impl FnOnce(&mut dyn FnMut(u64, usize))) -> Trace {
+        let mut trace = Trace::new(modules);
+        f(&mut |address, size| {
+            let _ = trace.try_add(address, size);
+        });
+        trace
+    }
+
+    /// Record blocks given as start address and size, reporting progress along the way.
+    ///
+    /// `on_progress` is called with the number of blocks processed so far after
+    /// every `every` blocks, and never if `every` is 0. Blocks are recorded as by
+    /// [`Trace::add`].
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if a block cannot be recorded.
+    pub fn extend_with_progress(
+        &mut self,
+        blocks: impl IntoIterator<Item = (u64, usize)>,
+        every: usize,
+        mut on_progress: impl FnMut(usize),
+    ) {
+        for (index, (address, size)) in blocks.into_iter().enumerate() {
+            self.add(address, size);
+            if every != 0 && (index + 1) % every == 0 {
+                on_progress(index + 1);
+            }
+        }
+    }
+}
+
+impl Extend<CoveredBlock> for Trace {
+    /// Record each block by its absolute address, as by [`Trace::add`].
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if a block cannot be recorded.
+    fn extend<T: IntoIterator<Item = CoveredBlock>>(&mut self, blocks: T) {
+        for block in blocks {
+            self.add(block.address, usize::from(block.size));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn into_iter_resolves_addresses() {
+        let mut trace = Trace::new(&[
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("libc.so", 0x5000, 0x6000),
+        ]);
+        trace.add(0x5020, 8);
+        trace.add(0x1010, 4);
+
+        let borrowed = trace.blocks().collect::<Vec<_>>();
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace.entry_at(1), Some(borrowed[1]));
+        assert_eq!(trace.entry_at(2), None);
+        let mut copy = Trace::new(trace.modules());
+        let owned = trace.into_iter().collect::<Vec<_>>();
+
+        assert_eq!(borrowed, owned);
+        let blocks = owned
+            .iter()
+            .map(|b| (b.address(), b.size(), b.mod_id()))
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, [(0x5020, 8, 1), (0x1010, 4, 0)]);
+        assert_eq!(copy.len(), 0);
+
+        copy.extend(owned);
+        assert_eq!(copy.blocks().collect::<Vec<_>>(), borrowed);
+
+        let mut progress = Vec::new();
+        let blocks = (0..10).map(|i| (0x1000 + i * 4, 4));
+        copy.extend_with_progress(blocks, 4, |count| progress.push(count));
+        assert_eq!(progress, [4, 8]);
+        assert_eq!(copy.len(), 12);
+    }
+
+    #[test]
+    fn from_callback_drops_unknown_blocks() {
+        let trace = Trace::from_callback(&[Module::new("abcd", 0x1000, 0x2000)], |emit| {
+            emit(0x1010, 4);
+            emit(0x3000, 4);
+            emit(0x1020, 0x10000);
+            emit(0x1030, 8);
+        });
+
+        let blocks = trace
+            .blocks()
+            .map(|b| (b.address(), b.size()))
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, [(0x1010, 4), (0x1030, 8)]);
+    }
+
+    #[test]
+    fn blocks_past_the_address_space_wrap() {
+        let mut trace = Trace::new(&[Module::new(
+            "top",
+            0xffff_ffff_ffff_f000,
+            0xffff_ffff_ffff_ffff,
+        )]);
+        trace.push_raw(0, 0x10_0000, 4).unwrap();
+
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        let parsed = Trace::from_reader(out.as_slice()).unwrap();
+        assert_eq!(parsed.blocks().count(), 1);
+        assert_eq!(parsed.entry_at(0).unwrap().address(), 0xf_f000);
+        assert_eq!(parsed.into_iter().count(), 1);
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use crate::Trace;
+
+impl Trace {
+    /// Export the coverage as approximate line coverage in the lcov tracefile format.
+    ///
+    /// `mapping` resolves a module id and block offset to a source file and line,
+    /// typically from debug information, and returns None for blocks without line
+    /// information. Each distinct block start is resolved once and every resolved
+    /// line is reported as hit once, as `DA:<line>,1`. Files and lines are sorted,
+    /// so the output is deterministic.
+    pub fn to_lcov(&self, mapping: impl Fn(usize, u32) -> Option<(PathBuf, u32)>) -> String {
+        let starts = self
+            .entries
+            .iter()
+            .map(|entry| (usize::from(entry.mod_id), entry.start))
+            .collect::<BTreeSet<_>>();
+
+        let mut files = BTreeMap::<PathBuf, BTreeSet<u32>>::new();
+        for (mod_id, start) in starts {
+            if let Some((file, line)) = mapping(mod_id, start) {
+                files.entry(file).or_default().insert(line);
+            }
+        }
+
+        let mut lcov = String::new();
+        for (file, lines) in files {
+            // Writing to a string cannot fail.
+            let _ = writeln!(lcov, "SF:{}", file.display());
+            for line in &lines {
+                let _ = writeln!(lcov, "DA:{line},1");
+            }
+            let _ = writeln!(
+                lcov,
+                "LF:{}\nLH:{}\nend_of_record",
+                lines.len(),
+                lines.len()
+            );
+        }
+        lcov
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    #[test]
+    fn lcov_groups_lines_by_file() {
+        let mut trace = Trace::new(&[
+            Module::new("target", 0x1000, 0x2000),
+            Module::new("libc.so", 0x5000, 0x6000),
+        ]);
+        trace.add(0x1020, 4);
+        trace.add(0x1010, 4);
+        trace.add(0x1010, 4);
+        trace.add(0x1030, 4);
+        trace.add(0x5000, 4);
+
+        let lcov = trace.to_lcov(|mod_id, offset| match (mod_id, offset) {
+            (0, 0x10) => Some((PathBuf::from("src/main.c"), 12)),
+            (0, 0x20) => Some((PathBuf::from("src/util.c"), 3)),
+            (0, 0x30) => Some((PathBuf::from("src/main.c"), 7)),
+            _ => None,
+        });
+        assert_eq!(
+            lcov,
+            "SF:src/main.c\nDA:7,1\nDA:12,1\nLF:2\nLH:2\nend_of_record\n\
+             SF:src/util.c\nDA:3,1\nLF:1\nLH:1\nend_of_record\n"
+        );
+    }
+}
@@ -17,26 +17,119 @@
 //! trace.add(0x1207, 12);
 //!
 //! // Save the code coverage to a file in drcov format.
+//! # let dir = std::env::temp_dir();
+//! # std::env::set_current_dir(&dir).unwrap();
 //! trace.save("trace.log").unwrap();
 //! ```
+//!
+//! # File format
+//!
+//! [`Trace::write`] emits a text header followed by a binary basic block table:
+//!
+//! ```text
+//! DRCOV VERSION: 2
+//! DRCOV FLAVOR: drcov
+//! Module Table: version 4, count 2
+//! Columns: id, containing_id, start, end, entry, offset, path
+//! 0, 0, 0x400000, 0x428000, 0, 0x0, /bin/target
+//! 1, 1, 0x7f0000000000, 0x7f0000200000, 0, 0x0, libc.so.6
+//! BB Table: 3 bbs
+//! ```
+//!
+//! Each line ends with `\n` unless [`LineEnding::CrLf`] is selected. The BB table
+//! line is followed by one 8-byte entry per basic block: the `u32` offset from the
+//! module start, the `u16` block size and the `u16` module id, all little-endian.
+
+use std::borrow::Cow;
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Write};
+use std::ops::Range;
+
+#[cfg(feature = "autosave")]
+mod autosave;
+mod banked;
+mod binary;
+mod buffer;
+mod canonical;
+mod corpus;
+mod diff;
+mod error;
+mod frame;
+mod guard;
+#[cfg(feature = "gzip")]
+mod gzip;
+mod index;
+mod iter;
+mod lcov;
+#[cfg(all(feature = "linux", target_os = "linux"))]
+mod linux;
+mod merge;
+mod multi;
+mod paged;
+mod parse;
+#[cfg(feature = "regex")]
+mod pattern;
+mod per_thread;
+pub mod prelude;
+mod provisional;
+mod remap;
+mod shared;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
+#[cfg(feature = "object")]
+mod validate;
+mod verify;
+
+#[cfg(feature = "autosave")]
+pub use autosave::AutosaveHandle;
+pub use banked::bank_high_bits;
+pub use corpus::{BlockFrequency, CorpusAnalysis};
+pub use diff::{DiffStats, ModuleDiff};
+pub use error::{AddError, DrcovError, MappingError, ModuleError, TableError};
+pub use frame::FrameCodec;
+pub use guard::TraceGuard;
+pub use index::IndexedTrace;
+pub use iter::{CoveredBlock, IntoIter};
+pub use merge::{merge_files, ConflictPolicy, MergeOptions};
+pub use multi::MultiProcessTrace;
+pub use paged::PagedTrace;
+pub use parse::{parse_visit, Visitor};
+pub use per_thread::PerThreadTrace;
+pub use provisional::EntryHandle;
+pub use shared::{SharedModules, ThreadTrace};
+pub use verify::Issue;
 
-use std::io::{Error, Write};
+/// Create a [`Trace`] with a single module spanning the given address range.
+///
+/// ```rust
+/// let mut trace = drcov_rs::record!("target", 0x400000..0x500000);
+/// trace.add(0x401000, 16);
+/// ```
+///
+/// # Panics
+///
+/// This macro will panic if the range is empty or larger than `u32::MAX` bytes, as
+/// by [`Module::new`].
+#[macro_export]
+macro_rules! record {
+    ($name:expr, $range:expr $(,)?) => {{
+        let range: ::std::ops::Range<u64> = $range;
+        $crate::Trace::new(&[$crate::Module::new($name, range.start, range.end)])
+    }};
+}
 
 /// The version of the drcov format to use.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum Version {
     /// Drcov version 2.
+    #[default]
     V2,
     /// Drcov version 3.
     V3,
 }
 
-impl Default for Version {
-    fn default() -> Self {
-        Version::V2
-    }
-}
-
 impl std::fmt::Display for Version {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match *self {
@@ -46,17 +139,172 @@ impl std::fmt::Display for Version {
     }
 }
 
+impl Version {
+    /// Returns the module table version written by default for this file version.
+    ///
+    /// Both file versions use a version 4 module table, which is what current drcov emits.
+    pub fn default_module_table_version(self) -> u32 {
+        4
+    }
+
+    /// Returns true if a module table of the given version may be written in a file of this version.
+    ///
+    /// Version 2 files carry module tables of version 2, 3 or 4. This crate only
+    /// writes version 3 files with a version 4 module table.
+    pub fn supports_module_table_version(self, module_table_version: u32) -> bool {
+        match self {
+            Version::V2 => (2..=4).contains(&module_table_version),
+            Version::V3 => module_table_version == 4,
+        }
+    }
+}
+
+/// The line terminator used for the text portion of a drcov file.
+///
+/// The binary BB table is not affected by this setting.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    /// Unix style `\n` line endings.
+    #[default]
+    Lf,
+    /// Windows style `\r\n` line endings.
+    CrLf,
+}
+
+impl std::fmt::Display for LineEnding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match *self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        })
+    }
+}
+
+/// The byte order of the entries in a BB table.
+///
+/// Traces are written little-endian, as drcov specifies. Big-endian tables are
+/// only read, for traces from big-endian tracers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Endianness {
+    /// Least significant byte first.
+    #[default]
+    Little,
+    /// Most significant byte first.
+    Big,
+}
+
+/// The order of the sections of a written trace, see [`Trace::write_with_layout`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// The module table followed by the BB table, as specified by drcov.
+    #[default]
+    Standard,
+    /// The BB table followed by the module table.
+    ///
+    /// This layout is non-standard and most drcov consumers cannot read it.
+    BbTableFirst,
+}
+
+/// A tool that consumes drcov files, see [`Trace::save_as`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tool {
+    /// The Lighthouse coverage plugin, which expects a `.drcov` file.
+    Lighthouse,
+    /// The IDA coverage plugins, which expect a `.cov` file.
+    Ida,
+    /// The DynamoRIO tools such as `drcov2lcov`, which expect a `.log` file.
+    DynamoRio,
+}
+
+impl Tool {
+    /// Returns the file extension that the tool expects, without the leading dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Tool::Lighthouse => "drcov",
+            Tool::Ida => "cov",
+            Tool::DynamoRio => "log",
+        }
+    }
+}
+
+/// What to do when a block with a size of zero is recorded.
+///
+/// Some consumers reject zero-size blocks, while others treat them as a single point.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ZeroSizePolicy {
+    /// Record the block with a size of zero.
+    #[default]
+    Allow,
+    /// Record the block with a size of one byte instead.
+    PromoteToOne,
+    /// Do not record the block and return [`AddError::ZeroSize`].
+    Reject,
+}
+
+/// Which fields identify a block when removing duplicates with [`Trace::dedup_with`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DedupKey {
+    /// Blocks are duplicates if their module, start and size are equal.
+    #[default]
+    StartSize,
+    /// Blocks are duplicates if their module and start are equal, and the largest
+    /// size is kept.
+    ///
+    /// This collapses blocks that a tracer reported with inconsistent sizes, for
+    /// example after re-splitting them, but also merges genuinely different blocks
+    /// that share a start.
+    StartOnly,
+}
+
 /// Represents a collection of code coverage information.
 #[derive(Clone, Debug)]
 pub struct Trace {
     /// Collection of all modules added to the trace.
     modules: Vec<Module>,
     /// Collection of all basic block entries recorded in the trace.
-    entries: Vec<BasicBlockEntry>,
+    entries: buffer::Buffer<BlockEntry>,
     /// A string used to describe the tool that generated the coverage information.
-    flavor: &'static str,
+    flavor: String,
     /// The drcov file format version to use.
     version: Version,
+    /// The line terminator used for the text header and module table.
+    line_ending: LineEnding,
+    /// Per-entry hit counts, present when hit counting is enabled.
+    hits: Option<HitCounts>,
+    /// The block size recorded by [`Trace::add_point`].
+    point_size: u16,
+    /// The version of the module table layout to write.
+    module_table_version: u32,
+    /// Blocks waiting for their size, created by [`Trace::add_provisional`].
+    provisional: provisional::Provisional,
+    /// The maximum number of entries to retain, set by [`Trace::with_entry_limit`].
+    entry_limit: Option<usize>,
+    /// The number of entries at which recording stops, set by [`Trace::with_max_entries`].
+    max_entries: Option<usize>,
+    /// How blocks with a size of zero are recorded.
+    zero_size: ZeroSizePolicy,
+    /// How [`Trace::add_banked`] folds memory banks into block offsets.
+    bank_encoder: banked::BankEncoder,
+    /// Whether each module is matched by [`Trace::try_add`], or None if all modules are.
+    active: Option<Vec<bool>>,
+    /// Sorted, disjoint address ranges whose blocks are not recorded.
+    exclusions: Vec<Range<u64>>,
+    /// The source tag of each block first recorded by [`Trace::merge_tagged`].
+    tags: HashMap<BlockEntry, u32>,
+    /// Key and value pairs written as comment lines after the flavor.
+    metadata: Vec<(String, String)>,
+}
+
+/// Tracks how many times each unique entry of a [`Trace`] was recorded.
+#[derive(Clone, Debug, Default)]
+struct HitCounts {
+    /// Hit count of each entry, parallel to `Trace::entries`.
+    counts: buffer::Buffer<u32>,
+    /// Index of each unique entry in `Trace::entries`, plus `evicted`.
+    index: HashMap<BlockEntry, usize>,
+    /// The number of entries evicted since the index was built, so eviction does
+    /// not have to shift every index.
+    evicted: usize,
 }
 
 impl Trace {
@@ -68,10 +316,281 @@ impl Trace {
     pub fn new(modules: &[Module]) -> Trace {
         Trace {
             modules: modules.to_vec(),
-            entries: Vec::new(),
-            flavor: "drcov",
+            entries: buffer::Buffer::default(),
+            flavor: String::from("drcov"),
             version: Version::default(),
+            line_ending: LineEnding::default(),
+            hits: None,
+            point_size: 1,
+            module_table_version: Version::default().default_module_table_version(),
+            provisional: Default::default(),
+            entry_limit: None,
+            max_entries: None,
+            zero_size: ZeroSizePolicy::default(),
+            bank_encoder: Default::default(),
+            active: None,
+            exclusions: Vec::new(),
+            tags: HashMap::new(),
+            metadata: Vec::new(),
+        }
+    }
+
+    /// Create a new drcov trace that only retains the most recently recorded entries.
+    ///
+    /// Once `limit` entries are stored, recording another one evicts the oldest, so
+    /// the trace describes recent coverage rather than all coverage, for example the
+    /// blocks that led up to a crash. With hit counting the limit applies to distinct
+    /// blocks, and the block that was first recorded longest ago is evicted. Eviction
+    /// takes amortized constant time, and the trace holds storage for up to twice
+    /// `limit` entries. A block tag set by [`Trace::merge_tagged`] is dropped when the
+    /// entry that carries it is evicted.
+    pub fn with_entry_limit(modules: &[Module], limit: usize) -> Trace {
+        Trace {
+            entry_limit: Some(limit),
+            ..Trace::new(modules)
+        }
+    }
+
+    /// Create a new drcov trace that stops recording once `max` entries are stored.
+    ///
+    /// Unlike [`Trace::with_entry_limit`] nothing is evicted: recording a new block
+    /// at the limit fails with [`AddError::LimitReached`], so the caller can flush the
+    /// entries, for example with [`Trace::drain_entries`], and continue, or abort.
+    /// With hit counting, blocks that are already stored are still counted. The
+    /// maximum applies to every function that records new blocks, including
+    /// [`Trace::merge`] and [`Trace::apply_frame`]. Functions that only rearrange the
+    /// stored entries, such as [`Trace::map_addresses`] or [`Trace::split_by_module`],
+    /// never store more entries than before and are not limited.
+    pub fn with_max_entries(modules: &[Module], max: usize) -> Trace {
+        Trace {
+            max_entries: Some(max),
+            ..Trace::new(modules)
+        }
+    }
+
+    /// Configure the trace to match the output of QEMU's `contrib/plugins/drcov` plugin.
+    ///
+    /// This selects the `drcov-64` flavor and a version 2 module table, which is the
+    /// layout expected by QEMU based tooling.
+    pub fn qemu_compatible(self) -> Trace {
+        Trace {
+            flavor: String::from("drcov-64"),
+            version: Version::V2,
+            module_table_version: 2,
+            ..self
+        }
+    }
+
+    /// Add a provenance metadata entry, such as the tool version or command line.
+    ///
+    /// Each entry is written as a `# key: value` comment line after the flavor line,
+    /// and collected again by the parser. This assumes that readers skip lines
+    /// starting with `#` before the module table; drcov itself has no such lines.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the key or value contains a line break, or if
+    /// the key contains `": "`.
+    pub fn add_metadata(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        let (key, value) = (key.into(), value.into());
+        assert!(
+            !key.contains(['\r', '\n']) && !value.contains(['\r', '\n']),
+            "Metadata must not contain line breaks"
+        );
+        assert!(!key.contains(": "), "Metadata keys must not contain `: `");
+        self.metadata.push((key, value));
+    }
+
+    /// Returns the metadata entries in the order they were added or read.
+    pub fn metadata(&self) -> &[(String, String)] {
+        &self.metadata
+    }
+
+    /// Returns the drcov file format version that is written.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Set the drcov file format version to write.
+    ///
+    /// If the module table version cannot be used with the new file version, it is
+    /// reset to [`Version::default_module_table_version`].
+    pub fn set_version(&mut self, version: Version) {
+        self.version = version;
+        if !version.supports_module_table_version(self.module_table_version) {
+            self.module_table_version = version.default_module_table_version();
+        }
+    }
+
+    /// Set the drcov file format version to write, keeping the module table version.
+    ///
+    /// Unlike [`Trace::set_version`], the two versions are independent, so any
+    /// combination seen in the wild can be reproduced. A combination that
+    /// [`Version::supports_module_table_version`] rejects is still written, and logs
+    /// a warning with the `log` feature.
+    pub fn set_file_version(&mut self, version: Version) {
+        self.version = version;
+        self.warn_unusual_versions();
+    }
+
+    /// Returns the trace with the drcov file format version set, as by [`Trace::set_version`].
+    pub fn with_version(mut self, version: Version) -> Trace {
+        self.set_version(version);
+        self
+    }
+
+    /// Returns the version of the module table layout that is written.
+    pub fn module_table_version(&self) -> u32 {
+        self.module_table_version
+    }
+
+    /// Override the version of the module table layout that is written.
+    ///
+    /// By default this is [`Version::default_module_table_version`] of the file version.
+    /// The module table version is independent of the file version: a combination
+    /// that [`Version::supports_module_table_version`] rejects is still written, and
+    /// logs a warning with the `log` feature.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the module table version is not 2, 3 or 4, the
+    /// layouts this crate can write.
+    pub fn set_module_table_version(&mut self, module_table_version: u32) {
+        assert!(
+            (2..=4).contains(&module_table_version),
+            "Module table version {module_table_version} is not supported"
+        );
+        self.module_table_version = module_table_version;
+        self.warn_unusual_versions();
+    }
+
+    /// Log a warning if the file and module table versions are not usually combined.
+    fn warn_unusual_versions(&self) {
+        #[cfg(feature = "log")]
+        if !self
+            .version
+            .supports_module_table_version(self.module_table_version)
+        {
+            log::warn!(
+                "Module table version {} is unusual for drcov version {}",
+                self.module_table_version,
+                self.version
+            );
+        }
+    }
+
+    /// Set the line terminator used for the text header and module table.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.line_ending = line_ending;
+    }
+
+    /// Returns the modules in the trace, indexed by module id.
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+
+    /// Returns the basic block entries recorded in the trace, in recording order.
+    pub fn entries(&self) -> &[BlockEntry] {
+        &self.entries
+    }
+
+    /// Returns an empty trace with the same modules and settings as this one.
+    ///
+    /// The flavor, versions, metadata and recording settings such as hit counting and
+    /// the entry limit are kept, but no entries or provisional blocks are copied.
+    /// Use [`SharedModules`] to share a single module table between many recorders
+    /// instead of copying it.
+    pub fn clone_modules_only(&self) -> Trace {
+        let mut trace = self.with_entries(Vec::new());
+        trace.entry_limit = self.entry_limit;
+        trace.max_entries = self.max_entries;
+        if self.hits.is_some() {
+            trace.hits = Some(HitCounts::default());
+        }
+        trace
+    }
+
+    /// Consume the trace and return its modules, entries, version and flavor.
+    ///
+    /// Hit counts, metadata and recording settings are discarded. Use
+    /// [`Trace::from_parts`] to build a trace from the parts again.
+    pub fn into_parts(self) -> (Vec<Module>, Vec<BlockEntry>, Version, String) {
+        (
+            self.modules,
+            self.entries.into_vec(),
+            self.version,
+            self.flavor,
+        )
+    }
+
+    /// Create a trace from its modules, entries, version and flavor, as returned by [`Trace::into_parts`].
+    ///
+    /// Every entry must refer to one of the modules, else
+    /// [`DrcovError::DanglingModuleId`] is returned. All other settings take their
+    /// defaults, as in [`Trace::new`].
+    pub fn from_parts(
+        modules: Vec<Module>,
+        entries: Vec<BlockEntry>,
+        version: Version,
+        flavor: String,
+    ) -> Result<Trace, DrcovError> {
+        if let Some((entry_index, entry)) = entries
+            .iter()
+            .enumerate()
+            .find(|(_, entry)| usize::from(entry.mod_id) >= modules.len())
+        {
+            return Err(DrcovError::DanglingModuleId {
+                entry_index,
+                mod_id: entry.mod_id,
+            });
         }
+
+        let mut trace = Trace {
+            modules,
+            entries: entries.into(),
+            flavor,
+            ..Trace::new(&[])
+        };
+        trace.set_version(version);
+        Ok(trace)
+    }
+
+    /// Remove and return the recorded entries, keeping the modules for further recording.
+    ///
+    /// If hit counting is enabled, it stays enabled and the counts start again from
+    /// zero. Provisional blocks that have not been finalized yet are kept.
+    pub fn drain_entries(&mut self) -> Vec<BlockEntry> {
+        self.tags.clear();
+        if let Some(hits) = &mut self.hits {
+            *hits = HitCounts::default();
+        }
+        std::mem::take(&mut self.entries).into_vec()
+    }
+
+    /// Count how many times each block is hit instead of storing an entry for every hit.
+    ///
+    /// Once enabled, each unique block is stored as a single entry and recording it
+    /// again increments its hit count. Entries that were already recorded are
+    /// collapsed in the same way.
+    pub fn track_hit_counts(&mut self) {
+        if self.hits.is_some() {
+            return;
+        }
+
+        self.hits = Some(HitCounts::default());
+        for entry in std::mem::take(&mut self.entries) {
+            self.record(entry, 1);
+        }
+    }
+
+    /// Returns the hit count of each entry, parallel to [`Trace::entries`], or None if hit counting is not enabled.
+    pub fn hit_counts(&self) -> Option<&[u32]> {
+        self.hits.as_ref().map(|hits| hits.counts.as_slice())
+    }
+
+    /// Returns a reference to the [`Module`] that the given entry belongs to or None if its module id is unknown.
+    pub fn module_for_entry(&self, entry: &BlockEntry) -> Option<&Module> {
+        self.modules.get(usize::from(entry.mod_id))
     }
 
     /// Returns a reference to the [`Module`] containing the given address or None if an unknown address.
@@ -79,145 +598,2254 @@ impl Trace {
         self.modules.iter().find(|m| m.contains(address))
     }
 
+    /// Returns the distinct recorded blocks that cover the given address, in the order they were first recorded.
+    ///
+    /// Only blocks of the module containing the address are considered. This scans
+    /// every entry; use [`IndexedTrace::blocks_hitting`] for repeated queries.
+    pub fn blocks_hitting(&self, address: u64) -> Vec<BlockEntry> {
+        let Some(mod_id) = self.modules.iter().position(|m| m.contains(address)) else {
+            return Vec::new();
+        };
+        let offset = address - self.modules[mod_id].base;
+
+        let mut seen = HashSet::new();
+        self.entries
+            .iter()
+            .filter(|entry| {
+                let start = u64::from(entry.start);
+                usize::from(entry.mod_id) == mod_id
+                    && (start..start + u64::from(entry.size)).contains(&offset)
+            })
+            .filter(|entry| seen.insert(**entry))
+            .copied()
+            .collect()
+    }
+
     /// Add a new coverage entry to the [`Trace`].
     ///
     /// # Arguments
     ///
     /// * `address` - The start address of the basic block to record.
     /// * `size` - The size of the basic block in bytes.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the entry cannot be recorded. Use [`Trace::try_add`]
+    /// for a version that returns an error instead.
     pub fn add(&mut self, address: u64, size: usize) {
-        let entry = self
-            .modules
-            .iter()
-            .enumerate()
-            .find(|(_, m)| m.contains(address))
-            .map(|(id, module)| BasicBlockEntry {
-                start: (address - module.base).try_into().unwrap(),
-                size: size
-                    .try_into()
-                    .expect("Entry size is too large (u16::MAX < entry)"),
-                mod_id: id as u16,
-            })
-            .expect("No module found that contains that address");
+        if let Err(err) = self.try_add(address, size) {
+            panic!("{err}");
+        }
+    }
 
-        self.entries.push(entry);
+    /// Record that the given address was reached when the size of its basic block is unknown.
+    ///
+    /// This is a point coverage approximation: the address is recorded as a block of
+    /// one byte, or of the size set with [`Trace::set_point_size`].
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if no module contains the address.
+    pub fn add_point(&mut self, address: u64) {
+        self.add(address, usize::from(self.point_size));
     }
 
-    /// Output the coverage information in the appropriate drcov format.
-    pub fn write(&self, writer: &mut impl Write) -> Result<(), Error> {
-        // Write the drcov header.
-        writeln!(writer, "DRCOV VERSION: {}", self.version)?;
-        writeln!(writer, "DRCOV FLAVOR: {}", self.flavor)?;
+    /// Set the block size recorded by [`Trace::add_point`].
+    pub fn set_point_size(&mut self, size: u16) {
+        self.point_size = size;
+    }
 
-        // Write the module table.
-        writeln!(
-            writer,
-            "Module Table: version 4, count {}",
-            self.modules.len()
-        )?;
-        writeln!(
-            writer,
-            "Columns: id, containing_id, start, end, entry, offset, path"
-        )?;
+    /// Set how blocks with a size of zero are recorded.
+    ///
+    /// The policy applies to all functions that record a single block of a given
+    /// size, such as [`Trace::add`] and [`Trace::push_raw`], and to the blocks added
+    /// by [`Trace::merge`] and [`Trace::apply_frame`]. Entries that are already
+    /// recorded keep their size when they are rearranged, for example by
+    /// [`Trace::retain_modules`]. Zero-size blocks are allowed by default.
+    pub fn set_zero_size_policy(&mut self, policy: ZeroSizePolicy) {
+        self.zero_size = policy;
+    }
 
-        for (id, Module { name, base, end }) in self.modules.iter().enumerate() {
-            writeln!(writer, "{id}, 0, {base:#x}, {end:#x}, 0, 0, {name}")?;
-        }
+    /// Record blocks in the module `mod_id` from their start addresses alone.
+    ///
+    /// The starts are sorted and deduplicated first. Each block is assumed to run up
+    /// to the next start, capped at `u16::MAX` bytes, and the last block gets
+    /// `last_size`. Blocks are recorded in ascending order and recording stops at the
+    /// first block that cannot be recorded, as by [`Trace::add_in_module`].
+    pub fn add_inferred_sizes(
+        &mut self,
+        mod_id: usize,
+        starts: &[u64],
+        last_size: u16,
+    ) -> Result<(), AddError> {
+        let mut starts = starts.to_vec();
+        starts.sort_unstable();
+        starts.dedup();
 
-        // Write the basic block entries.
-        writeln!(writer, "BB Table: {} bbs", self.entries.len())?;
-        for entry in &self.entries {
-            entry.write(writer)?;
+        for (index, &start) in starts.iter().enumerate() {
+            let size = match starts.get(index + 1) {
+                Some(next) => (next - start).min(u64::from(u16::MAX)) as usize,
+                None => usize::from(last_size),
+            };
+            self.add_in_module(mod_id, start, size)?;
         }
-
         Ok(())
     }
 
-    /// Save the coverage trace to a file at the given path.
-    pub fn save(&self, path: impl AsRef<std::path::Path>) -> Result<(), Error> {
-        let mut file = std::fs::File::create(path)?;
-        self.write(&mut file)
+    /// Add a new coverage entry to the [`Trace`], returning an error if it cannot be recorded.
+    ///
+    /// This function never panics, regardless of its input.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The start address of the basic block to record.
+    /// * `size` - The size of the basic block in bytes.
+    pub fn try_add(&mut self, address: u64, size: usize) -> Result<(), AddError> {
+        if self.is_excluded(address) {
+            return Ok(());
+        }
+        match self.active_module(address)? {
+            Some(mod_id) => self.add_in_module(mod_id, address, size),
+            None => Ok(()),
+        }
     }
-}
 
-/// Contains information about a single module in the program's address space.
-#[derive(Clone, Copy, Debug)]
-pub struct Module {
-    name: &'static str,
-    base: u64,
-    end: u64,
-}
+    /// Returns the id of the module that [`Trace::try_add`] records a block at `address` in.
+    ///
+    /// Returns None if the address is only within inactive modules, and an error if
+    /// it is not within any module.
+    fn active_module(&self, address: u64) -> Result<Option<usize>, AddError> {
+        let mod_id = self
+            .modules
+            .iter()
+            .position(|m| m.contains(address))
+            .ok_or_else(|| dropped(AddError::NoModule { address }))?;
+        if self.is_active(mod_id) {
+            return Ok(Some(mod_id));
+        }
+        // Look for an active module that overlaps the inactive one, else skip the block.
+        Ok((mod_id..self.modules.len())
+            .find(|&id| self.is_active(id) && self.modules[id].contains(address)))
+    }
 
-impl Module {
-    /// Create a new [`Module`].
+    /// Restrict the modules matched by [`Trace::add`] and [`Trace::try_add`] to the given ids.
+    ///
+    /// Blocks within other modules are silently skipped, and `try_add` returns Ok for
+    /// them, while the modules stay in the module table. Functions that take an
+    /// explicit module id, such as [`Trace::add_in_module`], are not affected.
+    /// Modules added to the trace later are active, and operations that renumber the
+    /// modules make all modules active again.
     ///
     /// # Panics
     ///
-    /// This function will panic if the end address is smaller than the base address.
-    pub fn new(name: &'static str, base: u64, end: u64) -> Module {
-        assert!(base < end, "`base` must be before `end`");
-        assert!(
-            (end - base) <= u32::MAX as u64,
-            "Module sizes > u32::MAX are not representable"
-        );
-        Module { name, base, end }
+    /// This function will panic if any of the given ids is not a module of the trace.
+    pub fn set_active_modules(&mut self, ids: &[usize]) {
+        let mut active = vec![false; self.modules.len()];
+        for &id in ids {
+            assert!(id < active.len(), "Module id out of range");
+            active[id] = true;
+        }
+        self.active = Some(active);
     }
 
-    /// Returns true if the given address is within this `Module`.
-    pub fn contains(&self, address: u64) -> bool {
-        self.base <= address && address < self.end
+    /// Skip blocks that start within the given address range when recording.
+    ///
+    /// [`Trace::add`], [`Trace::try_add`] and [`Trace::add_in_module`] silently skip
+    /// such blocks, and the fallible functions return Ok for them, even if no module
+    /// contains the address. Exclusions may overlap and are merged. Each recorded
+    /// block is checked with a binary search over the exclusions, including the blocks
+    /// added by [`Trace::merge`] and [`Trace::apply_frame`]. Blocks that were recorded
+    /// before the exclusion was added are kept, even if [`Trace::map_addresses`] later
+    /// moves them into an excluded range.
+    pub fn add_exclusion(&mut self, range: Range<u64>) {
+        let mut exclusions = std::mem::take(&mut self.exclusions);
+        exclusions.push(range);
+        self.exclusions = index::merge_ranges(exclusions)
+            .into_iter()
+            .filter(|range| !range.is_empty())
+            .collect();
     }
-}
 
-/// Represents a single executed basic block in a module.
-#[derive(Clone, Copy, Debug, Default)]
-struct BasicBlockEntry {
-    /// Offset of the basic block start from the image base.
-    start: u32,
-    /// Size of the basic block.
-    size: u16,
-    /// Id of the module where the basic block is located.
-    mod_id: u16,
-}
+    /// Returns true if the address is within an excluded range.
+    fn is_excluded(&self, address: u64) -> bool {
+        let index = self
+            .exclusions
+            .partition_point(|range| range.start <= address);
+        index > 0 && address < self.exclusions[index - 1].end
+    }
 
-impl BasicBlockEntry {
-    fn write(&self, writer: &mut impl Write) -> Result<(), Error> {
-        let mut buf = [0; 8];
+    /// Returns true if the module `mod_id` is matched by [`Trace::try_add`].
+    fn is_active(&self, mod_id: usize) -> bool {
+        self.active
+            .as_ref()
+            .is_none_or(|active| active.get(mod_id).copied().unwrap_or(true))
+    }
+
+    /// Add a new coverage entry for an address that is known to be in the module `mod_id`.
+    ///
+    /// This skips the module lookup done by [`Trace::try_add`]. This function never
+    /// panics, regardless of its input.
+    ///
+    /// # Arguments
+    ///
+    /// * `mod_id` - The id of the module containing the basic block.
+    /// * `address` - The start address of the basic block to record.
+    /// * `size` - The size of the basic block in bytes.
+    pub fn add_in_module(
+        &mut self,
+        mod_id: usize,
+        address: u64,
+        size: usize,
+    ) -> Result<(), AddError> {
+        let module = self
+            .modules
+            .get(mod_id)
+            .ok_or_else(|| dropped(AddError::InvalidModuleId { mod_id }))?;
+        if !module.contains(address) {
+            return Err(dropped(AddError::NotInModule { address, mod_id }));
+        }
+        if self.is_excluded(address) {
+            return Ok(());
+        }
 
-        buf[0..4].copy_from_slice(&self.start.to_ne_bytes());
-        buf[4..6].copy_from_slice(&self.size.to_ne_bytes());
-        buf[6..8].copy_from_slice(&self.mod_id.to_ne_bytes());
+        let start = module
+            .relative_offset(address)
+            .ok_or_else(|| dropped(AddError::OffsetTooLarge { address }))?;
+        let size = u16::try_from(size).map_err(|_| dropped(AddError::SizeTooLarge { size }))?;
 
-        writer.write_all(&buf)
+        self.push_raw(mod_id, start, size)
     }
-}
 
-// Ensure at compile-time that entry structs are 8 bytes in size.
-static_assertions::assert_eq_size!(u64, BasicBlockEntry);
+    /// Add a new coverage entry from an offset that is already relative to the module `mod_id`.
+    ///
+    /// An offset beyond the end of the module is rejected with
+    /// [`AddError::OffsetOutOfRange`]. This function never panics, regardless of its
+    /// input.
+    ///
+    /// # Arguments
+    ///
+    /// * `mod_id` - The id of the module containing the basic block.
+    /// * `rva` - The offset of the basic block from the module base.
+    /// * `size` - The size of the basic block in bytes.
+    pub fn add_rva(&mut self, mod_id: usize, rva: u32, size: u16) -> Result<(), AddError> {
+        let module = self
+            .modules
+            .get(mod_id)
+            .ok_or_else(|| dropped(AddError::InvalidModuleId { mod_id }))?;
+        if u64::from(rva) >= module.size() {
+            return Err(dropped(AddError::OffsetOutOfRange {
+                offset: rva,
+                mod_id,
+            }));
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        self.push_raw(mod_id, rva, size)
+    }
 
-    #[test]
-    fn smoke_test() {
-        let modules = [Module::new("abcd.so", 0x1000, 0x2000)];
-        let mut trace = Trace::new(&modules);
+    /// Add a new coverage entry from its raw fields.
+    ///
+    /// Only the module id is validated, so the offset may lie beyond the end of the
+    /// module, as in traces read from files. The functions reading the trace accept
+    /// such entries, and [`Trace::verify`] reports them. Use [`Trace::add_rva`] to
+    /// reject them instead. This function never panics, regardless of its input.
+    ///
+    /// # Arguments
+    ///
+    /// * `mod_id` - The id of the module containing the basic block.
+    /// * `start` - The offset of the basic block from the module base.
+    /// * `size` - The size of the basic block in bytes.
+    pub fn push_raw(&mut self, mod_id: usize, start: u32, size: u16) -> Result<(), AddError> {
+        self.push_counted(mod_id, start, size, 1)
+    }
 
-        trace.add(0x1234, 1);
+    /// Add a new coverage entry from its raw fields that was hit `count` times, as by [`Trace::push_raw`].
+    fn push_counted(
+        &mut self,
+        mod_id: usize,
+        start: u32,
+        size: u16,
+        count: u32,
+    ) -> Result<(), AddError> {
+        if mod_id >= self.modules.len() {
+            return Err(dropped(AddError::InvalidModuleId { mod_id }));
+        }
+        let entry = self.sized(BlockEntry {
+            start,
+            size,
+            mod_id: u16::try_from(mod_id)
+                .map_err(|_| dropped(AddError::InvalidModuleId { mod_id }))?,
+        })?;
 
-        let mut out = Vec::new();
-        trace.write(&mut out).unwrap();
+        self.check_max_entries(&entry)?;
+        self.record(entry, count);
+        Ok(())
+    }
 
-        assert!(!out.is_empty());
+    /// Returns the entry with its size changed as set by the zero size policy, or an error if it is rejected.
+    fn sized(&self, entry: BlockEntry) -> Result<BlockEntry, AddError> {
+        match (entry.size, self.zero_size) {
+            (0, ZeroSizePolicy::PromoteToOne) => Ok(BlockEntry { size: 1, ..entry }),
+            (0, ZeroSizePolicy::Reject) => Err(dropped(AddError::ZeroSize {
+                offset: entry.start,
+                mod_id: usize::from(entry.mod_id),
+            })),
+            _ => Ok(entry),
+        }
     }
 
-    #[test]
-    #[should_panic]
-    fn add_out_of_bounds() {
-        let modules = [Module::new("abcd.so", 0x1000, 0x2000)];
+    /// Returns true if the entry is skipped by the exclusions or the active modules.
+    ///
+    /// The module id of the entry must be valid.
+    fn is_skipped(&self, entry: &BlockEntry) -> bool {
+        let mod_id = usize::from(entry.mod_id);
+        // An offset reaching past the address space cannot be within an exclusion.
+        let excluded = self.modules[mod_id]
+            .base
+            .checked_add(u64::from(entry.start))
+            .is_some_and(|address| self.is_excluded(address));
+        excluded || !self.is_active(mod_id)
+    }
+
+    /// Returns an error if recording `entry` would store more entries than the maximum.
+    fn check_max_entries(&self, entry: &BlockEntry) -> Result<(), AddError> {
+        let Some(max) = self.max_entries else {
+            return Ok(());
+        };
+        let stored = self
+            .hits
+            .as_ref()
+            .is_some_and(|hits| hits.index.contains_key(entry));
+        match self.entries.len() >= max && !stored {
+            true => Err(dropped(AddError::LimitReached { max })),
+            false => Ok(()),
+        }
+    }
+
+    /// Remove duplicate entries, keeping the first occurrence of each block in recording order.
+    ///
+    /// This is the same as [`Trace::dedup_with`] with [`DedupKey::StartSize`].
+    pub fn dedup(&mut self) {
+        // Hit counting already stores every block once.
+        if self.hits.is_some() {
+            return;
+        }
+
+        let mut seen = HashSet::new();
+        self.entries.retain(|entry| seen.insert(*entry));
+    }
+
+    /// Remove duplicate entries as identified by `key`, keeping the first occurrence of each block.
+    ///
+    /// With [`DedupKey::StartOnly`] the kept entry takes the largest size of its
+    /// duplicates, and their hit counts are summed if hit counting is enabled.
+    pub fn dedup_with(&mut self, key: DedupKey) {
+        if key == DedupKey::StartSize {
+            return self.dedup();
+        }
+
+        let mut first = HashMap::<_, usize>::new();
+        let mut entries = Vec::<BlockEntry>::new();
+        let mut counts = Vec::<u32>::new();
+        for (index, entry) in self.entries.iter().enumerate() {
+            let count = self.hits.as_ref().map_or(1, |hits| hits.counts[index]);
+            match first.entry((entry.mod_id, entry.start)) {
+                Entry::Occupied(kept) => {
+                    let kept = *kept.get();
+                    entries[kept].size = entries[kept].size.max(entry.size);
+                    counts[kept] = counts[kept].saturating_add(count);
+                }
+                Entry::Vacant(kept) => {
+                    kept.insert(entries.len());
+                    entries.push(*entry);
+                    counts.push(count);
+                }
+            }
+        }
+
+        // Duplicates keep the lowest of their tags.
+        let mut tags = HashMap::new();
+        for (entry, tag) in std::mem::take(&mut self.tags) {
+            if let Some(&kept) = first.get(&(entry.mod_id, entry.start)) {
+                tags.entry(entries[kept])
+                    .and_modify(|t: &mut u32| *t = (*t).min(tag))
+                    .or_insert(tag);
+            }
+        }
+        self.tags = tags;
+        if self.hits.is_some() {
+            self.hits = Some(HitCounts {
+                counts: counts.into(),
+                index: entries
+                    .iter()
+                    .enumerate()
+                    .map(|(index, &entry)| (entry, index))
+                    .collect(),
+                evicted: 0,
+            });
+        }
+        self.entries = entries.into();
+    }
+
+    /// Store an entry that was hit `count` times, merging it with an existing entry if hit counting is enabled.
+    fn record(&mut self, entry: BlockEntry, count: u32) {
+        let Some(hits) = &mut self.hits else {
+            self.entries.push(entry);
+            self.evict();
+            return;
+        };
+
+        match hits.index.entry(entry) {
+            Entry::Occupied(index) => {
+                let hit = &mut hits.counts[*index.get() - hits.evicted];
+                *hit = hit.saturating_add(count);
+            }
+            Entry::Vacant(index) => {
+                index.insert(self.entries.len() + hits.evicted);
+                hits.counts.push(count);
+                self.entries.push(entry);
+                self.evict();
+            }
+        }
+    }
+
+    /// Remove the oldest entries until the entry limit is respected.
+    fn evict(&mut self) {
+        let Some(limit) = self.entry_limit else {
+            return;
+        };
+        if self.entries.len() <= limit {
+            return;
+        }
+
+        let excess = self.entries.len() - limit;
+        for entry in &self.entries[..excess] {
+            if !self.tags.is_empty() {
+                self.tags.remove(entry);
+            }
+            if let Some(hits) = &mut self.hits {
+                hits.index.remove(entry);
+            }
+        }
+        self.entries.remove_front(excess);
+        if let Some(hits) = &mut self.hits {
+            hits.counts.remove_front(excess);
+            hits.evicted += excess;
+        }
+    }
+
+    /// Remove all entries, keeping the module table and hit counting mode.
+    fn clear_entries(&mut self) {
+        self.entries.clear();
+        self.tags.clear();
+        if let Some(hits) = &mut self.hits {
+            *hits = HitCounts::default();
+        }
+    }
+
+    /// Output the coverage information in the appropriate drcov format.
+    pub fn write(&self, writer: &mut impl Write) -> Result<(), Error> {
+        self.write_header(writer, self.entries.len())?;
+        for entry in &self.entries {
+            entry.write(writer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Output the coverage information as by [`Trace::write`], returning the number of bytes written.
+    ///
+    /// The count covers the header, the module table and the BB table.
+    pub fn write_counted(&self, writer: &mut impl Write) -> Result<usize, Error> {
+        let mut counter = CountingWriter { writer, count: 0 };
+        self.write(&mut counter)?;
+        Ok(counter.count)
+    }
+
+    /// Returns the number of bytes that [`Trace::write`] outputs.
+    ///
+    /// The text header and module table are formatted into a counting sink without
+    /// being stored, and the BB table takes [`BlockEntry::SIZE`] bytes per entry.
+    /// Use it to preallocate an output buffer or to decide whether to compress.
+    pub fn estimated_output_len(&self) -> usize {
+        let mut sink = std::io::sink();
+        let mut counter = CountingWriter {
+            writer: &mut sink,
+            count: 0,
+        };
+        self.write_header(&mut counter, self.entries.len())
+            .expect("writing to a sink cannot fail");
+        counter.count + self.entries.len() * BlockEntry::SIZE
+    }
+
+    /// Output the coverage information with the sections in the given order.
+    ///
+    /// [`Layout::Standard`] writes the same output as [`Trace::write`].
+    /// [`Layout::BbTableFirst`] is not part of the drcov format and is only
+    /// understood by some custom consumers and by the parsers of this crate.
+    pub fn write_with_layout(&self, writer: &mut impl Write, layout: Layout) -> Result<(), Error> {
+        match layout {
+            Layout::Standard => self.write(writer),
+            Layout::BbTableFirst => {
+                self.write_preamble(writer)?;
+                write!(
+                    writer,
+                    "BB Table: {} bbs{}",
+                    self.entries.len(),
+                    self.line_ending
+                )?;
+                for entry in &self.entries {
+                    entry.write(writer)?;
+                }
+                self.write_module_table(writer)
+            }
+        }
+    }
+
+    /// Output the coverage information with a reference to a shared module table instead of the table itself.
+    ///
+    /// The module table is replaced by a `Module Manifest: <id>, count <n>` line
+    /// naming the table by its [`Trace::manifest_id`]. This is not part of the drcov
+    /// format: it saves space in corpora of traces that share a module layout, which
+    /// are read back with [`Trace::from_reader_with_manifest`] given the shared table.
+    pub fn write_slim(&self, writer: &mut impl Write) -> Result<(), Error> {
+        let nl = self.line_ending;
+        self.write_preamble(writer)?;
+        write!(
+            writer,
+            "Module Manifest: {:016x}, count {}{nl}",
+            self.manifest_id(),
+            self.modules.len()
+        )?;
+        write!(writer, "BB Table: {} bbs{nl}", self.entries.len())?;
+        for entry in &self.entries {
+            entry.write(writer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the text portion of the output of [`Trace::write`], up to and including the BB table line.
+    ///
+    /// This is the header exactly as a consumer sees it, without the binary entries.
+    pub fn header_string(&self) -> String {
+        let mut header = Vec::new();
+        self.write_header(&mut header, self.entries.len())
+            .expect("writing to a vector cannot fail");
+        String::from_utf8(header).expect("the header is built from strings")
+    }
+
+    /// Returns a readable listing of the trace for debugging and bug reports.
+    ///
+    /// This is the [`Trace::header_string`] followed by one `name+0xoffset (size)`
+    /// line per entry in recording order, and is not a valid drcov file.
+    pub fn to_debug_string(&self) -> String {
+        let mut listing = self.header_string();
+        for entry in &self.entries {
+            let name = self
+                .modules
+                .get(usize::from(entry.mod_id))
+                .map_or("<unknown>", Module::name);
+            listing.push_str(&format!(
+                "{name}+{:#x} ({}){}",
+                entry.start, entry.size, self.line_ending
+            ));
+        }
+        listing
+    }
+
+    /// Output the text header and module table, announcing `bbs` entries to follow.
+    fn write_header(&self, writer: &mut impl Write, bbs: usize) -> Result<(), Error> {
+        self.write_preamble(writer)?;
+        self.write_module_table(writer)?;
+
+        // The basic block entries follow this line.
+        write!(writer, "BB Table: {bbs} bbs{}", self.line_ending)
+    }
+
+    /// Output the version and flavor lines and the comment lines that follow them.
+    fn write_preamble(&self, writer: &mut impl Write) -> Result<(), Error> {
+        let nl = self.line_ending;
+
+        // Write the drcov header.
+        write!(writer, "DRCOV VERSION: {}{nl}", self.version)?;
+        write!(writer, "DRCOV FLAVOR: {}{nl}", self.flavor)?;
+        for (key, value) in &self.metadata {
+            write!(writer, "# {key}: {value}{nl}")?;
+        }
+        for (id, module) in self.modules.iter().enumerate() {
+            if let Some(load_order) = module.load_order {
+                write!(writer, "# module {id} load order: {load_order}{nl}")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Output the module table header, columns and rows.
+    fn write_module_table(&self, writer: &mut impl Write) -> Result<(), Error> {
+        let nl = self.line_ending;
+
+        // Write the module table.
+        write!(
+            writer,
+            "Module Table: version {}, count {}{nl}",
+            self.module_table_version,
+            self.modules.len()
+        )?;
+
+        // Version 3 traces also carry the module checksum and timestamp.
+        let checksums = matches!(self.version, Version::V3);
+        let columns = match self.module_table_version {
+            2 => "id, base, end, entry",
+            3 => "id, containing_id, start, end, entry",
+            _ => "id, containing_id, start, end, entry, offset",
+        };
+        match checksums {
+            true => write!(writer, "Columns: {columns}, checksum, timestamp, path{nl}")?,
+            false => write!(writer, "Columns: {columns}, path{nl}")?,
+        }
+
+        let mut containing_id = 0;
+        for (id, module) in self.modules.iter().enumerate() {
+            let Module {
+                name,
+                display_name: _,
+                base,
+                end,
+                offset,
+                continuation,
+                checksum,
+                entry,
+                build_id: _,
+                analysis_base,
+                load_order: _,
+            } = module;
+
+            // Addresses are written relative to the analysis base, if there is one.
+            let (base, end, entry) = match *analysis_base {
+                Some(analysis_base) => (
+                    analysis_base,
+                    analysis_base.wrapping_add(end - base),
+                    match entry {
+                        0 => 0,
+                        entry => entry.wrapping_sub(*base).wrapping_add(analysis_base),
+                    },
+                ),
+                None => (*base, *end, *entry),
+            };
+
+            // Segments refer back to the first segment of their image.
+            if !continuation {
+                containing_id = id;
+            }
+            // An unknown entry point has always been written as a plain `0`.
+            let entry = match entry {
+                0 => String::from("0"),
+                entry => format!("{entry:#x}"),
+            };
+            match self.module_table_version {
+                2 => write!(writer, "{id}, {base:#x}, {end:#x}, {entry}, ")?,
+                3 => write!(
+                    writer,
+                    "{id}, {containing_id}, {base:#x}, {end:#x}, {entry}, "
+                )?,
+                _ => write!(
+                    writer,
+                    "{id}, {containing_id}, {base:#x}, {end:#x}, {entry}, {offset:#x}, "
+                )?,
+            }
+            if checksums {
+                write!(writer, "{:#010x}, 0x00000000, ", checksum.unwrap_or(0))?;
+            }
+            write!(writer, "{name}{nl}")?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the basic block table as packed little-endian entries, exactly as written by [`Trace::write`].
+    pub fn bb_table_bytes(&self) -> Vec<u8> {
+        self.entries.iter().flat_map(|e| e.to_bytes()).collect()
+    }
+
+    /// Append entries from packed little-endian bytes in the format returned by [`Trace::bb_table_bytes`].
+    ///
+    /// Nothing is added if `bytes` is not a whole number of 8-byte entries or
+    /// if any entry refers to a module that is not in the trace. Otherwise each
+    /// entry is recorded as by [`Trace::push_raw`], skipping entries in excluded
+    /// ranges or inactive modules, until one is rejected by the zero size policy
+    /// or the maximum number of entries.
+    pub fn push_entry_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if !bytes.len().is_multiple_of(BlockEntry::SIZE) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Entry bytes are not a multiple of the entry size",
+            ));
+        }
+
+        let entries = bytes
+            .chunks_exact(BlockEntry::SIZE)
+            .map(BlockEntry::from_bytes)
+            .collect::<Vec<_>>();
+        self.push_entries(&entries, None)
+    }
+
+    /// Append entries with their hit counts, or a count of one each if `counts` is None.
+    ///
+    /// Nothing is added if any entry refers to a module that is not in the trace.
+    fn push_entries(
+        &mut self,
+        entries: &[BlockEntry],
+        counts: Option<&[u32]>,
+    ) -> Result<(), Error> {
+        if entries
+            .iter()
+            .any(|e| usize::from(e.mod_id) >= self.modules.len())
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Entry refers to an unknown module id",
+            ));
+        }
+
+        for (index, entry) in entries.iter().enumerate() {
+            if self.is_skipped(entry) {
+                continue;
+            }
+            let count = counts.map_or(1, |counts| counts[index]);
+            self.push_counted(usize::from(entry.mod_id), entry.start, entry.size, count)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        }
+        Ok(())
+    }
+
+    /// Change the name of the module with the given id.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if there is no module with the given id.
+    pub fn rename_module(&mut self, mod_id: usize, new_name: impl Into<Cow<'static, str>>) {
+        self.modules[mod_id].name = new_name.into();
+    }
+
+    /// Rewrite module names using the given function.
+    ///
+    /// Modules for which `f` returns `None` keep their current name.
+    pub fn remap_paths(&mut self, f: impl Fn(&str) -> Option<String>) {
+        for module in &mut self.modules {
+            if let Some(name) = f(&module.name) {
+                module.name = Cow::Owned(name);
+            }
+        }
+    }
+
+    /// Save the coverage trace to a file at the given path.
+    ///
+    /// An existing file is truncated. Use [`Trace::save_new`] to fail instead.
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> Result<(), Error> {
+        let mut file = std::fs::File::create(path)?;
+        self.write(&mut file)
+    }
+
+    /// Save the coverage trace to a new file at the given path.
+    ///
+    /// Fails with [`ErrorKind::AlreadyExists`] if the file already exists, leaving it untouched.
+    pub fn save_new(&self, path: impl AsRef<std::path::Path>) -> Result<(), Error> {
+        let mut file = std::fs::File::create_new(path)?;
+        self.write(&mut file)
+    }
+
+    /// Write the coverage trace to standard output, for use in shell pipelines.
+    ///
+    /// Standard output is locked for the duration of the write and flushed at the
+    /// end. The bytes are written unchanged on all platforms, with no line ending
+    /// translation of the BB table. On Windows, writing to a console rather than a
+    /// pipe or file fails, as the BB table is not valid UTF-8.
+    pub fn write_stdout(&self) -> Result<(), Error> {
+        let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+        self.write(&mut stdout)?;
+        stdout.flush()
+    }
+
+    /// Save the coverage trace to a file at the given path and flush it to disk.
+    ///
+    /// Like [`Trace::save`], but calls [`File::sync_all`](std::fs::File::sync_all)
+    /// before returning, so the trace survives a crash or power loss. This is
+    /// slower, as it waits for the storage device.
+    pub fn save_synced(&self, path: impl AsRef<std::path::Path>) -> Result<(), Error> {
+        let mut file = std::fs::File::create(path)?;
+        self.write(&mut file)?;
+        file.sync_all()
+    }
+
+    /// Save the coverage trace in the form that the given tool expects.
+    ///
+    /// The extension of the tool is appended to `path` unless it already has it, so
+    /// `trace` becomes `trace.drcov` for [`Tool::Lighthouse`]. None of the tools
+    /// accept comment lines in the header, so metadata and module load orders are
+    /// left out. Returns the path of the written file.
+    pub fn save_as(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        tool: Tool,
+    ) -> Result<std::path::PathBuf, Error> {
+        let mut path = path.as_ref().to_owned();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(tool.extension()) {
+            let mut name = path.into_os_string();
+            name.push(".");
+            name.push(tool.extension());
+            path = name.into();
+        }
+
+        let mut trace = self.clone();
+        trace.metadata.clear();
+        for module in &mut trace.modules {
+            module.load_order = None;
+        }
+        trace.save(&path)?;
+        Ok(path)
+    }
+
+    /// Save the coverage of a single module to `<dir>/<module>.cov`, for the IDA coverage plugins.
+    ///
+    /// `target` is compared with both the full name and the file name of each
+    /// module, so `"target.exe"` selects `C:\bin\target.exe`. The file holds only
+    /// the matching modules, including all segments of the image, and their blocks,
+    /// and is named after the file name of the module so the plugin can associate it
+    /// with the database of that binary. It is written as by [`Trace::save_as`] for
+    /// [`Tool::Ida`]. Returns the path of the written file, or an error of kind
+    /// [`ErrorKind::NotFound`] if no module matches.
+    pub fn save_for_ida(
+        &self,
+        dir: impl AsRef<std::path::Path>,
+        target: &str,
+    ) -> Result<std::path::PathBuf, Error> {
+        fn file_name(module: &Module) -> &str {
+            module.name.rsplit(['/', '\\']).next().unwrap_or("")
+        }
+        let matches = |module: &Module| module.name() == target || file_name(module) == target;
+
+        let module = self
+            .modules
+            .iter()
+            .find(|module| matches(module))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No module named {target}")))?;
+        let path = dir.as_ref().join(format!("{}.cov", file_name(module)));
+
+        self.filtered_modules(|_, module| matches(module))
+            .save_as(path, Tool::Ida)
+    }
+
+    /// Returns a copy of this trace with its entries replaced by `entries`.
+    fn with_entries(&self, entries: Vec<BlockEntry>) -> Trace {
+        Trace {
+            modules: self.modules.clone(),
+            entries: entries.into(),
+            flavor: self.flavor.clone(),
+            version: self.version,
+            line_ending: self.line_ending,
+            hits: None,
+            point_size: self.point_size,
+            module_table_version: self.module_table_version,
+            provisional: Default::default(),
+            entry_limit: None,
+            max_entries: None,
+            zero_size: self.zero_size,
+            bank_encoder: self.bank_encoder.clone(),
+            active: self.active.clone(),
+            exclusions: self.exclusions.clone(),
+            tags: HashMap::new(),
+            metadata: self.metadata.clone(),
+        }
+    }
+}
+
+/// A writer that counts the bytes written to the underlying writer.
+struct CountingWriter<'a, W> {
+    writer: &'a mut W,
+    count: usize,
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let written = self.writer.write(buf)?;
+        self.count += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+}
+
+/// Report an entry that could not be recorded through the `log` crate when the `log` feature is enabled.
+#[inline]
+fn dropped(err: AddError) -> AddError {
+    #[cfg(feature = "log")]
+    log::trace!("Dropped coverage entry: {err}");
+    err
+}
+
+/// Contains information about a single module in the program's address space.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Module {
+    /// Path of the module image, borrowed for static names to avoid an allocation.
+    name: Cow<'static, str>,
+    /// Name shown in reports instead of the path, if set.
+    display_name: Option<Cow<'static, str>>,
+    base: u64,
+    end: u64,
+    /// Offset of this segment from the start of the image.
+    offset: u64,
+    /// True if this module is a later segment of the preceding module.
+    continuation: bool,
+    /// Checksum of the module image, written to version 3 traces.
+    checksum: Option<u32>,
+    /// Address of the module entry point, or 0 if unknown.
+    entry: u64,
+    /// Build id of the module image, used instead of the name to match modules across traces.
+    build_id: Option<Vec<u8>>,
+    /// Base address of the module in the analyst's database, written instead of the load base.
+    analysis_base: Option<u64>,
+    /// Position of the module in the load order of the traced process.
+    load_order: Option<u64>,
+}
+
+impl std::fmt::Display for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.display_name())
+    }
+}
+
+/// The part of a module, together with its image offset, that identifies it across traces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Identity<'a> {
+    BuildId(&'a [u8]),
+    Name(&'a str),
+}
+
+impl Module {
+    /// Create a new [`Module`].
+    ///
+    /// Use [`Module::from_static`] to create modules in a constant context.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the end address is not after the base address, or if
+    /// the module is larger than `u32::MAX` bytes. In the largest module, the last
+    /// byte has the offset `u32::MAX - 1`, so every offset fits in an entry.
+    pub fn new(name: impl Into<Cow<'static, str>>, base: u64, end: u64) -> Module {
+        Module {
+            name: name.into(),
+            ..Module::from_static("", base, end)
+        }
+    }
+
+    /// Create a new [`Module`] with a static name in a constant context.
+    ///
+    /// This allows module tables that are known at compile time, such as firmware
+    /// layouts, to be declared as constants. An invalid range in a constant is a
+    /// compile time error.
+    ///
+    /// ```rust
+    /// use drcov_rs::{Module, Trace};
+    ///
+    /// const MODULES: [Module; 2] = [
+    ///     Module::from_static("bootloader", 0x0800_0000, 0x0800_4000),
+    ///     Module::from_static("firmware", 0x0800_4000, 0x0810_0000),
+    /// ];
+    ///
+    /// let trace = Trace::new(&MODULES);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the end address is not after the base address, or if
+    /// the module is larger than `u32::MAX` bytes. In the largest module, the last
+    /// byte has the offset `u32::MAX - 1`, so every offset fits in an entry.
+    pub const fn from_static(name: &'static str, base: u64, end: u64) -> Module {
+        assert!(base < end, "`base` must be before `end`");
+        assert!(
+            (end - base) <= u32::MAX as u64,
+            "Module sizes > u32::MAX are not representable"
+        );
+        Module {
+            name: Cow::Borrowed(name),
+            display_name: None,
+            base,
+            end,
+            offset: 0,
+            continuation: false,
+            checksum: None,
+            entry: 0,
+            build_id: None,
+            analysis_base: None,
+            load_order: None,
+        }
+    }
+
+    /// Create a new [`Module`], returning an error instead of panicking if the address range is invalid.
+    pub fn try_new(
+        name: impl Into<Cow<'static, str>>,
+        base: u64,
+        end: u64,
+    ) -> Result<Module, ModuleError> {
+        if base >= end {
+            return Err(ModuleError::InvalidRange { base, end });
+        }
+        if end - base > u32::MAX as u64 {
+            return Err(ModuleError::TooLarge { base, end });
+        }
+        Ok(Module::new(name, base, end))
+    }
+
+    /// Create a module table from `(name, base, end)` tuples, validating the whole set.
+    ///
+    /// Each range is checked as by [`Module::try_new`], and the modules must not
+    /// overlap. The modules are returned sorted by base address. Errors refer to
+    /// modules by their index in `entries`.
+    pub fn build_table(
+        entries: Vec<(impl Into<Cow<'static, str>>, u64, u64)>,
+    ) -> Result<Vec<Module>, TableError> {
+        let mut modules = entries
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, base, end))| {
+                Module::try_new(name, base, end)
+                    .map(|module| (index, module))
+                    .map_err(|error| TableError::InvalidModule { index, error })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        modules.sort_by_key(|(_, module)| module.base);
+
+        // Once sorted, any overlap also shows up between neighbours.
+        if let Some(pair) = modules
+            .windows(2)
+            .find(|pair| pair[1].1.base < pair[0].1.end)
+        {
+            return Err(TableError::Overlap {
+                first: pair[0].0,
+                second: pair[1].0,
+            });
+        }
+        Ok(modules.into_iter().map(|(_, module)| module).collect())
+    }
+
+    /// Create the modules for an image that is mapped as several non-contiguous segments.
+    ///
+    /// Each segment becomes its own [`Module`] so that unmapped gaps are not attributed
+    /// to the image. The segments are linked through the `containing_id` column when
+    /// written, and must be added to a trace consecutively and in the returned order.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the image.
+    /// * `base` - The load address of the image.
+    /// * `segments` - The mapped ranges, as offsets from `base`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if any segment is empty or larger than `u32::MAX` bytes.
+    pub fn with_segments(
+        name: impl Into<Cow<'static, str>>,
+        base: u64,
+        segments: &[Range<u64>],
+    ) -> Vec<Module> {
+        let name = name.into();
+
+        segments
+            .iter()
+            .enumerate()
+            .map(|(index, segment)| Module {
+                offset: segment.start,
+                continuation: index != 0,
+                ..Module::new(name.clone(), base + segment.start, base + segment.end)
+            })
+            .collect()
+    }
+
+    /// Returns the module with its image checksum set.
+    ///
+    /// The checksum is only written when the trace uses [`Version::V3`], where it lets
+    /// tools such as Lighthouse detect that the binary on disk differs from the
+    /// traced one.
+    pub fn with_checksum(self, checksum: u32) -> Module {
+        Module {
+            checksum: Some(checksum),
+            ..self
+        }
+    }
+
+    /// Returns the module with its entry point address set.
+    pub fn with_entry(self, entry: u64) -> Module {
+        Module { entry, ..self }
+    }
+
+    /// Returns the module with its build id set.
+    ///
+    /// This is the GNU build id of an ELF image, the PDB GUID and age of a PE image
+    /// or the UUID of a Mach-O image. When present, it is used instead of the name
+    /// to match modules in [`Trace::merge`], [`Trace::diff`] and
+    /// [`Trace::coalesce_by_name`], which keeps matching working across install
+    /// paths. The build id is not stored in drcov files.
+    pub fn with_build_id(self, build_id: impl Into<Vec<u8>>) -> Module {
+        Module {
+            build_id: Some(build_id.into()),
+            ..self
+        }
+    }
+
+    /// Returns the build id of the module image, if known.
+    pub fn build_id(&self) -> Option<&[u8]> {
+        self.build_id.as_deref()
+    }
+
+    /// Returns a copy of this module with its name replaced.
+    ///
+    /// The address range and all other properties are kept. Use
+    /// [`Trace::rename_module`] to rename a module that is already part of a trace.
+    pub fn with_name(self, name: impl Into<Cow<'static, str>>) -> Module {
+        Module {
+            name: name.into(),
+            ..self
+        }
+    }
+
+    /// Returns a copy of this module that is written at the given analysis base.
+    ///
+    /// Disassemblers often load an image at a base address different from the one
+    /// it had at runtime. When an analysis base is set, [`Trace::write`] emits
+    /// `analysis_base` as the start of the module, the end as `analysis_base` plus
+    /// the module size and a known entry point shifted by the same amount. Block
+    /// offsets are relative to the module start and stay unchanged, so a block at
+    /// runtime address `base + offset` is written at `analysis_base + offset`,
+    /// which is its address in the analyst's database. The module itself, and all
+    /// lookups by address on the trace, keep using the runtime base.
+    pub fn with_analysis_base(self, analysis_base: u64) -> Module {
+        Module {
+            analysis_base: Some(analysis_base),
+            ..self
+        }
+    }
+
+    /// Returns the analysis base the module is written at, if set.
+    pub fn analysis_base(&self) -> Option<u64> {
+        self.analysis_base
+    }
+
+    /// Returns a copy of this module with the given position in the load order of the process.
+    ///
+    /// The position may be a sequence number or a load timestamp, and tells modules
+    /// that reused the same address range during a run apart. It is written as a
+    /// `# module <id> load order: <n>` comment line before the module table, so the
+    /// standard columns are not affected.
+    pub fn with_load_order(self, load_order: u64) -> Module {
+        Module {
+            load_order: Some(load_order),
+            ..self
+        }
+    }
+
+    /// Returns the position of the module in the load order of the process, if known.
+    pub fn load_order(&self) -> Option<u64> {
+        self.load_order
+    }
+
+    /// Returns the address of the module entry point, or 0 if unknown.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Returns the checksum of the module image, if known.
+    pub fn checksum(&self) -> Option<u32> {
+        self.checksum
+    }
+
+    /// Returns the name of the module, which is the path written to the module table.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the path of the module image, as written to the module table and used
+    /// by tools such as Lighthouse to match the module. This is the same as [`Module::name`].
+    pub fn path(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns a copy of this module with a name to show in reports instead of its path.
+    ///
+    /// The display name is only kept in memory and used by the [`Display`](std::fmt::Display)
+    /// implementation; the path is still written to the module table.
+    pub fn with_display_name(self, display_name: impl Into<Cow<'static, str>>) -> Module {
+        Module {
+            display_name: Some(display_name.into()),
+            ..self
+        }
+    }
+
+    /// Returns the name to show in reports, which is the path unless a display name is set.
+    pub fn display_name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Returns the base address of the module.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Returns the end address of the module (exclusive).
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Returns the size of the module in bytes.
+    pub fn size(&self) -> u64 {
+        self.end - self.base
+    }
+
+    /// Returns the build id, or else the name, and image offset that identify this module across traces.
+    fn identity(&self) -> (Identity<'_>, u64) {
+        let key = match &self.build_id {
+            Some(build_id) => Identity::BuildId(build_id),
+            None => Identity::Name(&self.name),
+        };
+        (key, self.offset)
+    }
+
+    /// Returns the offset of the given address from the module base.
+    ///
+    /// Returns None if the address is not within this `Module` or the offset does not fit in a `u32`.
+    pub fn relative_offset(&self, address: u64) -> Option<u32> {
+        if !self.contains(address) {
+            return None;
+        }
+        u32::try_from(address - self.base).ok()
+    }
+
+    /// Returns true if the given address is within this `Module`.
+    pub fn contains(&self, address: u64) -> bool {
+        self.base <= address && address < self.end
+    }
+}
+
+/// Represents a single executed basic block in a module.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BlockEntry {
+    /// Offset of the basic block start from the image base.
+    start: u32,
+    /// Size of the basic block.
+    size: u16,
+    /// Id of the module where the basic block is located.
+    mod_id: u16,
+}
+
+impl BlockEntry {
+    /// Returns the offset of the basic block start from the module base.
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    /// Returns the size of the basic block in bytes.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the id of the module where the basic block is located.
+    pub fn mod_id(&self) -> u16 {
+        self.mod_id
+    }
+
+    /// Size of a serialized entry in bytes.
+    const SIZE: usize = 8;
+
+    /// Serialize the entry in the little-endian layout used by drcov.
+    fn to_bytes(self) -> [u8; BlockEntry::SIZE] {
+        let mut buf = [0; BlockEntry::SIZE];
+
+        buf[0..4].copy_from_slice(&self.start.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.size.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.mod_id.to_le_bytes());
+
+        buf
+    }
+
+    /// Deserialize an entry from exactly [`BlockEntry::SIZE`] little-endian bytes.
+    fn from_bytes(buf: &[u8]) -> BlockEntry {
+        BlockEntry {
+            start: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            size: u16::from_le_bytes([buf[4], buf[5]]),
+            mod_id: u16::from_le_bytes([buf[6], buf[7]]),
+        }
+    }
+
+    /// Deserialize an entry from exactly [`BlockEntry::SIZE`] big-endian bytes.
+    fn from_be_bytes(buf: &[u8]) -> BlockEntry {
+        BlockEntry {
+            start: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            size: u16::from_be_bytes([buf[4], buf[5]]),
+            mod_id: u16::from_be_bytes([buf[6], buf[7]]),
+        }
+    }
+
+    fn write(&self, writer: &mut impl Write) -> Result<(), Error> {
+        writer.write_all(&self.to_bytes())
+    }
+}
+
+/// A basic block entry with a guaranteed C layout, for sharing entry buffers over FFI.
+///
+/// The fields are laid out without padding in the order of the drcov BB table, so
+/// on little-endian targets an array of `CEntry` has exactly the bytes of a BB
+/// table. The struct is packed, so fields must be copied out rather than borrowed.
+///
+/// ```c
+/// struct drcov_entry {
+///     uint32_t start;
+///     uint16_t size;
+///     uint16_t mod_id;
+/// } __attribute__((packed));
+/// ```
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CEntry {
+    /// Offset of the basic block start from the module base.
+    pub start: u32,
+    /// Size of the basic block.
+    pub size: u16,
+    /// Id of the module where the basic block is located.
+    pub mod_id: u16,
+}
+
+impl From<BlockEntry> for CEntry {
+    fn from(entry: BlockEntry) -> CEntry {
+        CEntry {
+            start: entry.start,
+            size: entry.size,
+            mod_id: entry.mod_id,
+        }
+    }
+}
+
+impl From<CEntry> for BlockEntry {
+    fn from(entry: CEntry) -> BlockEntry {
+        BlockEntry {
+            start: entry.start,
+            size: entry.size,
+            mod_id: entry.mod_id,
+        }
+    }
+}
+
+// Ensure at compile-time that entry structs are 8 bytes in size.
+static_assertions::assert_eq_size!(u64, BlockEntry);
+static_assertions::assert_eq_size!(u64, CEntry);
+static_assertions::const_assert_eq!(std::mem::align_of::<CEntry>(), 1);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smoke_test() {
+        let modules = [Module::new("abcd.so", 0x1000, 0x2000)];
+        let mut trace = Trace::new(&modules);
+
+        trace.add(0x1234, 1);
+
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+
+        assert!(!out.is_empty());
+    }
+
+    /// Build the trace stored in the golden fixtures.
+    fn golden_trace(version: Version) -> Trace {
+        let modules = [
+            Module::new("/bin/target", 0x400000, 0x428000).with_checksum(0x1234abcd),
+            Module::new("libc.so.6", 0x7f0000000000, 0x7f0000200000),
+        ];
+        let mut trace = Trace::new(&modules).with_version(version);
+
+        trace.add(0x401234, 0x102);
+        trace.add(0x7f0000012345, 7);
+        trace.add(0x401234, 0x102);
+        trace
+    }
+
+    #[test]
+    fn golden_output() {
+        for (version, expected) in [
+            (
+                Version::V2,
+                include_bytes!("../tests/fixtures/golden_v2.log").as_slice(),
+            ),
+            (
+                Version::V3,
+                include_bytes!("../tests/fixtures/golden_v3.log").as_slice(),
+            ),
+        ] {
+            let mut out = Vec::new();
+            golden_trace(version).write(&mut out).unwrap();
+
+            assert_eq!(out, expected, "output for version {version} changed");
+        }
+    }
+
+    #[test]
+    fn module_table_versions() {
+        let mut trace = Trace::new(&[Module::new("abcd.so", 0x1000, 0x2000)]);
+        assert_eq!(
+            trace.module_table_version(),
+            Version::V2.default_module_table_version()
+        );
+
+        trace.set_module_table_version(3);
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        let header = String::from_utf8_lossy(&out);
+        assert!(header.contains("Module Table: version 3, count 1\n"));
+        assert!(header.contains("Columns: id, containing_id, start, end, entry, path\n"));
+
+        trace.set_version(Version::V3);
+        assert_eq!(trace.version(), Version::V3);
+        assert_eq!(trace.module_table_version(), 4);
+
+        assert!(!Version::V3.supports_module_table_version(2));
+
+        // The versions can also be combined freely.
+        trace.set_file_version(Version::V3);
+        trace.set_module_table_version(2);
+        assert_eq!(
+            (trace.version(), trace.module_table_version()),
+            (Version::V3, 2)
+        );
+        let header = trace.header_string();
+        assert!(header.starts_with("DRCOV VERSION: 3\n"));
+        assert!(header.contains("Module Table: version 2, count 1\n"));
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        let parsed = Trace::from_reader(out.as_slice()).unwrap();
+        assert_eq!(parsed.module_table_version(), 2);
+        assert!(!Version::V2.supports_module_table_version(5));
+    }
+
+    #[test]
+    #[should_panic]
+    fn invalid_module_table_version() {
+        let mut trace = Trace::new(&[]);
+        trace.set_module_table_version(1);
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let modules = [Module::new("abcd.so", 0x1000, 0x2000)];
+        let mut trace = Trace::new(&modules);
+
+        trace.add(0x100a, 0x0d);
+        trace.set_line_ending(LineEnding::CrLf);
+
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+
+        let (header, entries) = out.split_at(out.len() - 8);
+        let header = String::from_utf8(header.to_vec()).unwrap();
+        assert_eq!(header.matches("\r\n").count(), 6);
+        assert_eq!(header.matches('\n').count(), 6);
+        assert!(header.ends_with("BB Table: 1 bbs\r\n"));
+        assert_eq!(entries, [0x0a, 0, 0, 0, 0x0d, 0, 0, 0]);
+    }
+
+    #[test]
+    fn entry_bytes_round_trip() {
+        let modules = [Module::new("abcd.so", 0x1000, 0x2000)];
+        let mut trace = Trace::new(&modules);
+
+        trace.add(0x1234, 0x10);
+
+        let bytes = trace.bb_table_bytes();
+        assert_eq!(bytes, [0x34, 0x02, 0, 0, 0x10, 0, 0, 0]);
+
+        let mut copy = Trace::new(&modules);
+        copy.push_entry_bytes(&bytes).unwrap();
+        assert_eq!(copy.bb_table_bytes(), bytes);
+
+        assert!(copy.push_entry_bytes(&bytes[..7]).is_err());
+        assert!(copy.push_entry_bytes(&[0, 0, 0, 0, 1, 0, 1, 0]).is_err());
+
+        let mut top = Trace::new(&[Module::new(
+            "top",
+            0xffff_ffff_ffff_f000,
+            0xffff_ffff_ffff_ffff,
+        )]);
+        top.add_exclusion(0xffff_ffff_ffff_f000..u64::MAX);
+        top.push_entry_bytes(&[0, 0, 0x10, 0, 4, 0, 0, 0]).unwrap();
+        assert_eq!(top.entries().len(), 1);
+    }
+
+    #[test]
+    fn module_for_entry() {
+        let modules = [
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("libc.so", 0x555000, 0x556000),
+        ];
+        let mut trace = Trace::new(&modules);
+
+        trace.add(0x555204, 3);
+        trace.add(0x1207, 12);
+
+        let names = trace
+            .entries()
+            .iter()
+            .map(|e| trace.module_for_entry(e).unwrap().name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["libc.so", "abcd"]);
+
+        let dangling = BlockEntry {
+            mod_id: 2,
+            ..Default::default()
+        };
+        assert!(trace.module_for_entry(&dangling).is_none());
+    }
+
+    #[test]
+    fn segmented_module() {
+        let modules = Module::with_segments("target", 0x40000, &[0..0x1000, 0x3000..0x3800]);
+        let mut trace = Trace::new(&modules);
+
+        trace.add(0x40010, 4);
+        trace.add(0x43010, 4);
+        assert!(trace.try_add(0x42000, 4).is_err());
+        assert_eq!(trace.entries()[1].mod_id(), 1);
+        assert_eq!(trace.entries()[1].start(), 0x10);
+
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        let text = String::from_utf8_lossy(&out);
+        assert!(text.contains("\n0, 0, 0x40000, 0x41000, 0, 0x0, target\n"));
+        assert!(text.contains("\n1, 0, 0x43000, 0x43800, 0, 0x3000, target\n"));
+    }
+
+    #[test]
+    fn hit_counts() {
+        let modules = [Module::new("abcd", 0x1000, 0x2000)];
+        let mut trace = Trace::new(&modules);
+
+        trace.add(0x1010, 4);
+        trace.add(0x1010, 4);
+        assert_eq!(trace.hit_counts(), None);
+
+        trace.track_hit_counts();
+        trace.add(0x1020, 4);
+        trace.add(0x1010, 4);
+
+        assert_eq!(trace.entries().len(), 2);
+        assert_eq!(trace.hit_counts(), Some([3, 1].as_slice()));
+    }
+
+    #[test]
+    fn dedup_start_only_keeps_largest_size() {
+        let modules = [Module::new("abcd", 0x1000, 0x2000)];
+        let mut trace = Trace::new(&modules);
+        trace.track_hit_counts();
+        trace.add(0x1020, 4);
+        trace.add(0x1010, 4);
+        trace.add(0x1020, 8);
+        trace.add(0x1020, 4);
+
+        let mut by_start_size = trace.clone();
+        by_start_size.dedup_with(DedupKey::StartSize);
+        assert_eq!(by_start_size.entries().len(), 3);
+
+        trace.dedup_with(DedupKey::StartOnly);
+        let entries = trace
+            .entries()
+            .iter()
+            .map(|e| (e.start(), e.size()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [(0x20, 8), (0x10, 4)]);
+        assert_eq!(trace.hit_counts(), Some([3, 1].as_slice()));
+        trace.add(0x1020, 8);
+        assert_eq!(trace.hit_counts(), Some([4, 1].as_slice()));
+    }
+
+    #[test]
+    fn dedup_keeps_first_occurrence() {
+        let modules = [Module::new("abcd", 0x1000, 0x2000)];
+        let mut trace = Trace::new(&modules);
+
+        trace.add(0x1020, 4);
+        trace.add(0x1010, 4);
+        trace.add(0x1020, 4);
+        trace.add(0x1020, 8);
+        trace.dedup();
+
+        let starts = trace
+            .entries()
+            .iter()
+            .map(|e| (e.start(), e.size()))
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [(0x20, 4), (0x10, 4), (0x20, 8)]);
+    }
+
+    #[test]
+    fn add_point() {
+        let modules = [Module::new("abcd", 0x1000, 0x2000)];
+        let mut trace = Trace::new(&modules);
+
+        trace.add_point(0x1010);
+        trace.set_point_size(4);
+        trace.add_point(0x1020);
+
+        let blocks = trace
+            .entries()
+            .iter()
+            .map(|e| (e.start(), e.size()))
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, [(0x10, 1), (0x20, 4)]);
+    }
+
+    #[test]
+    fn add_inferred_sizes() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x40000)]);
+
+        trace
+            .add_inferred_sizes(0, &[0x1020, 0x1000, 0x1010, 0x1020, 0x30000], 6)
+            .unwrap();
+
+        let blocks = trace
+            .entries()
+            .iter()
+            .map(|e| (e.start(), e.size()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            blocks,
+            [(0, 0x10), (0x10, 0x10), (0x20, u16::MAX), (0x2f000, 6)]
+        );
+        assert_eq!(
+            trace.add_inferred_sizes(0, &[0x50000], 1),
+            Err(AddError::NotInModule {
+                address: 0x50000,
+                mod_id: 0
+            })
+        );
+    }
+
+    #[test]
+    fn entry_limit_keeps_recent_blocks() {
+        let modules = [Module::new("abcd", 0x1000, 0x2000)];
+        let mut trace = Trace::with_entry_limit(&modules, 2);
+        for address in [0x1010, 0x1020, 0x1030] {
+            trace.add(address, 4);
+        }
+        let starts = trace
+            .entries()
+            .iter()
+            .map(|e| e.start())
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [0x20, 0x30]);
+
+        let mut trace = Trace::with_entry_limit(&modules, 2);
+        trace.track_hit_counts();
+        for address in [0x1010, 0x1020, 0x1020, 0x1030, 0x1030, 0x1030] {
+            trace.add(address, 4);
+        }
+        let starts = trace
+            .entries()
+            .iter()
+            .map(|e| e.start())
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [0x20, 0x30]);
+        assert_eq!(trace.hit_counts(), Some([2, 3].as_slice()));
+    }
+
+    #[test]
+    fn entry_limit_drops_evicted_tags() {
+        let modules = [Module::new("abcd", 0x1000, 0x2000)];
+        let mut source = Trace::new(&modules);
+        source.add(0x1010, 4);
+        let mut trace = Trace::with_entry_limit(&modules, 2);
+        trace.track_hit_counts();
+        trace.merge_tagged(&source, 1).unwrap();
+        assert_eq!(trace.block_tags(), [Some(1)]);
+
+        for address in [0x1020, 0x1030, 0x1010] {
+            trace.add(address, 4);
+        }
+        assert_eq!(trace.block_tags(), [None, None]);
+        assert!(trace.tags.is_empty());
+        assert_eq!(trace.hit_counts(), Some([1, 1].as_slice()));
+    }
+
+    #[test]
+    fn entry_limit_bounds_storage() {
+        let modules = [Module::new("abcd", 0x1000, 0x10_0000)];
+        let mut trace = Trace::with_entry_limit(&modules, 3);
+        trace.track_hit_counts();
+        for address in (0x1000..0x9000).step_by(4) {
+            trace.add(address, 4);
+            trace.add(address, 4);
+            assert!(trace.entries.capacity() <= 16);
+        }
+        let starts = trace
+            .entries()
+            .iter()
+            .map(|e| e.start())
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [0x7ff4, 0x7ff8, 0x7ffc]);
+        assert_eq!(trace.hit_counts(), Some([2, 2, 2].as_slice()));
+        trace.add(0x8ff8, 4);
+        assert_eq!(trace.hit_counts(), Some([2, 3, 2].as_slice()));
+    }
+
+    #[test]
+    fn save_new_keeps_existing_files() {
+        let path = std::env::temp_dir().join(format!("drcov-save-new-{}.log", std::process::id()));
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        trace.add(0x1010, 4);
+        trace.save_new(&path).unwrap();
+
+        let err = Trace::new(&[]).save_new(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(Trace::load(&path).unwrap().entries().len(), 1);
+
+        Trace::new(&[]).save_synced(&path).unwrap();
+        assert!(Trace::load(&path).unwrap().entries().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn analysis_base_shifts_written_addresses() {
+        let module = Module::new("target.exe", 0x7ff6_0000_0000, 0x7ff6_0001_0000)
+            .with_entry(0x7ff6_0000_1230)
+            .with_analysis_base(0x140000000);
+        assert_eq!(module.analysis_base(), Some(0x140000000));
+        let mut trace = Trace::new(&[module]);
+        trace.add(0x7ff6_0000_1234, 4);
+
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        let text = String::from_utf8_lossy(&out);
+        assert!(text.contains("0, 0, 0x140000000, 0x140010000, 0x140001230, 0x0, target.exe\n"));
+
+        let parsed = Trace::from_reader(out.as_slice()).unwrap();
+        let block = parsed.blocks().next().unwrap();
+        assert_eq!(block.address(), 0x140001234);
+        assert_eq!(parsed.entries(), trace.entries());
+    }
+
+    #[test]
+    fn clone_modules_only() {
+        let mut trace = Trace::with_entry_limit(&[Module::new("abcd", 0x1000, 0x2000)], 2);
+        trace.set_version(Version::V3);
+        trace.track_hit_counts();
+        trace.add(0x1010, 4);
+
+        let mut fresh = trace.clone_modules_only();
+        assert!(fresh.entries().is_empty());
+        assert_eq!(fresh.modules(), trace.modules());
+        assert_eq!(fresh.version(), Version::V3);
+        assert_eq!(fresh.hit_counts(), Some(&[][..]));
+
+        for offset in [0x10, 0x20, 0x30] {
+            fresh.add(0x1000 + offset, 4);
+        }
+        assert_eq!(fresh.entries().len(), 2);
+        assert_eq!(trace.entries().len(), 1);
+    }
+
+    #[test]
+    fn active_modules_limit_lookups() {
+        let mut trace = Trace::new(&[
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("libc.so", 0x5000, 0x6000),
+            Module::new("overlay", 0x5800, 0x5900),
+        ]);
+        trace.set_active_modules(&[0, 2]);
+
+        trace.add(0x1010, 4);
+        assert_eq!(trace.try_add(0x5010, 4), Ok(()));
+        trace.add(0x5810, 4);
+        assert_eq!(
+            trace.try_add(0x3000, 4),
+            Err(AddError::NoModule { address: 0x3000 })
+        );
+        trace.add_in_module(1, 0x5020, 4).unwrap();
+
+        let blocks = trace
+            .entries()
+            .iter()
+            .map(|e| (e.mod_id(), e.start()))
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, [(0, 0x10), (2, 0x10), (1, 0x20)]);
+    }
+
+    #[test]
+    fn exclusions_skip_blocks() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        trace.add_exclusion(0x1100..0x1200);
+        trace.add_exclusion(0x3000..0x4000);
+        trace.add_exclusion(0x1180..0x1300);
+
+        trace.add(0x10ff, 4);
+        trace.add(0x1100, 4);
+        trace.add(0x12ff, 4);
+        assert_eq!(trace.try_add(0x3800, 4), Ok(()));
+        assert_eq!(trace.add_in_module(0, 0x1200, 4), Ok(()));
+        trace.add(0x1300, 4);
+
+        let starts = trace
+            .entries()
+            .iter()
+            .map(|e| e.start())
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [0xff, 0x300]);
+    }
+
+    #[test]
+    fn max_entries_stop_recording() {
+        let mut trace = Trace::with_max_entries(&[Module::new("abcd", 0x1000, 0x2000)], 2);
+        trace.track_hit_counts();
+        trace.add(0x1010, 4);
+        trace.add(0x1020, 4);
+        assert_eq!(
+            trace.try_add(0x1030, 4),
+            Err(AddError::LimitReached { max: 2 })
+        );
+        trace.add(0x1010, 4);
+        assert_eq!(trace.hit_counts(), Some([2, 1].as_slice()));
+
+        assert_eq!(trace.drain_entries().len(), 2);
+        trace.add(0x1030, 4);
+        assert_eq!(trace.entries().len(), 1);
+    }
+
+    #[test]
+    fn parts_round_trip() {
+        let mut trace =
+            Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]).with_version(Version::V3);
+        trace.add(0x1010, 4);
+        let (modules, mut entries, version, flavor) = trace.clone().into_parts();
+        assert_eq!((version, flavor.as_str()), (Version::V3, "drcov"));
+
+        let rebuilt =
+            Trace::from_parts(modules.clone(), entries.clone(), version, flavor.clone()).unwrap();
+        assert_eq!(rebuilt.header_string(), trace.header_string());
+        assert_eq!(rebuilt.entries(), trace.entries());
+
+        entries[0].mod_id = 1;
+        assert!(matches!(
+            Trace::from_parts(modules, entries, version, flavor),
+            Err(DrcovError::DanglingModuleId {
+                entry_index: 0,
+                mod_id: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn drain_entries() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        trace.track_hit_counts();
+        trace.add(0x1010, 4);
+        trace.add(0x1010, 4);
+
+        let drained = trace.drain_entries();
+        assert_eq!(drained.len(), 1);
+        assert!(trace.entries().is_empty());
+        assert_eq!(trace.modules().len(), 1);
+
+        trace.add(0x1010, 4);
+        assert_eq!(trace.entries(), drained);
+        assert_eq!(trace.hit_counts(), Some(&[1][..]));
+    }
+
+    #[test]
+    fn zero_size_policies() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        trace.add(0x1010, 0);
+        assert_eq!(trace.entries()[0].size(), 0);
+
+        trace.set_zero_size_policy(ZeroSizePolicy::PromoteToOne);
+        trace.add(0x1020, 0);
+        trace.add(0x1030, 4);
+        assert_eq!(trace.entries()[1].size(), 1);
+        assert_eq!(trace.entries()[2].size(), 4);
+
+        trace.set_zero_size_policy(ZeroSizePolicy::Reject);
+        assert_eq!(
+            trace.try_add(0x1040, 0),
+            Err(AddError::ZeroSize {
+                offset: 0x40,
+                mod_id: 0
+            })
+        );
+        assert!(trace.try_add(0x1040, 2).is_ok());
+        assert_eq!(trace.entries().len(), 4);
+    }
+
+    #[test]
+    fn write_counted() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        trace.add(0x1010, 4);
+        trace.add(0x1020, 4);
+
+        let mut out = Vec::new();
+        let count = trace.write_counted(&mut out).unwrap();
+        assert_eq!(count, out.len());
+        assert_eq!(count, 181 + 2 * BlockEntry::SIZE);
+        assert_eq!(trace.estimated_output_len(), count);
+
+        let header = trace.header_string();
+        assert!(header.ends_with("0, 0, 0x1000, 0x2000, 0, 0x0, abcd\nBB Table: 2 bbs\n"));
+        assert_eq!(header.as_bytes(), &out[..181]);
+
+        let listing = trace.to_debug_string();
+        assert_eq!(listing, header + "abcd+0x10 (4)\nabcd+0x20 (4)\n");
+    }
+
+    #[test]
+    fn save_as_appends_extension() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000).with_load_order(1)]);
+        trace.add_metadata("tool", "fuzzer");
+        trace.add(0x1010, 4);
+
+        let base = std::env::temp_dir().join(format!("drcov-save-as-{}", std::process::id()));
+        let path = trace.save_as(&base, Tool::Lighthouse).unwrap();
+        assert_eq!(path.extension().unwrap(), "drcov");
+        let saved = std::fs::read(&path).unwrap();
+        assert!(saved.starts_with(b"DRCOV VERSION: 2\nDRCOV FLAVOR: drcov\nModule Table"));
+        assert_eq!(Trace::try_from(saved).unwrap().entries(), trace.entries());
+        std::fs::remove_file(&path).unwrap();
+
+        let log = base.with_extension("log");
+        assert_eq!(trace.save_as(&log, Tool::DynamoRio).unwrap(), log);
+        std::fs::remove_file(&log).unwrap();
+        let cov = trace.save_as(&log, Tool::Ida).unwrap();
+        assert!(cov.to_string_lossy().ends_with(".log.cov"));
+        std::fs::remove_file(&cov).unwrap();
+    }
+
+    #[test]
+    fn save_for_ida_filters_to_target() {
+        let mut trace = Trace::new(&[
+            Module::new("C:\\bin\\target.exe", 0x1000, 0x2000),
+            Module::new("/usr/lib/libc.so.6", 0x5000, 0x6000),
+        ]);
+        trace.add(0x5010, 4);
+        trace.add(0x1020, 8);
+
+        let dir = std::env::temp_dir();
+        let path = trace.save_for_ida(&dir, "target.exe").unwrap();
+        assert_eq!(path, dir.join("target.exe.cov"));
+
+        let saved = Trace::load(&path).unwrap();
+        assert_eq!(saved.modules(), &trace.modules()[..1]);
+        assert_eq!(saved.entries().len(), 1);
+        assert_eq!(saved.entries()[0].start(), 0x20);
+        std::fs::remove_file(path).unwrap();
+
+        let err = trace.save_for_ida(&dir, "missing.dll").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn c_entry_layout() {
+        let entry = BlockEntry {
+            start: 0x1234,
+            size: 0x10,
+            mod_id: 3,
+        };
+        let c_entry = CEntry::from(entry);
+        assert_eq!(BlockEntry::from(c_entry), entry);
+
+        // SAFETY: `CEntry` is a packed plain-old-data struct of 8 bytes.
+        let bytes: [u8; 8] = unsafe { std::mem::transmute(c_entry) };
+        if cfg!(target_endian = "little") {
+            assert_eq!(bytes, entry.to_bytes());
+        }
+    }
+
+    #[test]
+    fn record_macro() {
+        let mut trace = record!("target", 0x400000..0x500000);
+        trace.add(0x401000, 16);
+
+        assert_eq!(trace.modules(), [Module::new("target", 0x400000, 0x500000)]);
+        assert_eq!(trace.entries().len(), 1);
+    }
+
+    #[test]
+    fn const_module_table() {
+        const MODULES: [Module; 2] = [
+            Module::from_static("abcd", 0x1000, 0x2000),
+            Module::from_static("libc.so", 0x5000, 0x6000),
+        ];
+        assert_eq!(MODULES[1], Module::new("libc.so", 0x5000, 0x6000));
+
+        let mut trace = Trace::new(&MODULES);
+        trace.add(0x5010, 4);
+        assert_eq!(trace.entries()[0].mod_id(), 1);
+    }
+
+    #[test]
+    fn static_names_are_borrowed() {
+        let module = Module::new("libc.so", 0x1000, 0x2000);
+        assert!(matches!(module.name, Cow::Borrowed("libc.so")));
+
+        let module = Module::new(format!("lib{}.so", 'c'), 0x1000, 0x2000);
+        assert!(matches!(module.name, Cow::Owned(_)));
+        assert_eq!(module.name(), "libc.so");
+
+        let renamed = module.clone().with_name("/lib/libc.so");
+        assert_eq!(renamed.name(), "/lib/libc.so");
+        assert_eq!((renamed.base, renamed.end), (module.base, module.end));
+    }
+
+    #[test]
+    fn relative_offset() {
+        let module = Module::new("abcd", 0x1000, 0x2000);
+
+        assert_eq!(module.relative_offset(0x1000), Some(0));
+        assert_eq!(module.relative_offset(0x1fff), Some(0xfff));
+        assert_eq!(module.relative_offset(0x2000), None);
+        assert_eq!(module.relative_offset(0xfff), None);
+    }
+
+    #[test]
+    fn display_name_is_not_written() {
+        let module = Module::new("/usr/lib/libc.so.6", 0x1000, 0x2000).with_display_name("libc");
+        assert_eq!(module.path(), "/usr/lib/libc.so.6");
+        assert_eq!(module.to_string(), "libc");
+        assert_eq!(Module::new("abcd", 0x1000, 0x2000).display_name(), "abcd");
+
+        let trace = Trace::new(&[module]);
+        let header = trace.header_string();
+        assert!(header.contains("/usr/lib/libc.so.6\n"));
+        assert!(!header.contains("libc\n"));
+    }
+
+    #[test]
+    fn build_table_validates_and_sorts() {
+        let modules = Module::build_table(vec![
+            ("libc.so", 0x7f00_0000, 0x7f10_0000),
+            ("abcd", 0x1000, 0x2000),
+        ])
+        .unwrap();
+        let names = modules.iter().map(|m| m.name()).collect::<Vec<_>>();
+        assert_eq!(names, ["abcd", "libc.so"]);
+
+        assert_eq!(
+            Module::build_table(vec![("abcd", 0x1000, 0x2000), ("bad", 0x3000, 0x3000)]),
+            Err(TableError::InvalidModule {
+                index: 1,
+                error: ModuleError::InvalidRange {
+                    base: 0x3000,
+                    end: 0x3000
+                }
+            })
+        );
+        assert_eq!(
+            Module::build_table(vec![
+                ("c", 0x5000, 0x6000),
+                ("a", 0x1000, 0x4000),
+                ("b", 0x2000, 0x3000),
+            ]),
+            Err(TableError::Overlap {
+                first: 1,
+                second: 2
+            })
+        );
+    }
+
+    #[test]
+    fn max_size_module() {
+        let base = 0x7f00_0000_0000;
+        let end = base + u64::from(u32::MAX);
+        let module = Module::new("huge", base, end);
+        assert_eq!(module.size(), u64::from(u32::MAX));
+        assert_eq!(module.relative_offset(end - 1), Some(u32::MAX - 1));
+        assert_eq!(module.relative_offset(end), None);
+        assert_eq!(
+            Module::try_new("huge", base, end + 1),
+            Err(ModuleError::TooLarge { base, end: end + 1 })
+        );
+
+        // A block at the last byte may extend past the end without overflowing its offset.
+        let mut trace = Trace::new(&[module]);
+        trace.add(end - 1, 16);
+        assert_eq!(
+            trace.try_add(end, 1),
+            Err(AddError::NoModule { address: end })
+        );
+        // Only the last byte of the block lies within the module.
+        assert_eq!(trace.covered_bytes(0), 1);
+        assert_eq!(
+            trace.add_rva(0, u32::MAX, 1),
+            Err(AddError::OffsetOutOfRange {
+                offset: u32::MAX,
+                mod_id: 0
+            })
+        );
+
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        let parsed = Trace::from_reader(out.as_slice()).unwrap();
+        assert_eq!(parsed.blocks().next().unwrap().address(), end - 1);
+        assert_eq!(parsed.entries()[0].start(), u32::MAX - 1);
+    }
+
+    #[test]
+    fn rename_modules() {
+        let modules = [
+            Module::new("/build/server/target.elf", 0x1000, 0x2000),
+            Module::new("/usr/lib/libc.so", 0x3000, 0x4000),
+        ];
+        let mut trace = Trace::new(&modules);
+
+        trace.remap_paths(|name| {
+            name.strip_prefix("/build/server/")
+                .map(|rest| format!("/home/analyst/{rest}"))
+        });
+        assert_eq!(trace.modules[0].name, "/home/analyst/target.elf");
+        assert_eq!(trace.modules[1].name, "/usr/lib/libc.so");
+
+        trace.rename_module(1, String::from("libc.so"));
+        assert_eq!(trace.modules[1].name, "libc.so");
+    }
+
+    #[test]
+    #[should_panic]
+    fn add_out_of_bounds() {
+        let modules = [Module::new("abcd.so", 0x1000, 0x2000)];
         let mut trace = Trace::new(&modules);
 
         trace.add(0xdead, 10);
     }
+
+    #[test]
+    fn try_add_errors() {
+        let modules = [Module::new("abcd.so", 0x1000, 0x2000)];
+        let mut trace = Trace::new(&modules);
+
+        assert_eq!(
+            trace.try_add(0xdead, 10),
+            Err(AddError::NoModule { address: 0xdead })
+        );
+        assert_eq!(
+            trace.try_add(0x1000, 0x10000),
+            Err(AddError::SizeTooLarge { size: 0x10000 })
+        );
+        assert_eq!(
+            trace.add_in_module(0, 0x2000, 1),
+            Err(AddError::NotInModule {
+                address: 0x2000,
+                mod_id: 0
+            })
+        );
+        assert_eq!(
+            trace.push_raw(1, 0, 1),
+            Err(AddError::InvalidModuleId { mod_id: 1 })
+        );
+        assert_eq!(
+            trace.add_rva(0, 0x1000, 1),
+            Err(AddError::OffsetOutOfRange {
+                offset: 0x1000,
+                mod_id: 0
+            })
+        );
+        assert!(trace.entries.is_empty());
+
+        trace.add_rva(0, 0xfff, 1).unwrap();
+        assert_eq!(trace.entries[0].start(), 0xfff);
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn try_add_logs_dropped_entries() {
+        use std::sync::Mutex;
+
+        // Other tests log concurrently, so remember which thread each message came from.
+        static MESSAGES: Mutex<Vec<(std::thread::ThreadId, String)>> = Mutex::new(Vec::new());
+
+        struct Logger;
+
+        impl log::Log for Logger {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                let message = (std::thread::current().id(), record.args().to_string());
+                MESSAGES.lock().unwrap().push(message);
+            }
+
+            fn flush(&self) {}
+        }
+
+        log::set_logger(&Logger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let mut trace = Trace::new(&[Module::new("abcd.so", 0x1000, 0x2000)]);
+        trace.try_add(0xdead, 10).unwrap_err();
+        trace.try_add(0x1000, 0x10000).unwrap_err();
+
+        let messages = MESSAGES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(thread, _)| *thread == std::thread::current().id())
+            .map(|(_, message)| message.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].contains("0xdead"));
+    }
+
+    #[test]
+    fn try_add_never_panics() {
+        let modules = [
+            Module::new("abcd.so", 0x1000, 0x2000),
+            Module::new("huge.so", 0x1_0000_0000, 0x1_ffff_ffff),
+            Module::new("top", 0xffff_ffff_0000_0000, u64::MAX),
+        ];
+        let mut trace = Trace::new(&modules);
+
+        // A small xorshift generator keeps the garbage input reproducible.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..10_000 {
+            let address = next();
+            let size = next() as usize;
+            let mod_id = next() as usize % 5;
+            let _ = trace.try_add(address, size);
+            let _ = trace.try_add(address % 0x2_0000_0000, size % 0x20000);
+            let _ = trace.add_in_module(mod_id, address, size);
+            let _ = trace.push_raw(mod_id, address as u32, size as u16);
+            let _ = trace.add_rva(mod_id, address as u32 % 0x2000, size as u16);
+        }
+
+        // The recorded garbage must be readable as well.
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        let parsed = Trace::from_reader(out.as_slice()).unwrap();
+        assert_eq!(parsed.blocks().count(), trace.len());
+        assert!(parsed.entry_at(trace.len() - 1).is_some());
+        parsed.to_weighted_blocks();
+        let _ = parsed.verify();
+        for mod_id in 0..modules.len() {
+            parsed.covered_bytes(mod_id);
+        }
+        parsed.clone().map_addresses(|address| address);
+        let index = parsed.index();
+        for block in index.trace().blocks().take(100) {
+            index.contains_block(block.address(), block.size());
+            index.blocks_hitting(block.address());
+        }
+        assert_eq!(index.into_trace().into_iter().count(), trace.len());
+    }
 }
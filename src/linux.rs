@@ -0,0 +1,120 @@
+use std::ffi::CStr;
+use std::io::{Error, ErrorKind};
+
+use crate::{Module, Trace};
+
+impl Trace {
+    /// Create a new trace whose modules are the objects loaded into the current process.
+    ///
+    /// The objects are enumerated with `dl_iterate_phdr`, and each module spans the
+    /// `PT_LOAD` segments of its object. The main executable is named by
+    /// [`std::env::current_exe`]. Objects that cannot be represented as a [`Module`]
+    /// are skipped.
+    pub fn from_current_process() -> Result<Trace, Error> {
+        let mut objects: Vec<(String, u64, u64)> = Vec::new();
+
+        // The ELF address types are only 64 bits wide on 64-bit targets.
+        #[allow(clippy::unnecessary_cast)]
+        unsafe extern "C" fn callback(
+            info: *mut libc::dl_phdr_info,
+            _size: libc::size_t,
+            data: *mut libc::c_void,
+        ) -> libc::c_int {
+            // SAFETY: `data` is the `objects` vector passed to `dl_iterate_phdr` below, and
+            // `info` points to a valid `dl_phdr_info` for the duration of the callback.
+            let objects = unsafe { &mut *(data as *mut Vec<(String, u64, u64)>) };
+            let info = unsafe { &*info };
+
+            let headers = match info.dlpi_phdr.is_null() {
+                true => &[][..],
+                false => unsafe {
+                    std::slice::from_raw_parts(info.dlpi_phdr, usize::from(info.dlpi_phnum))
+                },
+            };
+            let (start, end) = headers
+                .iter()
+                .filter(|header| header.p_type == libc::PT_LOAD)
+                // Skip corrupt headers whose segment would extend past the address space.
+                .filter_map(|header| {
+                    let vaddr = header.p_vaddr as u64;
+                    Some((vaddr, vaddr.checked_add(header.p_memsz as u64)?))
+                })
+                .fold((u64::MAX, 0), |(start, end), (vaddr, vend)| {
+                    (start.min(vaddr), end.max(vend))
+                });
+
+            let name = match info.dlpi_name.is_null() {
+                true => String::new(),
+                false => unsafe { CStr::from_ptr(info.dlpi_name) }
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            if start < end {
+                let base = info.dlpi_addr as u64;
+                objects.push((name, base.wrapping_add(start), base.wrapping_add(end)));
+            }
+            0
+        }
+
+        // SAFETY: the callback only accesses `objects` through the pointer passed here.
+        unsafe {
+            libc::dl_iterate_phdr(
+                Some(callback),
+                &mut objects as *mut Vec<(String, u64, u64)> as *mut libc::c_void,
+            );
+        }
+
+        let executable = std::env::current_exe()?;
+        let modules = objects
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, (name, base, end))| {
+                // The first object is the main program, which is reported without a name.
+                let name = match (index, name.is_empty()) {
+                    (0, true) => executable.to_string_lossy().into_owned(),
+                    (_, true) => return None,
+                    (_, false) => name,
+                };
+                Module::try_new(name, base, end).ok()
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Trace::new(&modules))
+    }
+}
+
+impl Module {
+    /// Create a module for the main executable of the current process.
+    ///
+    /// The module is named by [`std::env::current_exe`] and spans the `PT_LOAD`
+    /// segments of the executable, as found by [`Trace::from_current_process`].
+    pub fn current_executable() -> Result<Module, Error> {
+        let name = std::env::current_exe()?.to_string_lossy().into_owned();
+
+        Trace::from_current_process()?
+            .modules
+            .into_iter()
+            .find(|module| module.name() == name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Main executable is not mapped"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_process_contains_this_function() {
+        let mut trace = Trace::from_current_process().unwrap();
+        let address = current_process_contains_this_function as *const () as u64;
+
+        let module = trace.get_module(address).unwrap();
+        assert_eq!(
+            module.name(),
+            std::env::current_exe().unwrap().to_string_lossy()
+        );
+        let executable = Module::current_executable().unwrap();
+        assert_eq!(&executable, module);
+        trace.add(address, 1);
+    }
+}
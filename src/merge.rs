@@ -0,0 +1,576 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+use crate::parse::{parse_header, read_entry};
+use crate::{AddError, BlockEntry, DrcovError, Module, Trace};
+
+/// Merge drcov files into a single output file without loading their entries into memory.
+///
+/// The module tables of all inputs are read first and unified as by [`Trace::merge`].
+/// The entries are then streamed from each input in turn, relabelled to the unified
+/// module ids, and written directly to `output`. Duplicate entries are kept. The
+/// output uses the flavor and versions of the first input.
+///
+/// Inputs whose BB table precedes the module table, as written with
+/// [`crate::Layout::BbTableFirst`], are the exception: their entries are read
+/// into memory while their header is parsed, one input at a time.
+///
+/// The output is written to a temporary file next to `output` and renamed into
+/// place once complete, so an error leaves an existing `output` unchanged.
+pub fn merge_files(inputs: &[&Path], output: &Path) -> Result<(), DrcovError> {
+    let mut merged = Trace::new(&[]);
+    let mut ids = Vec::with_capacity(inputs.len());
+    let mut bbs = 0usize;
+    for (index, input) in inputs.iter().enumerate() {
+        let (trace, count, _) = parse_header(&mut BufReader::new(File::open(input)?))?;
+        if index == 0 {
+            merged = Trace {
+                modules: Vec::new(),
+                ..trace.clone()
+            };
+        }
+        ids.push(
+            trace
+                .modules
+                .iter()
+                .map(|module| merged.merge_module(module))
+                .collect::<Result<Vec<_>, _>>()?,
+        );
+        bbs = bbs.saturating_add(count);
+    }
+
+    let mut temp = output.as_os_str().to_owned();
+    temp.push(".tmp");
+    let written = write_merged(inputs, ids, &merged, bbs, Path::new(&temp));
+    match written {
+        Ok(()) => std::fs::rename(&temp, output)?,
+        Err(_) => {
+            let _ = std::fs::remove_file(&temp);
+        }
+    }
+    written
+}
+
+/// Write the header of `merged` and the relabelled entries of `inputs` to `path`.
+fn write_merged(
+    inputs: &[&Path],
+    ids: Vec<Vec<u16>>,
+    merged: &Trace,
+    bbs: usize,
+    path: &Path,
+) -> Result<(), DrcovError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    merged.write_header(&mut writer, bbs)?;
+    let mut buf = [0; BlockEntry::SIZE];
+    for (input, ids) in inputs.iter().zip(ids) {
+        let mut reader = BufReader::new(File::open(input)?);
+        let (_, count, leading) = parse_header(&mut reader)?;
+        let mut entries = leading.as_slice().chain(&mut reader);
+        for entry_index in 0..count {
+            read_entry(&mut entries, &mut buf)?;
+            let entry = BlockEntry::from_bytes(&buf);
+            let mod_id =
+                *ids.get(usize::from(entry.mod_id))
+                    .ok_or(DrcovError::DanglingModuleId {
+                        entry_index,
+                        mod_id: entry.mod_id,
+                    })?;
+            BlockEntry { mod_id, ..entry }.write(&mut writer)?;
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// How [`Trace::merge_with`] resolves modules that match but differ in their metadata.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the metadata of the module in this trace, as [`Trace::merge`] does.
+    #[default]
+    KeepExisting,
+    /// Replace the metadata of the module in this trace with that of the other trace.
+    PreferOther,
+    /// Fail the merge without changing this trace.
+    ErrorOnConflict,
+}
+
+/// Options for [`Trace::merge_with`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MergeOptions {
+    /// How matching modules with differing entry points, checksums or display names are resolved.
+    pub module_conflicts: ConflictPolicy,
+}
+
+impl Trace {
+    /// Merge the coverage of `other` into this trace.
+    ///
+    /// Modules are matched by build id, or name if it is unknown, and image offset.
+    /// Modules of `other` that are not
+    /// in this trace are appended to its module table. If this trace tracks hit
+    /// counts, the counts of matching blocks are summed (an entry of `other` without
+    /// hit counts counts as a single hit). Otherwise only blocks that are not already
+    /// present are added.
+    ///
+    /// The blocks of `other` are recorded like new blocks of this trace: the
+    /// exclusions and active modules skip them, and the zero size policy and the
+    /// maximum number of entries apply. If a block is rejected, an error of kind
+    /// [`ErrorKind::InvalidData`] wrapping the [`AddError`] is returned and the blocks
+    /// merged before it are kept.
+    pub fn merge(&mut self, other: &Trace) -> Result<(), Error> {
+        self.merge_entries(other, None, ConflictPolicy::KeepExisting)
+    }
+
+    /// Merge the coverage of `other` into this trace as by [`Trace::merge`], resolving
+    /// module metadata conflicts as set by `options`.
+    ///
+    /// Modules that match by build id, or name, and image offset conflict if their
+    /// entry points, checksums or display names differ. With
+    /// [`ConflictPolicy::ErrorOnConflict`] an error of kind
+    /// [`ErrorKind::InvalidData`] is returned before anything is merged.
+    pub fn merge_with(&mut self, other: &Trace, options: MergeOptions) -> Result<(), Error> {
+        if options.module_conflicts == ConflictPolicy::ErrorOnConflict {
+            for module in &other.modules {
+                let conflict = self
+                    .modules
+                    .iter()
+                    .find(|m| m.identity() == module.identity())
+                    .is_some_and(|existing| !same_metadata(existing, module));
+                if conflict {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Module `{}` has conflicting metadata", module.name),
+                    ));
+                }
+            }
+        }
+        self.merge_entries(other, None, options.module_conflicts)
+    }
+
+    /// Merge the coverage of `other` into this trace as by [`Trace::merge`], tagging
+    /// the blocks it contributes with `tag`.
+    ///
+    /// Only blocks that are not yet present in this trace are tagged, so each block
+    /// keeps the tag of the input that first reached it. The tags are kept in memory
+    /// only and are not written to drcov files; see [`Trace::block_tags`].
+    pub fn merge_tagged(&mut self, other: &Trace, tag: u32) -> Result<(), Error> {
+        self.merge_entries(other, Some(tag), ConflictPolicy::KeepExisting)
+    }
+
+    /// Returns the tag of each entry, parallel to [`Trace::entries`].
+    ///
+    /// Entries that were not contributed by [`Trace::merge_tagged`] have no tag.
+    /// Tags follow their blocks when the modules are renumbered, and are dropped when
+    /// recorded addresses are moved with [`Trace::map_addresses`].
+    pub fn block_tags(&self) -> Vec<Option<u32>> {
+        self.entries
+            .iter()
+            .map(|entry| self.tags.get(entry).copied())
+            .collect()
+    }
+
+    /// Merge the entries of `other`, tagging the blocks that are new to this trace if `tag` is given.
+    fn merge_entries(
+        &mut self,
+        other: &Trace,
+        tag: Option<u32>,
+        conflicts: ConflictPolicy,
+    ) -> Result<(), Error> {
+        // Fail before any module is added or updated, so a failed merge changes nothing.
+        self.check_module_capacity(other)?;
+        let ids = other
+            .modules
+            .iter()
+            .map(|module| self.merge_module_with(module, conflicts))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut present = match (&self.hits, tag) {
+            (Some(_), None) => HashSet::new(),
+            _ => self.entries.iter().copied().collect(),
+        };
+
+        for (index, entry) in other.entries.iter().enumerate() {
+            let entry = BlockEntry {
+                mod_id: ids[usize::from(entry.mod_id)],
+                ..*entry
+            };
+            if self.is_skipped(&entry) {
+                continue;
+            }
+            let entry = self.sized(entry).map_err(invalid_entry)?;
+
+            let new = present.insert(entry);
+            if !new && self.hits.is_none() {
+                continue;
+            }
+            let count = other.hits.as_ref().map_or(1, |hits| hits.counts[index]);
+            self.check_max_entries(&entry).map_err(invalid_entry)?;
+            self.record(entry, count);
+            if let (Some(tag), true) = (tag, new) {
+                self.tags.insert(entry, tag);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load the drcov file at `path` and merge its coverage into this trace, as by [`Trace::merge`].
+    ///
+    /// This keeps a running trace in memory while the traces of individual runs are
+    /// folded in from disk.
+    pub fn merge_from_file(&mut self, path: impl AsRef<Path>) -> Result<(), DrcovError> {
+        let trace = Trace::load(path)?;
+        Ok(self.merge(&trace)?)
+    }
+
+    /// Merge all of the given traces into a single trace.
+    ///
+    /// The result starts from the first trace and merges the rest in order, as by
+    /// [`Trace::merge`]. Hit counts are summed if any of the traces tracks them.
+    pub fn merge_all(traces: &[Trace]) -> Result<Trace, Error> {
+        let Some((first, rest)) = traces.split_first() else {
+            return Ok(Trace::new(&[]));
+        };
+
+        let mut merged = first.clone();
+        if traces.iter().any(|trace| trace.hits.is_some()) {
+            merged.track_hit_counts();
+        }
+        for trace in rest {
+            merged.merge(trace)?;
+        }
+
+        Ok(merged)
+    }
+
+    /// Returns an error if merging the modules of `other` would exceed `u16::MAX + 1` modules.
+    fn check_module_capacity(&self, other: &Trace) -> Result<(), Error> {
+        let mut known = self
+            .modules
+            .iter()
+            .map(Module::identity)
+            .collect::<HashSet<_>>();
+        let added = other
+            .modules
+            .iter()
+            .filter(|module| known.insert(module.identity()))
+            .count();
+
+        match self.modules.len() + added > usize::from(u16::MAX) + 1 {
+            true => Err(too_many_modules()),
+            false => Ok(()),
+        }
+    }
+
+    /// Returns the id of the module matching `module`, adding it to the module table if needed.
+    fn merge_module(&mut self, module: &Module) -> Result<u16, Error> {
+        self.merge_module_with(module, ConflictPolicy::KeepExisting)
+    }
+
+    /// Returns the id of the module matching `module` as by [`Trace::merge_module`], taking
+    /// its metadata if `conflicts` prefers it.
+    fn merge_module_with(
+        &mut self,
+        module: &Module,
+        conflicts: ConflictPolicy,
+    ) -> Result<u16, Error> {
+        let existing = self
+            .modules
+            .iter()
+            .position(|m| m.identity() == module.identity());
+
+        let id = u16::try_from(existing.unwrap_or(self.modules.len()))
+            .map_err(|_| too_many_modules())?;
+        match existing {
+            Some(existing) if conflicts == ConflictPolicy::PreferOther => {
+                let existing = &mut self.modules[existing];
+                existing.entry = module.entry;
+                existing.checksum = module.checksum;
+                existing.display_name.clone_from(&module.display_name);
+            }
+            Some(_) => {}
+            None => self.modules.push(module.clone()),
+        }
+
+        Ok(id)
+    }
+}
+
+/// Returns the error for a merged entry rejected by the zero size policy or the maximum number of entries.
+fn invalid_entry(err: AddError) -> Error {
+    Error::new(ErrorKind::InvalidData, err)
+}
+
+/// Returns the error for a merged module table with more ids than fit in a `u16`.
+fn too_many_modules() -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        "Merged module table exceeds u16::MAX modules",
+    )
+}
+
+/// Returns true if the modules have the same entry point, checksum and display name.
+fn same_metadata(first: &Module, second: &Module) -> bool {
+    (first.entry, first.checksum, &first.display_name)
+        == (second.entry, second.checksum, &second.display_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_unions_blocks() {
+        let mut first = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        first.add(0x1010, 4);
+        let mut second = Trace::new(&[
+            Module::new("libc.so", 0x5000, 0x6000),
+            Module::new("abcd", 0x8000, 0x9000),
+        ]);
+        second.add(0x8010, 4);
+        second.add(0x5020, 8);
+
+        first.merge(&second).unwrap();
+
+        assert_eq!(first.modules().len(), 2);
+        assert_eq!(first.modules()[1].name(), "libc.so");
+        assert_eq!(
+            first.entries(),
+            [
+                BlockEntry {
+                    start: 0x10,
+                    size: 4,
+                    mod_id: 0
+                },
+                BlockEntry {
+                    start: 0x20,
+                    size: 8,
+                    mod_id: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_sums_hit_counts() {
+        let modules = [Module::new("abcd", 0x1000, 0x2000)];
+        let mut first = Trace::new(&modules);
+        first.track_hit_counts();
+        let mut second = first.clone();
+        for _ in 0..3 {
+            first.add(0x1010, 4);
+        }
+        for _ in 0..5 {
+            second.add(0x1010, 4);
+        }
+
+        let merged = Trace::merge_all(&[first.clone(), second.clone()]).unwrap();
+        assert_eq!(merged.entries().len(), 1);
+        assert_eq!(merged.hit_counts(), Some([8].as_slice()));
+
+        first.merge(&second).unwrap();
+        assert_eq!(first.hit_counts(), Some([8].as_slice()));
+    }
+
+    #[test]
+    fn merge_matches_build_ids() {
+        let build_id = [0xab; 20];
+        let mut first =
+            Trace::new(&[Module::new("/usr/bin/target", 0x1000, 0x2000).with_build_id(build_id)]);
+        first.add(0x1010, 4);
+        let mut second = Trace::new(&[
+            Module::new("/opt/target", 0x5000, 0x6000).with_build_id(build_id),
+            Module::new("/usr/bin/target", 0x8000, 0x9000),
+        ]);
+        second.add(0x5020, 4);
+        second.add(0x8030, 4);
+
+        first.merge(&second).unwrap();
+        assert_eq!(first.modules().len(), 2);
+        let entries = first
+            .entries()
+            .iter()
+            .map(|e| (e.mod_id(), e.start()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [(0, 0x10), (0, 0x20), (1, 0x30)]);
+    }
+
+    #[test]
+    fn merge_with_resolves_conflicts() {
+        let mut first = Trace::new(&[Module::new("abcd", 0x1000, 0x2000).with_checksum(1)]);
+        first.add(0x1010, 4);
+        let mut second = Trace::new(&[Module::new("abcd", 0x5000, 0x6000).with_checksum(2)]);
+        second.add(0x5020, 4);
+
+        let options = |module_conflicts| MergeOptions { module_conflicts };
+        let mut merged = first.clone();
+        let err = merged
+            .merge_with(&second, options(ConflictPolicy::ErrorOnConflict))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(merged.entries().len(), 1);
+
+        merged
+            .merge_with(&second, options(ConflictPolicy::KeepExisting))
+            .unwrap();
+        assert_eq!(merged.modules()[0].checksum(), Some(1));
+        merged
+            .merge_with(&second, options(ConflictPolicy::PreferOther))
+            .unwrap();
+        assert_eq!(merged.modules()[0].checksum(), Some(2));
+        assert_eq!(merged.modules()[0].base(), 0x1000);
+        assert_eq!(merged.entries().len(), 2);
+    }
+
+    #[test]
+    fn failed_merge_leaves_trace_unchanged() {
+        let modules = (0..=u64::from(u16::MAX))
+            .map(|id| Module::new(format!("lib{id}.so"), id * 0x1000, id * 0x1000 + 0x1000))
+            .collect::<Vec<_>>();
+        let mut merged = Trace::new(&modules[..modules.len() - 1]);
+        merged.add(0x10, 4);
+        let mut other = Trace::new(&[
+            Module::new("lib0.so", 0x1000, 0x2000).with_checksum(7),
+            modules[modules.len() - 1].clone(),
+            Module::new("extra.so", 0x1_0000_0000, 0x1_0000_1000),
+        ]);
+        other.add(0x1010, 4);
+
+        let options = MergeOptions {
+            module_conflicts: ConflictPolicy::PreferOther,
+        };
+        let err = merged.merge_with(&other, options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(merged.modules().len(), modules.len() - 1);
+        assert_eq!(merged.modules()[0].checksum(), None);
+        assert_eq!(merged.entries().len(), 1);
+
+        other.modules.pop();
+        merged.merge_with(&other, options).unwrap();
+        assert_eq!(merged.modules().len(), modules.len());
+        assert_eq!(merged.modules()[0].checksum(), Some(7));
+    }
+
+    #[test]
+    fn merge_applies_recording_settings() {
+        let modules = [Module::new("abcd", 0x1000, 0x2000)];
+        let mut other = Trace::new(&modules);
+        for (address, size) in [(0x1100, 4), (0x1010, 4), (0x1020, 0), (0x1030, 4)] {
+            other.add(address, size);
+        }
+        let mut merged = Trace::with_max_entries(&modules, 1);
+        merged.set_zero_size_policy(crate::ZeroSizePolicy::Reject);
+        merged.add_exclusion(0x1100..0x1200);
+
+        let rejected = |err: Error| *err.into_inner().unwrap().downcast::<AddError>().unwrap();
+        let err = merged.merge(&other).unwrap_err();
+        assert_eq!(
+            rejected(err),
+            AddError::ZeroSize {
+                offset: 0x20,
+                mod_id: 0
+            }
+        );
+        assert_eq!(merged.entries().len(), 1);
+
+        merged.set_zero_size_policy(crate::ZeroSizePolicy::PromoteToOne);
+        let err = merged.merge(&other).unwrap_err();
+        assert_eq!(rejected(err), AddError::LimitReached { max: 1 });
+        assert_eq!(merged.entries().len(), 1);
+    }
+
+    #[test]
+    fn merge_tagged_records_first_source() {
+        let mut merged = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        merged.add(0x1010, 4);
+        let mut first = merged.clone();
+        first.add(0x1020, 4);
+        let mut second = Trace::new(&[
+            Module::new("libc.so", 0x5000, 0x6000),
+            Module::new("abcd", 0x8000, 0x9000),
+        ]);
+        second.add(0x8020, 4);
+        second.add(0x5030, 4);
+
+        merged.merge_tagged(&first, 1).unwrap();
+        merged.merge_tagged(&second, 2).unwrap();
+        assert_eq!(merged.entries().len(), 3);
+        assert_eq!(merged.block_tags(), [None, Some(1), Some(2)]);
+
+        merged.remap_module_ids(&[(0, 1), (1, 0)]).unwrap();
+        assert_eq!(merged.block_tags(), [None, Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn merge_files_streams_entries() {
+        let dir = std::env::temp_dir().join(format!("drcov-merge-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (first_path, second_path, output) =
+            (dir.join("a.log"), dir.join("b.log"), dir.join("out.log"));
+
+        let mut first = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        first.add(0x1010, 4);
+        first.save(&first_path).unwrap();
+        let mut second = Trace::new(&[
+            Module::new("libc.so", 0x5000, 0x6000),
+            Module::new("abcd", 0x8000, 0x9000),
+        ]);
+        second.add(0x5020, 8);
+        second.add(0x8010, 4);
+        second.save(&second_path).unwrap();
+
+        merge_files(&[&first_path, &second_path], &output).unwrap();
+        let mut master = first.clone();
+        master.merge_from_file(&second_path).unwrap();
+        assert_eq!(master.entries().len(), 2);
+        assert!(master.merge_from_file(dir.join("missing.log")).is_err());
+
+        let merged = Trace::load(&output).unwrap();
+        let names = merged
+            .modules()
+            .iter()
+            .map(|m| m.name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["abcd", "libc.so"]);
+        let entries = merged
+            .entries()
+            .iter()
+            .map(|e| (e.mod_id(), e.start()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [(0, 0x10), (1, 0x20), (0, 0x10)]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_files_keeps_output_on_error() {
+        let dir = std::env::temp_dir().join(format!("drcov-merge-error-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (first_path, second_path, output) =
+            (dir.join("a.log"), dir.join("b.log"), dir.join("out.log"));
+
+        let mut first = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        first.add(0x1010, 4);
+        let mut bytes = Vec::new();
+        first
+            .write_with_layout(&mut bytes, crate::Layout::BbTableFirst)
+            .unwrap();
+        std::fs::write(&first_path, bytes).unwrap();
+        first.add(0x1020, 4);
+        let mut bytes = Vec::new();
+        first.write(&mut bytes).unwrap();
+        bytes.truncate(bytes.len() - 4);
+        std::fs::write(&second_path, bytes).unwrap();
+
+        merge_files(&[&first_path], &output).unwrap();
+        assert_eq!(Trace::load(&output).unwrap().entries().len(), 1);
+
+        assert!(merge_files(&[&first_path, &second_path], &output).is_err());
+        assert_eq!(Trace::load(&output).unwrap().entries().len(), 1);
+        assert!(!dir.join("out.log.tmp").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
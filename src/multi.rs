@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+
+use crate::Trace;
+
+/// A collection of traces recorded from several processes, keyed by pid.
+///
+/// Each process keeps its own module table, so coverage from forked children
+/// stays attributable to the process that produced it.
+#[derive(Clone, Debug, Default)]
+pub struct MultiProcessTrace {
+    /// Per-process traces, ordered by pid so output is deterministic.
+    traces: BTreeMap<u32, Trace>,
+}
+
+impl MultiProcessTrace {
+    /// Create an empty [`MultiProcessTrace`].
+    pub fn new() -> MultiProcessTrace {
+        MultiProcessTrace::default()
+    }
+
+    /// Add the trace for the process `pid`, returning the previous trace for that pid if any.
+    pub fn insert(&mut self, pid: u32, trace: Trace) -> Option<Trace> {
+        self.traces.insert(pid, trace)
+    }
+
+    /// Returns a reference to the [`Trace`] for the process `pid`.
+    pub fn get(&self, pid: u32) -> Option<&Trace> {
+        self.traces.get(&pid)
+    }
+
+    /// Returns a mutable reference to the [`Trace`] for the process `pid`.
+    pub fn get_mut(&mut self, pid: u32) -> Option<&mut Trace> {
+        self.traces.get_mut(&pid)
+    }
+
+    /// Iterate over the recorded processes in ascending pid order.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Trace)> {
+        self.traces.iter().map(|(pid, trace)| (*pid, trace))
+    }
+
+    /// Output every process as a single drcov file.
+    ///
+    /// The module tables are concatenated in pid order and each entry's module id
+    /// is offset by the number of modules belonging to the preceding processes.
+    /// The flavor, versions, line ending and metadata are taken from the process
+    /// with the lowest pid.
+    ///
+    /// Module names are written unchanged, so modules of different processes with
+    /// the same path appear as separate, identically named entries of the module
+    /// table; only their order tells which process they belong to. Hit counts are
+    /// not part of the drcov format, so a process that counts hits contributes each
+    /// block once. Use [`MultiProcessTrace::write_separate`] to keep processes
+    /// distinguishable by file name.
+    pub fn write_merged(&self, writer: &mut impl Write) -> Result<(), Error> {
+        let mut merged = match self.traces.values().next() {
+            Some(first) => Trace {
+                modules: Vec::new(),
+                ..first.with_entries(Vec::new())
+            },
+            None => Trace::new(&[]),
+        };
+
+        for trace in self.traces.values() {
+            let offset = merged.modules.len();
+            if offset + trace.modules.len() > usize::from(u16::MAX) + 1 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Merged module table exceeds u16::MAX modules",
+                ));
+            }
+
+            merged.modules.extend_from_slice(&trace.modules);
+            merged
+                .entries
+                .extend(trace.entries.iter().map(|entry| crate::BlockEntry {
+                    mod_id: entry.mod_id + offset as u16,
+                    ..*entry
+                }));
+        }
+
+        merged.write(writer)
+    }
+
+    /// Save each process to its own `drcov.<pid>.log` file inside `dir`.
+    ///
+    /// The directory is created if it does not already exist.
+    pub fn write_separate(&self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+
+        for (pid, trace) in &self.traces {
+            trace.save(dir.join(format!("drcov.{pid}.log")))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    #[test]
+    fn write_merged_offsets_module_ids() {
+        let mut parent =
+            Trace::new(&[Module::new("parent", 0x1000, 0x2000)]).with_version(crate::Version::V3);
+        parent.flavor = String::from("tracer");
+        parent.add(0x1010, 4);
+        let mut child = Trace::new(&[Module::new("child", 0x1000, 0x2000)]);
+        child.add(0x1020, 8);
+
+        let mut traces = MultiProcessTrace::new();
+        traces.insert(100, parent);
+        traces.insert(101, child);
+
+        let mut out = Vec::new();
+        traces.write_merged(&mut out).unwrap();
+
+        let text = String::from_utf8_lossy(&out);
+        assert!(text.starts_with("DRCOV VERSION: 3\nDRCOV FLAVOR: tracer\n"));
+        assert!(text.contains("Module Table: version 4, count 2"));
+        assert!(text.contains("BB Table: 2 bbs"));
+
+        // The child's entry is the last one written and must point at the second module.
+        let last = &out[out.len() - 8..];
+        assert_eq!(u16::from_le_bytes([last[6], last[7]]), 1);
+    }
+
+    #[test]
+    fn write_merged_writes_counted_blocks_once() {
+        let modules = [Module::new("abcd", 0x1000, 0x2000)];
+        let mut parent = Trace::new(&modules);
+        parent.track_hit_counts();
+        for address in [0x1010, 0x1010, 0x1010, 0x1020] {
+            parent.add(address, 4);
+        }
+        let mut child = Trace::new(&modules);
+        child.add(0x1010, 4);
+
+        let mut traces = MultiProcessTrace::new();
+        traces.insert(100, parent);
+        traces.insert(101, child);
+
+        let mut out = Vec::new();
+        traces.write_merged(&mut out).unwrap();
+        let merged = Trace::from_reader(&mut out.as_slice()).unwrap();
+        let names = merged
+            .modules()
+            .iter()
+            .map(|m| m.name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["abcd", "abcd"]);
+        let entries = merged
+            .entries()
+            .iter()
+            .map(|e| (e.mod_id(), e.start()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [(0, 0x10), (0, 0x20), (1, 0x10)]);
+    }
+
+    #[test]
+    fn write_separate_saves_each_process() {
+        let dir = std::env::temp_dir().join(format!("drcov-multi-{}", std::process::id()));
+        let mut parent = Trace::new(&[Module::new("parent", 0x1000, 0x2000)]);
+        parent.add(0x1010, 4);
+        let mut child = Trace::new(&[Module::new("child", 0x4000, 0x5000)]);
+        child.add(0x4020, 8);
+        child.add(0x4030, 4);
+
+        let mut traces = MultiProcessTrace::new();
+        traces.insert(100, parent);
+        traces.insert(101, child);
+        traces.write_separate(&dir).unwrap();
+
+        let parent = Trace::load(dir.join("drcov.100.log")).unwrap();
+        assert_eq!(parent.modules()[0].name(), "parent");
+        assert_eq!(parent.entries().len(), 1);
+        let child = Trace::load(dir.join("drcov.101.log")).unwrap();
+        assert_eq!(child.modules()[0].name(), "child");
+        assert_eq!(child.entries().len(), 2);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
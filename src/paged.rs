@@ -0,0 +1,166 @@
+use std::io::{Error, Write};
+
+use crate::{AddError, BlockEntry, Module, Trace};
+
+/// The size of a page of a [`PagedTrace`], in bytes.
+const PAGE_SIZE: u32 = 0x10000;
+
+/// A recorder that stores blocks compactly as offsets within 64 KiB pages of their module.
+///
+/// Consecutive blocks in the same page of the same module share a single run
+/// header, so each block only takes a 16-bit page offset and its 16-bit size,
+/// half the size of a [`BlockEntry`]. This helps for large traces whose blocks
+/// cluster in a few pages, as code that runs in loops or hot functions does. It
+/// hurts when consecutive blocks keep changing pages, as every change starts a new
+/// run, and a block that starts a run takes more memory than a [`BlockEntry`].
+///
+/// The compact form is only kept in memory: [`PagedTrace::write`] and
+/// [`PagedTrace::to_trace`] reconstruct the full 32-bit offsets.
+#[derive(Clone, Debug)]
+pub struct PagedTrace {
+    /// An empty trace holding the modules and settings, used to resolve addresses.
+    template: Trace,
+    /// The runs of consecutive blocks in the same page, in recording order.
+    runs: Vec<Run>,
+    /// The page offset and size of each block, in recording order.
+    blocks: Vec<(u16, u16)>,
+}
+
+/// Consecutive blocks recorded in the same page of the same module.
+#[derive(Clone, Copy, Debug)]
+struct Run {
+    mod_id: u16,
+    page: u16,
+    /// The number of blocks in the run.
+    len: u32,
+}
+
+impl PagedTrace {
+    /// Create a recorder using the given modules.
+    pub fn new(modules: &[Module]) -> PagedTrace {
+        PagedTrace::from_template(Trace::new(modules))
+    }
+
+    /// Create a recorder with the modules and settings of `template`.
+    ///
+    /// The settings that decide which blocks are recorded, such as exclusions and
+    /// the zero size policy, apply as in [`Trace::try_add`]. Hit counting and the
+    /// entry limits are not supported, and any entries of the template are ignored.
+    pub fn from_template(template: Trace) -> PagedTrace {
+        PagedTrace {
+            template: template.with_entries(Vec::new()),
+            runs: Vec::new(),
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Add a new coverage entry, as by [`Trace::add`].
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the entry cannot be recorded.
+    pub fn add(&mut self, address: u64, size: usize) {
+        if let Err(err) = self.try_add(address, size) {
+            panic!("{err}");
+        }
+    }
+
+    /// Add a new coverage entry, returning an error if it cannot be recorded, as by [`Trace::try_add`].
+    pub fn try_add(&mut self, address: u64, size: usize) -> Result<(), AddError> {
+        self.template.try_add(address, size)?;
+        // Excluded and skipped blocks are not recorded by the template.
+        if let Some(entry) = self.template.entries.pop() {
+            self.push(entry);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of recorded blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Returns true if no blocks are recorded.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Returns the recorded blocks with their full offsets, in recording order.
+    pub fn entries(&self) -> impl Iterator<Item = BlockEntry> + '_ {
+        let runs = self.runs.iter().scan(0, |start, run| {
+            let blocks = *start..*start + run.len as usize;
+            *start = blocks.end;
+            Some((run, blocks))
+        });
+        runs.flat_map(move |(run, blocks)| {
+            self.blocks[blocks]
+                .iter()
+                .map(move |&(offset, size)| BlockEntry {
+                    start: u32::from(run.page) * PAGE_SIZE + u32::from(offset),
+                    size,
+                    mod_id: run.mod_id,
+                })
+        })
+    }
+
+    /// Returns a [`Trace`] with the modules, settings and blocks of this recorder.
+    pub fn to_trace(&self) -> Trace {
+        self.template.with_entries(self.entries().collect())
+    }
+
+    /// Output the coverage information in drcov format, as by [`Trace::write`].
+    pub fn write(&self, writer: &mut impl Write) -> Result<(), Error> {
+        self.template.write_header(writer, self.len())?;
+        for entry in self.entries() {
+            entry.write(writer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Store a block, extending the last run if it is in the same page.
+    fn push(&mut self, entry: BlockEntry) {
+        let page = (entry.start / PAGE_SIZE) as u16;
+        match self.runs.last_mut() {
+            Some(run) if run.mod_id == entry.mod_id && run.page == page && run.len < u32::MAX => {
+                run.len += 1;
+            }
+            _ => self.runs.push(Run {
+                mod_id: entry.mod_id,
+                page,
+                len: 1,
+            }),
+        }
+        self.blocks
+            .push(((entry.start % PAGE_SIZE) as u16, entry.size));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paged_trace_writes_full_offsets() {
+        let modules = [
+            Module::new("abcd", 0x10000, 0x40000),
+            Module::new("libc.so", 0x80000, 0x90000),
+        ];
+        let mut paged = PagedTrace::new(&modules);
+        let mut trace = Trace::new(&modules);
+        for address in [0x10010, 0x10020, 0x2fff0, 0x80040, 0x10030] {
+            paged.add(address, 4);
+            trace.add(address, 4);
+        }
+        assert!(paged.try_add(0x50000, 4).is_err());
+
+        assert_eq!(paged.len(), 5);
+        assert_eq!(paged.runs.len(), 4);
+        assert_eq!(paged.to_trace().entries(), trace.entries());
+
+        let (mut expected, mut written) = (Vec::new(), Vec::new());
+        trace.write(&mut expected).unwrap();
+        paged.write(&mut written).unwrap();
+        assert_eq!(written, expected);
+    }
+}
@@ -0,0 +1,1071 @@
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::path::Path;
+
+use crate::{BlockEntry, DrcovError, Endianness, Issue, LineEnding, Module, Trace, Version};
+
+impl Trace {
+    /// Read a trace in drcov format.
+    ///
+    /// Only the header and module table are decoded as UTF-8 text, and a leading
+    /// UTF-8 byte order mark is skipped. The BB table is read as raw bytes. Any data
+    /// following the declared number of basic block entries is ignored. Traces
+    /// written with the non-standard [`Layout::BbTableFirst`](crate::Layout::BbTableFirst)
+    /// are read as well.
+    pub fn from_reader(reader: impl Read) -> Result<Trace, DrcovError> {
+        Trace::from_reader_with(reader, Endianness::Little)
+    }
+
+    /// Read a trace in drcov format whose BB table uses the given byte order.
+    ///
+    /// The entries are stored, and written again, in the native representation of
+    /// this crate, so a trace read from a big-endian table is written little-endian.
+    pub fn from_reader_with(
+        reader: impl Read,
+        endianness: Endianness,
+    ) -> Result<Trace, DrcovError> {
+        parse(&mut BufReader::new(reader), endianness, None)
+    }
+
+    /// Read a trace in drcov format, failing unless the BB table holds exactly the declared entries.
+    ///
+    /// Unlike [`Trace::from_reader`], any data following the declared number of
+    /// basic block entries is an error, so a file whose BB table is longer than its
+    /// `BB Table: N bbs` line is reported as malformed. A shorter table is an error
+    /// in both cases.
+    pub fn from_reader_strict(reader: impl Read) -> Result<Trace, DrcovError> {
+        let mut reader = BufReader::new(reader);
+        let trace = parse(&mut reader, Endianness::Little, None)?;
+        if !reader.fill_buf()?.is_empty() {
+            return Err(malformed("trailing data after the BB table"));
+        }
+        Ok(trace)
+    }
+
+    /// Read a trace written by [`Trace::write_slim`], using `modules` as its module table.
+    ///
+    /// The manifest id and module count of the trace must match `modules`, else the
+    /// trace is reported as malformed. Traces with a full module table are read as
+    /// by [`Trace::from_reader`], ignoring `modules`.
+    pub fn from_reader_with_manifest(
+        reader: impl Read,
+        modules: &[Module],
+    ) -> Result<Trace, DrcovError> {
+        let mut reader = BufReader::new(reader);
+        let header = parse_header_with(&mut reader, Some(modules))?;
+        read_entries(&mut reader, header, Endianness::Little, None)
+    }
+
+    /// Read a trace in drcov format, dropping entries that refer to unknown modules.
+    ///
+    /// This recovers the valid part of concatenated or truncated files, where
+    /// [`Trace::from_reader`] fails with [`DrcovError::DanglingModuleId`]. Each
+    /// dropped entry is reported as an [`Issue::InvalidModuleId`] with its index in
+    /// the BB table.
+    pub fn from_reader_lenient(reader: impl Read) -> Result<(Trace, Vec<Issue>), DrcovError> {
+        let mut issues = Vec::new();
+        let trace = parse(
+            &mut BufReader::new(reader),
+            Endianness::Little,
+            Some(&mut issues),
+        )?;
+        Ok((trace, issues))
+    }
+
+    /// Read all traces from a stream of concatenated drcov files.
+    ///
+    /// Each trace ends after the number of entries declared by its BB table line,
+    /// and the next trace starts directly after it. Reading stops at the end of the
+    /// stream, so an empty stream yields no traces.
+    pub fn read_all(reader: impl Read) -> Result<Vec<Trace>, DrcovError> {
+        let mut reader = BufReader::new(reader);
+        let mut traces = Vec::new();
+        while !reader.fill_buf()?.is_empty() {
+            traces.push(parse(&mut reader, Endianness::Little, None)?);
+        }
+        Ok(traces)
+    }
+
+    /// Parse a trace at the start of `bytes`, returning it with the number of bytes it occupied.
+    ///
+    /// The count includes the full BB table, so the next item in a buffer of packed
+    /// traces or frames starts at `bytes[consumed..]`.
+    pub fn parse_prefix(bytes: &[u8]) -> Result<(Trace, usize), DrcovError> {
+        let mut cursor = std::io::Cursor::new(bytes);
+        let trace = parse(&mut cursor, Endianness::Little, None)?;
+        Ok((trace, cursor.position() as usize))
+    }
+
+    /// Read a trace written by drcov with the `-dump_text` option.
+    ///
+    /// The header and module table are the same as in the binary format, but the BB
+    /// table is text: a `module id, start, size:` line followed by one
+    /// `module[  0]: 0x00001000,   9` line per entry, giving the module id, the
+    /// offset from the module base and the size. Any lines following the declared
+    /// number of entries are ignored.
+    pub fn from_drcov_text(reader: impl Read) -> Result<Trace, DrcovError> {
+        let mut reader = BufReader::new(reader);
+        let (mut trace, count, leading) = parse_header(&mut reader)?;
+        if !leading.is_empty() {
+            return Err(malformed("BB table before the module table in text dump"));
+        }
+
+        let (line, _) = next_line(&mut reader)?;
+        if line.trim() != "module id, start, size:" {
+            return Err(malformed(format!(
+                "expected BB table columns, found `{line}`"
+            )));
+        }
+
+        for entry_index in 0..count {
+            let (line, _) = next_line(&mut reader)?;
+            let entry = line
+                .trim()
+                .strip_prefix("module[")
+                .and_then(|rest| rest.split_once("]:"))
+                .and_then(|(mod_id, rest)| {
+                    let (start, size) = rest.split_once(',')?;
+                    Some(BlockEntry {
+                        start: u32::try_from(parse_number(start.trim())?).ok()?,
+                        size: u16::try_from(parse_number(size.trim())?).ok()?,
+                        mod_id: mod_id.trim().parse().ok()?,
+                    })
+                })
+                .ok_or_else(|| malformed(format!("invalid BB table entry `{line}`")))?;
+            if usize::from(entry.mod_id) >= trace.modules.len() {
+                return Err(DrcovError::DanglingModuleId {
+                    entry_index,
+                    mod_id: entry.mod_id,
+                });
+            }
+            trace.entries.push(entry);
+        }
+
+        Ok(trace)
+    }
+
+    /// Load a trace from the drcov file at the given path.
+    pub fn load(path: impl AsRef<Path>) -> Result<Trace, DrcovError> {
+        Trace::from_reader(std::fs::File::open(path)?)
+    }
+}
+
+impl Module {
+    /// Read a module table from a simple text layout, one module per line.
+    ///
+    /// Each line holds the module name, its base address and its end address,
+    /// separated by whitespace, for example `/bin/target 0x400000 0x428000`. The
+    /// end may instead be given as a size by prefixing it with `+`, as in
+    /// `libc.so.6 0x7f0000000000 +0x200000`. Numbers are decimal or `0x` prefixed
+    /// hexadecimal, and names may contain spaces. Empty lines and lines starting
+    /// with `#` are ignored.
+    pub fn load_table(reader: impl Read) -> Result<Vec<Module>, DrcovError> {
+        let mut modules = Vec::new();
+
+        for (index, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || malformed(format!("invalid module on line {}: `{line}`", index + 1));
+            let (rest, end) = line.rsplit_once(char::is_whitespace).ok_or_else(invalid)?;
+            let (name, base) = rest
+                .trim_end()
+                .rsplit_once(char::is_whitespace)
+                .ok_or_else(invalid)?;
+
+            let base = parse_number(base).ok_or_else(invalid)?;
+            let end = match end.strip_prefix('+') {
+                Some(size) => parse_number(size).and_then(|size| base.checked_add(size)),
+                None => parse_number(end),
+            }
+            .ok_or_else(invalid)?;
+            let module = Module::try_new(name.trim_end().to_owned(), base, end)
+                .map_err(|err| malformed(format!("{err} on line {}", index + 1)))?;
+            modules.push(module);
+        }
+
+        Ok(modules)
+    }
+}
+
+/// Receives the modules and blocks of a trace as it is read by [`parse_visit`].
+///
+/// Both methods do nothing by default, so implement only the ones you need.
+pub trait Visitor {
+    /// Called for each module of the module table, in module id order.
+    fn on_module(&mut self, module: &Module) {
+        let _ = module;
+    }
+
+    /// Called for each entry of the BB table, in file order.
+    fn on_block(&mut self, entry: &BlockEntry) {
+        let _ = entry;
+    }
+}
+
+/// Read a trace in drcov format, passing its modules and blocks to `visitor` instead of storing them.
+///
+/// All modules are visited before the first block. The entries are streamed from
+/// `reader` without being collected, so reading takes constant memory apart from
+/// the module table, regardless of the size of the BB table. Only the
+/// [`Layout::BbTableFirst`](crate::Layout::BbTableFirst) layout buffers the BB
+/// table until the module table has been read. The same traces are
+/// accepted as by [`Trace::from_reader`], and blocks that refer to unknown modules
+/// are an error after the preceding blocks were visited.
+pub fn parse_visit(reader: impl Read, visitor: &mut impl Visitor) -> Result<(), DrcovError> {
+    let mut reader = BufReader::new(reader);
+    let (trace, count, leading) = parse_header(&mut reader)?;
+    for module in &trace.modules {
+        visitor.on_module(module);
+    }
+
+    let mut entries = leading.as_slice().chain(&mut reader);
+    visit_entries(
+        &mut entries,
+        trace.modules.len(),
+        count,
+        Endianness::Little,
+        None,
+        |entry| visitor.on_block(&entry),
+    )
+}
+
+impl TryFrom<&[u8]> for Trace {
+    type Error = DrcovError;
+
+    /// Parse a trace held entirely in memory, as by [`Trace::from_reader`].
+    fn try_from(bytes: &[u8]) -> Result<Trace, DrcovError> {
+        parse(&mut &*bytes, Endianness::Little, None)
+    }
+}
+
+impl TryFrom<Vec<u8>> for Trace {
+    type Error = DrcovError;
+
+    /// Parse a trace held entirely in memory, as by [`Trace::from_reader`].
+    fn try_from(bytes: Vec<u8>) -> Result<Trace, DrcovError> {
+        Trace::try_from(bytes.as_slice())
+    }
+}
+
+/// Parse a single trace, leaving `reader` positioned directly after its BB table.
+///
+/// Entries that refer to unknown modules are an error, unless `dangling` is given
+/// to collect them instead.
+pub(crate) fn parse(
+    reader: &mut impl BufRead,
+    endianness: Endianness,
+    dangling: Option<&mut Vec<Issue>>,
+) -> Result<Trace, DrcovError> {
+    let header = parse_header(reader)?;
+    read_entries(reader, header, endianness, dangling)
+}
+
+/// Read the BB table of a trace whose header was parsed by [`parse_header`].
+fn read_entries(
+    reader: &mut impl BufRead,
+    (mut trace, count, leading): (Trace, usize, Vec<u8>),
+    endianness: Endianness,
+    dangling: Option<&mut Vec<Issue>>,
+) -> Result<Trace, DrcovError> {
+    let modules = trace.modules.len();
+    let mut entries = leading.as_slice().chain(reader);
+    visit_entries(
+        &mut entries,
+        modules,
+        count,
+        endianness,
+        dangling,
+        |entry| trace.entries.push(entry),
+    )?;
+    Ok(trace)
+}
+
+/// Read `count` entries, passing each one that refers to one of the `modules` to `on_entry`.
+///
+/// Entries that refer to unknown modules are an error, unless `dangling` is given
+/// to collect them instead.
+fn visit_entries(
+    reader: &mut impl Read,
+    modules: usize,
+    count: usize,
+    endianness: Endianness,
+    mut dangling: Option<&mut Vec<Issue>>,
+    mut on_entry: impl FnMut(BlockEntry),
+) -> Result<(), DrcovError> {
+    let mut buf = [0; BlockEntry::SIZE];
+    for entry_index in 0..count {
+        read_entry(reader, &mut buf)?;
+
+        let entry = match endianness {
+            Endianness::Little => BlockEntry::from_bytes(&buf),
+            Endianness::Big => BlockEntry::from_be_bytes(&buf),
+        };
+        if usize::from(entry.mod_id) >= modules {
+            let mod_id = entry.mod_id;
+            match &mut dangling {
+                Some(issues) => {
+                    issues.push(Issue::InvalidModuleId {
+                        entry: entry_index,
+                        mod_id,
+                    });
+                    continue;
+                }
+                None => {
+                    return Err(DrcovError::DanglingModuleId {
+                        entry_index,
+                        mod_id,
+                    })
+                }
+            }
+        }
+        on_entry(entry);
+    }
+
+    Ok(())
+}
+
+/// Parse everything up to and including the BB table line, returning a trace without
+/// entries, the number of entries and the raw entries that were read before the module
+/// table.
+///
+/// In the standard layout the entries follow the header and the returned bytes are
+/// empty. In the [`Layout::BbTableFirst`](crate::Layout::BbTableFirst) layout they hold
+/// the whole BB table, and nothing follows the module table.
+pub(crate) fn parse_header(
+    reader: &mut impl BufRead,
+) -> Result<(Trace, usize, Vec<u8>), DrcovError> {
+    parse_header_with(reader, None)
+}
+
+/// Parse the header as by [`parse_header`], accepting a module manifest line in place of
+/// the module table if the shared `manifest` table is given.
+fn parse_header_with(
+    reader: &mut impl BufRead,
+    manifest: Option<&[Module]>,
+) -> Result<(Trace, usize, Vec<u8>), DrcovError> {
+    // Remember the line terminator so that re-writing the trace keeps it.
+    let (line, line_ending) = next_line(reader)?;
+    // Some Windows tools start the header with a byte order mark.
+    let line = line.strip_prefix('\u{feff}').unwrap_or(&line);
+    let version = match line.strip_prefix("DRCOV VERSION:").map(str::trim) {
+        Some("2") => Version::V2,
+        Some("3") => Version::V3,
+        _ => return Err(malformed(format!("unsupported version line `{line}`"))),
+    };
+
+    let (line, _) = next_line(reader)?;
+    let flavor = line
+        .strip_prefix("DRCOV FLAVOR:")
+        .ok_or_else(|| malformed(format!("expected flavor line, found `{line}`")))?
+        .trim()
+        .to_owned();
+
+    // Comment lines before the module table carry metadata and module load orders.
+    let mut metadata = Vec::new();
+    let mut load_orders = Vec::new();
+    let line = loop {
+        let (line, _) = next_line(reader)?;
+        let Some(comment) = line.strip_prefix('#') else {
+            break line;
+        };
+        let Some((key, value)) = comment.trim_start().split_once(": ") else {
+            continue;
+        };
+        let load_order = key
+            .strip_prefix("module ")
+            .and_then(|key| key.strip_suffix(" load order"))
+            .and_then(|id| Some((id.parse::<usize>().ok()?, value.parse::<u64>().ok()?)));
+        match load_order {
+            Some(load_order) => load_orders.push(load_order),
+            None => metadata.push((key.to_owned(), value.to_owned())),
+        }
+    };
+    let manifest = manifest.filter(|_| line.starts_with("Module Manifest:"));
+    let (count, leading, (mut modules, module_table_version)) =
+        match (manifest, bb_table_count(&line)) {
+            (Some(manifest), _) => {
+                let table = parse_manifest(&line, manifest)?;
+                let (line, _) = next_line(reader)?;
+                let count = bb_table_count(&line)
+                    .ok_or_else(|| malformed(format!("expected BB table line, found `{line}`")))?;
+                (count, Vec::new(), table)
+            }
+            (None, Some(count)) => {
+                let mut leading = Vec::new();
+                let len = count.saturating_mul(BlockEntry::SIZE);
+                reader.take(len as u64).read_to_end(&mut leading)?;
+                if leading.len() != len {
+                    return Err(malformed("truncated BB table"));
+                }
+                let (line, _) = next_line(reader)?;
+                (count, leading, parse_module_table(reader, &line)?)
+            }
+            (None, None) => {
+                let table = parse_module_table(reader, &line)?;
+                let (line, _) = next_line(reader)?;
+                let count = bb_table_count(&line)
+                    .ok_or_else(|| malformed(format!("expected BB table line, found `{line}`")))?;
+                (count, Vec::new(), table)
+            }
+        };
+    for (id, load_order) in load_orders {
+        if let Some(module) = modules.get_mut(id) {
+            module.load_order = Some(load_order);
+        }
+    }
+
+    let trace = Trace {
+        modules,
+        flavor,
+        version,
+        line_ending,
+        module_table_version,
+        metadata,
+        ..Trace::new(&[])
+    };
+    Ok((trace, count, leading))
+}
+
+/// Check a `Module Manifest: <id>, count <n>` line against the shared module table.
+fn parse_manifest(line: &str, modules: &[Module]) -> Result<(Vec<Module>, u32), DrcovError> {
+    let (id, count) = line
+        .strip_prefix("Module Manifest:")
+        .and_then(|rest| rest.split_once(','))
+        .and_then(|(id, count)| {
+            let id = u64::from_str_radix(id.trim(), 16).ok()?;
+            let count = count
+                .trim()
+                .strip_prefix("count")?
+                .trim()
+                .parse::<usize>()
+                .ok()?;
+            Some((id, count))
+        })
+        .ok_or_else(|| malformed(format!("invalid module manifest line `{line}`")))?;
+    if id != crate::canonical::manifest_id(modules) || count != modules.len() {
+        return Err(malformed(format!(
+            "module manifest {id:016x} with {count} modules does not match the given modules"
+        )));
+    }
+    Ok((
+        modules.to_vec(),
+        Version::default().default_module_table_version(),
+    ))
+}
+
+/// Returns the number of entries announced by a `BB Table: <count> bbs` line.
+fn bb_table_count(line: &str) -> Option<usize> {
+    line.strip_prefix("BB Table:")
+        .and_then(|rest| rest.trim().strip_suffix("bbs"))
+        .and_then(|count| count.trim().parse().ok())
+}
+
+/// Read the bytes of the next BB table entry.
+pub(crate) fn read_entry(
+    reader: &mut impl Read,
+    buf: &mut [u8; BlockEntry::SIZE],
+) -> Result<(), DrcovError> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        ErrorKind::UnexpectedEof => malformed("truncated BB table"),
+        _ => err.into(),
+    })
+}
+
+/// Parse the module table header, columns and rows.
+///
+/// Returns the modules and the module table version to use when writing them back out.
+fn parse_module_table(
+    reader: &mut impl BufRead,
+    line: &str,
+) -> Result<(Vec<Module>, u32), DrcovError> {
+    let table = line
+        .strip_prefix("Module Table:")
+        .ok_or_else(|| malformed(format!("expected module table line, found `{line}`")))?;
+
+    // Newer tables look like `version 4, count 2` while version 1 only gives the count.
+    let version = table
+        .split(',')
+        .find_map(|part| part.trim().strip_prefix("version"))
+        .and_then(|version| version.trim().parse::<u32>().ok())
+        .filter(|version| (2..=4).contains(version))
+        .unwrap_or(4);
+    let count = match table.rsplit_once("count") {
+        Some((_, count)) => count,
+        None => table,
+    };
+    let count = count
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .ok_or_else(|| malformed(format!("invalid module table line `{line}`")))?;
+
+    // Some producers omit the optional columns line, in which case the version implies
+    // the layout.
+    let line = match reader.fill_buf()?.starts_with(b"Columns:") {
+        true => next_line(reader)?.0,
+        false => String::new(),
+    };
+    let mut pending = None;
+    let columns = match line.strip_prefix("Columns:") {
+        Some(columns) => columns.split(',').map(str::trim).collect::<Vec<_>>(),
+        None if count == 0 => return Ok((Vec::new(), version)),
+        None => {
+            let (row, _) = next_line(reader)?;
+            let columns = implied_columns(version, &row);
+            pending = Some(row);
+            columns
+        }
+    };
+    let column = |names: &[&str]| {
+        columns
+            .iter()
+            .position(|column| names.contains(column))
+            .ok_or_else(|| malformed(format!("missing `{}` column", names[0])))
+    };
+    let id_column = column(&["id"])?;
+    let base_column = column(&["start", "base"])?;
+    let end_column = column(&["end"])?;
+    let path_column = column(&["path"])?;
+    let containing_column = column(&["containing_id"]).ok();
+    let offset_column = column(&["offset"]).ok();
+    let checksum_column = column(&["checksum"]).ok();
+    let entry_column = column(&["entry"]).ok();
+
+    let mut modules = Vec::new();
+    for index in 0..count {
+        let row = match pending.take() {
+            Some(row) => row,
+            None => next_line(reader)?.0,
+        };
+
+        // The path is the last column and may itself contain commas.
+        let fields = row
+            .splitn(columns.len(), ',')
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        if fields.len() != columns.len() {
+            return Err(malformed(format!("invalid module row `{row}`")));
+        }
+
+        let number = |column: usize| {
+            parse_number(fields[column])
+                .ok_or_else(|| malformed(format!("invalid number in module row `{row}`")))
+        };
+        if number(id_column)? != index as u64 {
+            return Err(malformed(format!("unexpected module id in row `{row}`")));
+        }
+
+        let mut module = Module::try_new(
+            fields[path_column].to_owned(),
+            number(base_column)?,
+            number(end_column)?,
+        )
+        .map_err(|err| malformed(err.to_string()))?;
+        if let Some(column) = containing_column {
+            // Older writers set every `containing_id` to 0, so only treat rows as
+            // segments when they point back at an earlier module of the same name.
+            let containing_id = number(column)?;
+            module.continuation = containing_id != index as u64
+                && modules
+                    .get(containing_id as usize)
+                    .is_some_and(|head: &Module| head.name == module.name);
+        }
+        if let Some(column) = offset_column {
+            module.offset = number(column)?;
+        }
+        if let Some(column) = entry_column {
+            module.entry = number(column)?;
+        }
+        if let Some(column) = checksum_column {
+            // A zero checksum means that none was recorded.
+            let checksum = u32::try_from(number(column)?)
+                .map_err(|_| malformed(format!("invalid checksum in module row `{row}`")))?;
+            module.checksum = (checksum != 0).then_some(checksum);
+        }
+        modules.push(module);
+    }
+
+    Ok((modules, version))
+}
+
+/// Returns the columns written by DynamoRIO for a module table `version` without a columns line.
+///
+/// Rows whose two fields after the fixed columns are hexadecimal numbers followed by a
+/// path are taken to include the checksum and timestamp columns. Only the fields
+/// before the path are inspected, so a path containing commas is not mistaken for
+/// extra columns.
+fn implied_columns(version: u32, row: &str) -> Vec<&'static str> {
+    let mut columns = match version {
+        2 => vec!["id", "base", "end", "entry"],
+        3 => vec!["id", "containing_id", "start", "end", "entry"],
+        _ => vec!["id", "containing_id", "start", "end", "entry", "offset"],
+    };
+    let fields = row.splitn(columns.len() + 3, ',').collect::<Vec<_>>();
+    let is_hex = |field: &str| {
+        field
+            .trim()
+            .strip_prefix("0x")
+            .is_some_and(|hex| u64::from_str_radix(hex, 16).is_ok())
+    };
+    let extra = &fields[columns.len().min(fields.len())..];
+    if extra.len() == 3 && is_hex(extra[0]) && is_hex(extra[1]) {
+        columns.extend(["checksum", "timestamp"]);
+    }
+    columns.push("path");
+    columns
+}
+
+/// Read a single line of the text header, returning it without its line terminator.
+fn next_line(reader: &mut impl BufRead) -> Result<(String, LineEnding), DrcovError> {
+    let mut buf = Vec::new();
+    if reader.read_until(b'\n', &mut buf)? == 0 {
+        return Err(malformed("unexpected end of file"));
+    }
+
+    let line = String::from_utf8(buf).map_err(|_| malformed("header is not valid UTF-8"))?;
+    let line_ending = match line.ends_with("\r\n") {
+        true => LineEnding::CrLf,
+        false => LineEnding::Lf,
+    };
+
+    Ok((line.trim_end_matches(['\r', '\n']).to_owned(), line_ending))
+}
+
+/// Parse a hexadecimal (`0x` prefixed) or decimal number.
+fn parse_number(field: &str) -> Option<u64> {
+    match field.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => field.parse().ok(),
+    }
+}
+
+fn malformed(reason: impl Into<String>) -> DrcovError {
+    DrcovError::Malformed(reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Layout;
+
+    #[test]
+    fn round_trip() {
+        let mut modules = vec![
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("C:\\Program Files, x86\\libc.dll", 0x555000, 0x556000),
+        ];
+        modules.extend(Module::with_segments(
+            "split",
+            0x8000,
+            &[0..0x100, 0x200..0x300],
+        ));
+        let mut trace = Trace::new(&modules);
+        trace.add(0x1204, 3);
+        trace.add(0x555207, 12);
+
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        let parsed = Trace::from_reader(out.as_slice()).unwrap();
+
+        assert_eq!(parsed.modules(), trace.modules());
+        assert_eq!(parsed.entries(), trace.entries());
+        assert_eq!(parsed.flavor, "drcov");
+
+        let mut v3 = trace.clone();
+        v3.set_version(Version::V3);
+        v3.modules[0] = v3.modules[0].clone().with_checksum(0xdeadbeef);
+        let mut out_v3 = Vec::new();
+        v3.write(&mut out_v3).unwrap();
+        let parsed = Trace::from_reader(out_v3.as_slice()).unwrap();
+        assert_eq!(parsed.modules(), v3.modules());
+
+        let from_slice = Trace::try_from(out.as_slice()).unwrap();
+        assert_eq!(from_slice.entries(), trace.entries());
+        let from_vec = Trace::try_from(out).unwrap();
+        assert_eq!(from_vec.modules(), trace.modules());
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        trace.add_metadata("tool", "fuzzer 1.2");
+        trace.add_metadata("command", "fuzzer --input a: b");
+        trace.add(0x1010, 4);
+
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        assert!(out.starts_with(
+            b"DRCOV VERSION: 2\nDRCOV FLAVOR: drcov\n# tool: fuzzer 1.2\n# command: fuzzer --input a: b\nModule Table"
+        ));
+
+        let parsed = Trace::try_from(out).unwrap();
+        assert_eq!(parsed.metadata(), trace.metadata());
+        assert_eq!(parsed.entries(), trace.entries());
+
+        let modules = [
+            Module::new("abcd", 0x1000, 0x2000).with_load_order(7),
+            Module::new("libc.so", 0x5000, 0x6000),
+        ];
+        let mut out = Vec::new();
+        Trace::new(&modules).write(&mut out).unwrap();
+        assert!(out.starts_with(
+            b"DRCOV VERSION: 2\nDRCOV FLAVOR: drcov\n# module 0 load order: 7\nModule Table"
+        ));
+        let parsed = Trace::try_from(out).unwrap();
+        assert_eq!(parsed.modules(), modules);
+        assert_eq!(parsed.modules()[0].load_order(), Some(7));
+        assert!(parsed.metadata().is_empty());
+    }
+
+    #[test]
+    fn slim_trace_uses_manifest() {
+        let modules = [
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("libc.so", 0x5000, 0x6000),
+        ];
+        let mut trace = Trace::new(&modules);
+        trace.add(0x1010, 4);
+        trace.add(0x5020, 8);
+
+        let mut out = Vec::new();
+        trace.write_slim(&mut out).unwrap();
+        let header = format!("Module Manifest: {:016x}, count 2\n", trace.manifest_id());
+        assert!(String::from_utf8_lossy(&out).contains(&header));
+        assert!(Trace::from_reader(out.as_slice()).is_err());
+
+        let parsed = Trace::from_reader_with_manifest(out.as_slice(), &modules).unwrap();
+        assert_eq!(parsed.modules(), modules);
+        assert_eq!(parsed.entries(), trace.entries());
+        assert!(matches!(
+            Trace::from_reader_with_manifest(out.as_slice(), &modules[..1]),
+            Err(DrcovError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn visit_modules_and_blocks() {
+        #[derive(Default)]
+        struct Totals {
+            modules: Vec<String>,
+            bytes: u64,
+        }
+
+        impl Visitor for Totals {
+            fn on_module(&mut self, module: &Module) {
+                self.modules.push(module.name().to_owned());
+            }
+
+            fn on_block(&mut self, entry: &BlockEntry) {
+                self.bytes += u64::from(entry.size());
+            }
+        }
+
+        let mut trace = Trace::new(&[
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("libc.so", 0x5000, 0x6000),
+        ]);
+        trace.add(0x1010, 4);
+        trace.add(0x5020, 8);
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+
+        let mut totals = Totals::default();
+        parse_visit(out.as_slice(), &mut totals).unwrap();
+        assert_eq!(totals.modules, ["abcd", "libc.so"]);
+        assert_eq!(totals.bytes, 12);
+
+        let mut out = Vec::new();
+        trace
+            .write_with_layout(&mut out, Layout::BbTableFirst)
+            .unwrap();
+        let mut totals = Totals::default();
+        parse_visit(out.as_slice(), &mut totals).unwrap();
+        assert_eq!(totals.bytes, 12);
+    }
+
+    #[test]
+    fn strict_rejects_trailing_data() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        trace.add(0x1010, 4);
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        assert_eq!(
+            Trace::from_reader_strict(out.as_slice()).unwrap().entries(),
+            trace.entries()
+        );
+
+        out.extend_from_slice(&[0x20, 0, 0, 0, 4, 0, 0, 0]);
+        assert!(Trace::from_reader(out.as_slice()).is_ok());
+        assert!(matches!(
+            Trace::from_reader_strict(out.as_slice()),
+            Err(DrcovError::Malformed(_))
+        ));
+        assert!(Trace::from_reader_strict(&out[..out.len() - 9]).is_err());
+    }
+
+    #[test]
+    fn read_concatenated_traces() {
+        let mut first = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        first.add(0x1010, 4);
+        // The entry bytes end in a line feed, which must not be mistaken for a header line.
+        first.add(0x1000 + 0x0a0a, 0x0a0a);
+        let mut second = Trace::new(&[Module::new("libc.so", 0x5000, 0x6000)]);
+        second.add(0x5020, 8);
+
+        let mut out = Vec::new();
+        first.write(&mut out).unwrap();
+        second.write(&mut out).unwrap();
+        Trace::new(&[]).write(&mut out).unwrap();
+
+        let traces = Trace::read_all(out.as_slice()).unwrap();
+        assert_eq!(traces.len(), 3);
+        assert_eq!(traces[0].entries(), first.entries());
+        assert_eq!(traces[1].modules(), second.modules());
+        assert_eq!(traces[1].entries(), second.entries());
+        assert!(traces[2].modules().is_empty());
+        assert!(Trace::read_all(&[][..]).unwrap().is_empty());
+
+        second.write(&mut out).unwrap();
+        out.truncate(out.len() - 1);
+        assert!(Trace::read_all(out.as_slice()).is_err());
+
+        let (parsed, consumed) = Trace::parse_prefix(&out).unwrap();
+        assert_eq!(parsed.entries(), first.entries());
+        let (parsed, _) = Trace::parse_prefix(&out[consumed..]).unwrap();
+        assert_eq!(parsed.entries(), second.entries());
+    }
+
+    #[test]
+    fn parse_bb_table_first_layout() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000).with_load_order(1)]);
+        trace.add_metadata("tool", "fuzzer");
+        trace.add(0x1010, 4);
+        trace.add(0x1000 + 0x0a0a, 0x0a0a);
+
+        let mut out = Vec::new();
+        trace
+            .write_with_layout(&mut out, Layout::BbTableFirst)
+            .unwrap();
+        assert!(out.starts_with(
+            b"DRCOV VERSION: 2\nDRCOV FLAVOR: drcov\n# tool: fuzzer\n# module 0 load order: 1\nBB Table: 2 bbs\n"
+        ));
+        assert!(out.ends_with(b"abcd\n"));
+
+        let mut standard = Vec::new();
+        trace
+            .write_with_layout(&mut standard, Layout::Standard)
+            .unwrap();
+        let mut written = Vec::new();
+        trace.write(&mut written).unwrap();
+        assert_eq!(standard, written);
+
+        // Both layouts can follow each other in a single stream.
+        out.extend(standard);
+        let traces = Trace::read_all(out.as_slice()).unwrap();
+        assert_eq!(traces.len(), 2);
+        for parsed in traces {
+            assert_eq!(parsed.modules(), trace.modules());
+            assert_eq!(parsed.entries(), trace.entries());
+            assert_eq!(parsed.metadata(), trace.metadata());
+        }
+    }
+
+    #[test]
+    fn parse_dump_text() {
+        let input = "DRCOV VERSION: 2\n\
+            DRCOV FLAVOR: drcov\n\
+            Module Table: version 2, count 2\n\
+            Columns: id, base, end, entry, checksum, timestamp, path\n  \
+              0, 0x400000, 0x428000, 0x0000000000000000, 0x00000000, 0x00000000, /bin/true\n  \
+              1, 0x7f0000000000, 0x7f0000200000, 0x0000000000000000, 0x00000000, 0x00000000, /lib/libc.so.6\n\
+            BB Table: 2 bbs\n\
+            module id, start, size:\n\
+            module[  0]: 0x00001a20,   9\n\
+            module[  1]: 0x000200f0,  12\n";
+
+        let trace = Trace::from_drcov_text(input.as_bytes()).unwrap();
+        assert_eq!(trace.modules().len(), 2);
+        let entries = trace
+            .entries()
+            .iter()
+            .map(|e| (e.mod_id(), e.start(), e.size()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [(0, 0x1a20, 9), (1, 0x200f0, 12)]);
+
+        let truncated = &input[..input.len() - 30];
+        assert!(Trace::from_drcov_text(truncated.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn parse_module_table_version_2() {
+        let mut input = b"DRCOV VERSION: 2\r\n\
+            DRCOV FLAVOR: drcov-64\r\n\
+            Module Table: version 2, count 1\r\n\
+            Columns: id, base, end, entry, checksum, timestamp, path\r\n\
+             0, 0x00007ff6a0a40000, 0x00007ff6a0a5c000, 0x0000000000000000, 0x00000000, 0x00000000, C:\\target.exe\r\n\
+            BB Table: 1 bbs\r\n"
+            .to_vec();
+        input.extend_from_slice(&[0x10, 0, 0, 0, 4, 0, 0, 0]);
+
+        let trace = Trace::from_reader(input.as_slice()).unwrap();
+
+        assert_eq!(trace.flavor, "drcov-64");
+        assert_eq!(trace.line_ending, LineEnding::CrLf);
+        assert_eq!(trace.modules()[0].name(), "C:\\target.exe");
+        assert_eq!(trace.modules()[0].base(), 0x7ff6a0a40000);
+        assert_eq!(trace.entries()[0].start(), 0x10);
+        assert_eq!(trace.entries()[0].size(), 4);
+    }
+
+    #[test]
+    fn parse_missing_columns_line() {
+        let input = include_bytes!("../tests/fixtures/no_columns_v2.log");
+        let trace = Trace::from_reader(input.as_slice()).unwrap();
+
+        assert_eq!(trace.module_table_version, 2);
+        let modules = trace
+            .modules()
+            .iter()
+            .map(|m| (m.name(), m.base(), m.checksum()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            modules,
+            [
+                ("/bin/target", 0x400000, Some(0x1234)),
+                ("libc.so.6", 0x7f0000000000, None)
+            ]
+        );
+        assert_eq!(trace.entries().len(), 1);
+
+        let input = b"DRCOV VERSION: 2\n\
+            DRCOV FLAVOR: drcov\n\
+            Module Table: version 2, count 1\n\
+            0, 0x400000, 0x428000, 0x0000000000000000, /opt/a,b,c/target\n\
+            BB Table: 0 bbs\n";
+        let trace = Trace::from_reader(input.as_slice()).unwrap();
+        assert_eq!(trace.modules()[0].name(), "/opt/a,b,c/target");
+        assert_eq!(trace.modules()[0].checksum(), None);
+
+        let input = b"DRCOV VERSION: 2\n\
+            DRCOV FLAVOR: drcov\n\
+            Module Table: version 4, count 0\n\
+            BB Table: 0 bbs\n";
+        assert!(Trace::from_reader(input.as_slice())
+            .unwrap()
+            .modules()
+            .is_empty());
+    }
+
+    #[test]
+    fn qemu_round_trip() {
+        // A trace in the layout written by QEMU's `contrib/plugins/drcov.c`.
+        let input = include_bytes!("../tests/fixtures/qemu.log");
+
+        let trace = Trace::try_from(input.as_slice()).unwrap();
+        assert_eq!(trace.modules()[0].name(), "/tmp/target");
+        assert_eq!(trace.modules()[0].entry(), 0x401020);
+        assert_eq!(trace.entries().len(), 3);
+
+        let mut out = Vec::new();
+        let mut rebuilt = Trace::new(trace.modules()).qemu_compatible();
+        rebuilt.extend(trace);
+        rebuilt.write(&mut out).unwrap();
+        assert_eq!(out, input);
+    }
+
+    #[test]
+    fn parse_byte_order_mark() {
+        let trace =
+            Trace::try_from(include_bytes!("../tests/fixtures/bom.log").as_slice()).unwrap();
+        assert_eq!(trace.line_ending, LineEnding::CrLf);
+        assert_eq!(trace.modules()[0].name(), "C:\\target.exe");
+
+        // The entry bytes look like a CRLF but are not treated as text.
+        let entries = trace
+            .entries()
+            .iter()
+            .map(|e| (e.start(), e.size()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [(0x1010, 4), (0x1a0d, 0x0a)]);
+    }
+
+    #[test]
+    fn parse_big_endian_bb_table() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x20000)]);
+        trace.add(0x11234, 0x56);
+
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        let entry = out.len() - BlockEntry::SIZE;
+        out[entry..].copy_from_slice(&[0, 0x01, 0x02, 0x34, 0, 0x56, 0, 0]);
+
+        let parsed = Trace::from_reader_with(out.as_slice(), Endianness::Big).unwrap();
+        assert_eq!(parsed.entries(), trace.entries());
+    }
+
+    #[test]
+    fn load_module_table() {
+        let input = "# layout of the fuzz target\n\
+                     /opt/fuzz target 0x400000 0x428000\n\
+                     \n\
+                     libc.so.6\t0x7f0000000000   +0x200000\n";
+
+        let modules = Module::load_table(input.as_bytes()).unwrap();
+        assert_eq!(
+            modules,
+            [
+                Module::new("/opt/fuzz target", 0x400000, 0x428000),
+                Module::new("libc.so.6", 0x7f0000000000, 0x7f0000200000),
+            ]
+        );
+
+        assert!(Module::load_table("libc.so 0x1000".as_bytes()).is_err());
+        assert!(Module::load_table("libc.so 0x2000 0x1000".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn parse_dangling_module_id() {
+        let input = include_bytes!("../tests/fixtures/dangling.log");
+
+        assert!(matches!(
+            Trace::try_from(input.as_slice()),
+            Err(DrcovError::DanglingModuleId {
+                entry_index: 1,
+                mod_id: 5
+            })
+        ));
+
+        let (trace, issues) = Trace::from_reader_lenient(input.as_slice()).unwrap();
+        let starts = trace
+            .entries()
+            .iter()
+            .map(|e| e.start())
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [0x1000, 0x3000]);
+        assert_eq!(
+            issues,
+            [Issue::InvalidModuleId {
+                entry: 1,
+                mod_id: 5
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_truncated_bb_table() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        trace.add(0x1204, 3);
+
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        out.pop();
+
+        assert!(matches!(
+            Trace::from_reader(out.as_slice()),
+            Err(DrcovError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,52 @@
+use regex::Regex;
+
+use crate::Trace;
+
+impl Trace {
+    /// Returns a copy of this trace with only the modules whose names match `pattern` and their blocks.
+    ///
+    /// The pattern is matched anywhere in the module name, so anchor it to match
+    /// whole paths. The kept modules are renumbered in their original order and
+    /// hit counts are kept. A segment only stays linked to its image if the
+    /// preceding segment matches as well.
+    pub fn filter_modules_matching(&self, pattern: &Regex) -> Trace {
+        self.filtered_modules(|_, module| pattern.is_match(module.name()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    #[test]
+    fn filter_modules_by_pattern() {
+        let mut trace = Trace::new(&[
+            Module::new("/app/bin/server", 0x1000, 0x2000),
+            Module::new("/usr/lib/libc.so.6", 0x5000, 0x6000),
+            Module::new("/app/lib/libplugin.so", 0x8000, 0x9000),
+        ]);
+        trace.track_hit_counts();
+        trace.add(0x8010, 4);
+        trace.add(0x5010, 4);
+        trace.add(0x1020, 4);
+        trace.add(0x8010, 4);
+
+        let filtered = trace.filter_modules_matching(&Regex::new("^/app/").unwrap());
+        let names = filtered
+            .modules()
+            .iter()
+            .map(Module::name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["/app/bin/server", "/app/lib/libplugin.so"]);
+
+        let blocks = filtered
+            .entries()
+            .iter()
+            .map(|e| (e.mod_id(), e.start()))
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, [(1, 0x10), (0, 0x20)]);
+        assert_eq!(filtered.hit_counts(), Some(&[2, 1][..]));
+        assert_eq!(trace.entries().len(), 3);
+    }
+}
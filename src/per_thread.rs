@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use crate::{AddError, Module, Trace};
+
+/// A recorder that keeps a separate trace for each guest thread.
+///
+/// Blocks are recorded with the id of the guest thread that executed them, and
+/// every thread's trace uses the same module table. This attributes coverage to
+/// the threads of the traced program, unlike [`SharedModules`](crate::SharedModules),
+/// which combines the recorders of several host threads into one trace.
+#[derive(Clone, Debug)]
+pub struct PerThreadTrace {
+    /// The empty trace that the trace of each new thread is cloned from.
+    template: Trace,
+    /// The trace of each thread that recorded a block, by thread id.
+    traces: HashMap<u64, Trace>,
+}
+
+impl PerThreadTrace {
+    /// Create a recorder whose per-thread traces use the given modules.
+    pub fn new(modules: &[Module]) -> PerThreadTrace {
+        PerThreadTrace::from_template(Trace::new(modules))
+    }
+
+    /// Create a recorder whose per-thread traces start as empty copies of `template`.
+    ///
+    /// The traces keep the modules and settings of the template, such as hit
+    /// counting, as by [`Trace::clone_modules_only`].
+    pub fn from_template(template: Trace) -> PerThreadTrace {
+        PerThreadTrace {
+            template: template.clone_modules_only(),
+            traces: HashMap::new(),
+        }
+    }
+
+    /// Add a new coverage entry for the thread `thread_id`, as by [`Trace::add`].
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the entry cannot be recorded.
+    pub fn add(&mut self, thread_id: u64, address: u64, size: usize) {
+        if let Err(err) = self.try_add(thread_id, address, size) {
+            panic!("{err}");
+        }
+    }
+
+    /// Add a new coverage entry for the thread `thread_id`, returning an error if it cannot be recorded.
+    ///
+    /// A thread's trace is only created once it records a block, so failed entries
+    /// and entries skipped by the template's exclusions or active modules do not
+    /// create empty traces.
+    pub fn try_add(&mut self, thread_id: u64, address: u64, size: usize) -> Result<(), AddError> {
+        match self.traces.get_mut(&thread_id) {
+            Some(trace) => trace.try_add(address, size),
+            None => {
+                let mut trace = self.template.clone_modules_only();
+                trace.try_add(address, size)?;
+                if !trace.entries().is_empty() {
+                    self.traces.insert(thread_id, trace);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the trace of the thread `thread_id`, if it recorded any blocks.
+    pub fn get(&self, thread_id: u64) -> Option<&Trace> {
+        self.traces.get(&thread_id)
+    }
+
+    /// Consume the recorder, returning the trace of each thread by thread id.
+    pub fn into_traces(self) -> HashMap<u64, Trace> {
+        self.traces
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_attributed_to_threads() {
+        let mut recorder = PerThreadTrace::new(&[
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("libc.so", 0x5000, 0x6000),
+        ]);
+        recorder.add(1, 0x1010, 4);
+        recorder.add(2, 0x5020, 8);
+        recorder.add(1, 0x1030, 4);
+        assert!(recorder.try_add(3, 0x3000, 4).is_err());
+        assert_eq!(recorder.get(1).unwrap().entries().len(), 2);
+
+        let traces = recorder.into_traces();
+        assert_eq!(traces.len(), 2);
+        assert_eq!(traces[&2].modules(), traces[&1].modules());
+        let starts = traces[&1]
+            .entries()
+            .iter()
+            .map(|e| e.start())
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [0x10, 0x30]);
+        assert_eq!(traces[&2].entries()[0].mod_id(), 1);
+    }
+
+    #[test]
+    fn skipped_blocks_do_not_create_traces() {
+        let mut template = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        template.add_exclusion(0x1800..0x2000);
+        let mut recorder = PerThreadTrace::from_template(template);
+
+        recorder.try_add(1, 0x1810, 4).unwrap();
+        assert!(recorder.get(1).is_none());
+        recorder.add(1, 0x1010, 4);
+        recorder.add(1, 0x1820, 4);
+        assert_eq!(recorder.get(1).unwrap().entries().len(), 1);
+        assert_eq!(recorder.into_traces().len(), 1);
+    }
+}
@@ -0,0 +1,14 @@
+//! Re-exports of the commonly used items of this crate.
+//!
+//! ```rust
+//! use drcov_rs::prelude::*;
+//!
+//! let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]).with_version(Version::V3);
+//! trace.add(0x1204, 3);
+//! ```
+
+pub use crate::record;
+pub use crate::{
+    AddError, BlockEntry, CoveredBlock, DrcovError, Issue, LineEnding, Module, SharedModules,
+    ThreadTrace, Trace, Version,
+};
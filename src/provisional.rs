@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use crate::{dropped, AddError, BlockEntry, Trace};
+
+/// A basic block whose size is not known yet, created by [`Trace::add_provisional`].
+///
+/// Pass it to [`Trace::finalize_size`] on the same trace once the size is known.
+#[derive(Debug, PartialEq, Eq, Hash)]
+#[must_use = "the block is only recorded once its size is finalized"]
+pub struct EntryHandle {
+    id: u64,
+}
+
+/// The provisional blocks of a [`Trace`] that have not been finalized.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Provisional {
+    /// The id given to the next provisional block.
+    next: u64,
+    /// The pending blocks by handle id, with a size of 0, or None for blocks that
+    /// are skipped like excluded blocks in [`Trace::try_add`].
+    pending: HashMap<u64, Option<BlockEntry>>,
+}
+
+impl Provisional {
+    /// Returns the approximate number of heap bytes held by the pending blocks.
+    pub(crate) fn heap_size(&self) -> usize {
+        crate::stats::map_heap_size(&self.pending)
+    }
+
+    /// Move the pending blocks to their new module ids, skipping blocks whose module is removed.
+    pub(crate) fn relabel(&mut self, new_id: impl Fn(usize) -> Option<usize>) {
+        for pending in self.pending.values_mut() {
+            *pending = pending.and_then(|entry| {
+                let mod_id = new_id(usize::from(entry.mod_id))?;
+                Some(BlockEntry {
+                    mod_id: mod_id as u16,
+                    ..entry
+                })
+            });
+        }
+    }
+}
+
+impl Trace {
+    /// Start recording a basic block whose size is only known later.
+    ///
+    /// The block is recorded when [`Trace::finalize_size`] is called with the returned
+    /// handle, so it appears after the blocks recorded before that call. Provisional
+    /// blocks that are never finalized are not written.
+    ///
+    /// Exclusions and the active modules apply as in [`Trace::try_add`]: a skipped
+    /// block still gets a handle, but finalizing it records nothing. Pending blocks
+    /// follow their module when the modules are renumbered, and are skipped if it
+    /// is removed.
+    pub fn add_provisional(&mut self, address: u64) -> Result<EntryHandle, AddError> {
+        let entry = match self.is_excluded(address) {
+            true => None,
+            false => self.provisional_entry(address)?,
+        };
+
+        let id = self.provisional.next;
+        self.provisional.next += 1;
+        self.provisional.pending.insert(id, entry);
+        Ok(EntryHandle { id })
+    }
+
+    /// Returns the pending entry for a block at `address`, or None if its module is not active.
+    fn provisional_entry(&self, address: u64) -> Result<Option<BlockEntry>, AddError> {
+        let Some(mod_id) = self.active_module(address)? else {
+            return Ok(None);
+        };
+
+        let start = self.modules[mod_id]
+            .relative_offset(address)
+            .ok_or_else(|| dropped(AddError::OffsetTooLarge { address }))?;
+        let mod_id =
+            u16::try_from(mod_id).map_err(|_| dropped(AddError::InvalidModuleId { mod_id }))?;
+        Ok(Some(BlockEntry {
+            start,
+            size: 0,
+            mod_id,
+        }))
+    }
+
+    /// Set the size of a provisional basic block and record it.
+    ///
+    /// The block is recorded as by [`Trace::push_raw`], so the zero size policy and
+    /// the maximum number of entries apply. If the size is too large or the block
+    /// is rejected, it is discarded.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the handle was not created by this trace.
+    pub fn finalize_size(&mut self, handle: EntryHandle, size: usize) -> Result<(), AddError> {
+        let entry = self
+            .provisional
+            .pending
+            .remove(&handle.id)
+            .expect("Entry handle does not belong to this trace");
+        let Some(entry) = entry else {
+            return Ok(());
+        };
+        let size = u16::try_from(size).map_err(|_| dropped(AddError::SizeTooLarge { size }))?;
+
+        self.push_raw(usize::from(entry.mod_id), entry.start, size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Module, ZeroSizePolicy};
+
+    #[test]
+    fn provisional_blocks() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        trace.track_hit_counts();
+
+        let first = trace.add_provisional(0x1010).unwrap();
+        let second = trace.add_provisional(0x1018).unwrap();
+        assert!(trace.entries().is_empty());
+        assert_eq!(
+            trace.add_provisional(0x3000),
+            Err(AddError::NoModule { address: 0x3000 })
+        );
+
+        trace.finalize_size(first, 8).unwrap();
+        trace.add(0x1010, 8);
+        assert_eq!(
+            trace.finalize_size(second, 0x10000),
+            Err(AddError::SizeTooLarge { size: 0x10000 })
+        );
+
+        let entries = trace
+            .entries()
+            .iter()
+            .map(|e| (e.start(), e.size()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [(0x10, 8)]);
+        assert_eq!(trace.hit_counts(), Some([2].as_slice()));
+    }
+
+    #[test]
+    fn provisional_blocks_follow_trace_filters() {
+        let mut trace = Trace::new(&[
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("libc.so", 0x5000, 0x6000),
+        ]);
+        trace.add_exclusion(0x1100..0x1200);
+        trace.set_active_modules(&[1]);
+        trace.set_zero_size_policy(ZeroSizePolicy::Reject);
+
+        let excluded = trace.add_provisional(0x1100).unwrap();
+        let inactive = trace.add_provisional(0x1010).unwrap();
+        let empty = trace.add_provisional(0x5010).unwrap();
+        trace.finalize_size(excluded, 4).unwrap();
+        trace.finalize_size(inactive, 4).unwrap();
+        assert_eq!(
+            trace.finalize_size(empty, 0),
+            Err(AddError::ZeroSize {
+                offset: 0x10,
+                mod_id: 1
+            })
+        );
+        assert!(trace.entries().is_empty());
+    }
+
+    #[test]
+    fn provisional_blocks_follow_renumbering() {
+        let mut trace = Trace::new(&[
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("libc.so", 0x5000, 0x6000),
+        ]);
+        let first = trace.add_provisional(0x5010).unwrap();
+        let second = trace.add_provisional(0x1010).unwrap();
+        trace.remap_module_ids(&[(0, 1), (1, 0)]).unwrap();
+        trace.retain_modules(|module| module.name() == "libc.so");
+
+        trace.finalize_size(first, 4).unwrap();
+        trace.finalize_size(second, 4).unwrap();
+        let blocks = trace
+            .blocks()
+            .map(|block| block.address())
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, [0x5010]);
+    }
+}
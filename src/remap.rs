@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+use crate::{dropped, AddError, BlockEntry, MappingError, Module, ModuleError, Trace};
+
+impl Trace {
+    /// Shift the base and end of every module by a signed delta.
+    ///
+    /// Entries are relative to their module base and stay unchanged, so every block
+    /// moves with its module. Known entry points are shifted as well. If any module
+    /// would leave the address space, an error is returned and the trace is left
+    /// unchanged.
+    pub fn rebase_all(&mut self, delta: i64) -> Result<(), ModuleError> {
+        let shift = |address: u64| address.checked_add_signed(delta);
+
+        let mut shifted = Vec::with_capacity(self.modules.len());
+        for module in &self.modules {
+            let overflow = ModuleError::RebaseOverflow {
+                base: module.base,
+                end: module.end,
+                delta,
+            };
+            let entry = match module.entry {
+                0 => Some(0),
+                entry => shift(entry),
+            };
+            match (shift(module.base), shift(module.end), entry) {
+                (Some(base), Some(end), Some(entry)) => shifted.push((base, end, entry)),
+                _ => return Err(overflow),
+            }
+        }
+
+        for (module, (base, end, entry)) in self.modules.iter_mut().zip(shifted) {
+            module.base = base;
+            module.end = end;
+            module.entry = entry;
+        }
+        Ok(())
+    }
+
+    /// Apply a transform to the absolute address of every recorded entry.
+    ///
+    /// Each new address is resolved to its module again, so entries may move
+    /// between modules. Entries whose new address is not within any module, or
+    /// whose current address is past the end of the address space, are dropped, and
+    /// the number of dropped entries is returned. Entries that become identical are
+    /// merged when hit counting is enabled.
+    pub fn map_addresses(&mut self, f: impl Fn(u64) -> u64) -> usize {
+        let entries = std::mem::take(&mut self.entries);
+        let counts = self.hits.take().map(|hits| hits.counts);
+        if counts.is_some() {
+            self.hits = Some(Default::default());
+        }
+
+        self.tags.clear();
+        let mut lost = 0;
+        for (index, entry) in entries.into_iter().enumerate() {
+            let base = self.modules[usize::from(entry.mod_id)].base;
+            let Some(address) = base.checked_add(u64::from(entry.start)).map(&f) else {
+                lost += 1;
+                continue;
+            };
+            let resolved = self
+                .modules
+                .iter()
+                .enumerate()
+                .find_map(|(mod_id, module)| {
+                    Some(BlockEntry {
+                        start: module.relative_offset(address)?,
+                        size: entry.size,
+                        mod_id: u16::try_from(mod_id).ok()?,
+                    })
+                });
+
+            match resolved {
+                Some(entry) => {
+                    let count = counts.as_ref().map_or(1, |counts| counts[index]);
+                    self.record(entry, count);
+                }
+                None => {
+                    dropped(AddError::NoModule { address });
+                    lost += 1;
+                }
+            }
+        }
+        lost
+    }
+
+    /// Renumber the modules according to an explicit mapping of old to new module ids.
+    ///
+    /// The mapping must contain exactly one `(old, new)` pair for every module of the
+    /// trace, and every old and new id must appear once. All entries are relabelled
+    /// to the new ids. A segment only stays linked to the preceding module if that
+    /// module is still its predecessor after renumbering.
+    pub fn remap_module_ids(&mut self, mapping: &[(usize, usize)]) -> Result<(), MappingError> {
+        let count = self.modules.len();
+        if mapping.len() != count {
+            return Err(MappingError::WrongLength {
+                expected: count,
+                actual: mapping.len(),
+            });
+        }
+
+        let mut new_ids = vec![None; count];
+        let mut taken = vec![false; count];
+        for &(old, new) in mapping {
+            if let Some(&mod_id) = [old, new].iter().find(|&&id| id >= count) {
+                return Err(MappingError::InvalidModuleId { mod_id });
+            }
+            if new_ids[old].is_some() {
+                return Err(MappingError::DuplicateModuleId { mod_id: old });
+            }
+            if std::mem::replace(&mut taken[new], true) {
+                return Err(MappingError::DuplicateModuleId { mod_id: new });
+            }
+            new_ids[old] = Some(new);
+        }
+        let new_ids = new_ids.into_iter().flatten().collect::<Vec<_>>();
+
+        let mut old_ids = vec![0; count];
+        for (old, &new) in new_ids.iter().enumerate() {
+            old_ids[new] = old;
+        }
+        self.active = None;
+        let old_modules = std::mem::take(&mut self.modules);
+        self.modules = old_ids
+            .iter()
+            .enumerate()
+            .map(|(new, &old)| {
+                let mut module = old_modules[old].clone();
+                module.continuation &= new > 0 && old > 0 && old_ids[new - 1] == old - 1;
+                module
+            })
+            .collect();
+
+        for entry in &mut self.entries {
+            entry.mod_id = new_ids[usize::from(entry.mod_id)] as u16;
+        }
+        self.provisional.relabel(|mod_id| Some(new_ids[mod_id]));
+        self.tags = std::mem::take(&mut self.tags)
+            .into_iter()
+            .map(|(entry, tag)| {
+                let mod_id = new_ids[usize::from(entry.mod_id)] as u16;
+                (BlockEntry { mod_id, ..entry }, tag)
+            })
+            .collect();
+        if let Some(hits) = &mut self.hits {
+            // Relabelling is a bijection, so entries stay distinct and only their keys change.
+            hits.index = self
+                .entries
+                .iter()
+                .enumerate()
+                .map(|(index, &entry)| (entry, index))
+                .collect();
+            hits.evicted = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Treat the modules in `other_ids` as aliases of the module `canonical_id`.
+    ///
+    /// Entries recorded in the aliased modules are moved onto the canonical module,
+    /// keeping their offsets, and the aliased modules are removed from the module
+    /// table. The remaining modules are renumbered in their original order.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if any of the given ids is not a module of the trace.
+    pub fn alias_modules(&mut self, canonical_id: usize, other_ids: &[usize]) {
+        let count = self.modules.len();
+        assert!(
+            canonical_id < count && other_ids.iter().all(|&id| id < count),
+            "Module id out of range"
+        );
+
+        let mut target = (0..count).collect::<Vec<_>>();
+        for &id in other_ids {
+            target[id] = canonical_id;
+        }
+        self.relabel_modules(&target);
+    }
+
+    /// Alias every module onto the first module with the same build id, or name if it is
+    /// unknown, and image offset.
+    ///
+    /// This collapses images that were mapped more than once into a single module,
+    /// as by [`Trace::alias_modules`].
+    pub fn coalesce_by_name(&mut self) {
+        let target = self
+            .modules
+            .iter()
+            .map(|module| {
+                self.modules
+                    .iter()
+                    .position(|m| m.identity() == module.identity())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        self.relabel_modules(&target);
+    }
+
+    /// Keep only the modules for which `keep` returns true, removing the others and their entries.
+    ///
+    /// This is the in-place counterpart of `Trace::filter_modules_matching`. The
+    /// kept modules are renumbered in their original order and their entries
+    /// relabelled, keeping hit counts and block tags. A segment only stays linked if
+    /// its preceding segment is kept as well.
+    pub fn retain_modules(&mut self, mut keep: impl FnMut(&Module) -> bool) {
+        let mut new_ids = vec![None; self.modules.len()];
+        let mut next = 0;
+        self.active = None;
+        self.modules = std::mem::take(&mut self.modules)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(id, mut module)| {
+                if !keep(&module) {
+                    return None;
+                }
+                module.continuation &= id > 0 && new_ids[id - 1].is_some();
+                new_ids[id] = Some(next);
+                next += 1;
+                Some(module)
+            })
+            .collect();
+
+        let relabel = |entry: &BlockEntry| {
+            new_ids[usize::from(entry.mod_id)].map(|mod_id| BlockEntry { mod_id, ..*entry })
+        };
+        match self.hits.take() {
+            None => self.entries.retain_mut(|entry| match relabel(entry) {
+                Some(relabelled) => {
+                    *entry = relabelled;
+                    true
+                }
+                None => false,
+            }),
+            Some(hits) => {
+                let entries = std::mem::take(&mut self.entries);
+                self.hits = Some(Default::default());
+                for (entry, count) in entries.iter().zip(hits.counts) {
+                    if let Some(entry) = relabel(entry) {
+                        self.record(entry, count);
+                    }
+                }
+            }
+        }
+        self.tags = std::mem::take(&mut self.tags)
+            .into_iter()
+            .filter_map(|(entry, tag)| Some((relabel(&entry)?, tag)))
+            .collect();
+        self.provisional
+            .relabel(|mod_id| new_ids[mod_id].map(usize::from));
+    }
+
+    /// Returns a copy of this trace with only the modules for which `keep` returns true, given their id, and their entries.
+    ///
+    /// The kept modules are renumbered in their original order, and hit counts are
+    /// kept. A segment only stays linked if its preceding segment is kept as well.
+    pub(crate) fn filtered_modules(&self, keep: impl Fn(usize, &Module) -> bool) -> Trace {
+        let mut new_ids = vec![None; self.modules.len()];
+        let mut modules = Vec::new();
+        for (id, module) in self.modules.iter().enumerate() {
+            if !keep(id, module) {
+                continue;
+            }
+            let mut module = module.clone();
+            module.continuation &= id > 0 && new_ids[id - 1].is_some();
+            new_ids[id] = Some(modules.len() as u16);
+            modules.push(module);
+        }
+
+        let mut trace = self.with_entries(Vec::new());
+        trace.modules = modules;
+        trace.active = None;
+        if self.hits.is_some() {
+            trace.hits = Some(Default::default());
+        }
+        for (index, entry) in self.entries.iter().enumerate() {
+            if let Some(mod_id) = new_ids[usize::from(entry.mod_id)] {
+                let count = self.hits.as_ref().map_or(1, |hits| hits.counts[index]);
+                trace.record(BlockEntry { mod_id, ..*entry }, count);
+            }
+        }
+        trace
+    }
+
+    /// Split the trace into one single-module trace per module, keyed by the original module id.
+    ///
+    /// Every module gets a trace, including modules without entries. In each trace
+    /// the module has the id 0 and is not linked to other segments, and the entries
+    /// keep their recording order and hit counts.
+    pub fn split_by_module(&self) -> HashMap<usize, Trace> {
+        let empty = self.filtered_modules(|_, _| false);
+        let mut traces = self
+            .modules
+            .iter()
+            .map(|module| {
+                let mut trace = empty.clone();
+                trace.modules.push(Module {
+                    continuation: false,
+                    ..module.clone()
+                });
+                trace
+            })
+            .collect::<Vec<_>>();
+
+        for (index, entry) in self.entries.iter().enumerate() {
+            let count = self.hits.as_ref().map_or(1, |hits| hits.counts[index]);
+            traces[usize::from(entry.mod_id)].record(
+                BlockEntry {
+                    mod_id: 0,
+                    ..*entry
+                },
+                count,
+            );
+        }
+        traces.into_iter().enumerate().collect()
+    }
+
+    /// Save the trace as several drcov files in `dir`, each with at most `max_modules_per_file` modules.
+    ///
+    /// The modules are split into consecutive groups, and each group is saved with
+    /// its blocks and renumbered module ids to `chunk-<n>.log`, numbered from 0. The
+    /// segments of an image are kept in one file unless they exceed the limit
+    /// together. A trace without modules writes no files. Returns the paths of the
+    /// written files in order.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `max_modules_per_file` is 0.
+    pub fn write_chunked(
+        &self,
+        dir: impl AsRef<Path>,
+        max_modules_per_file: usize,
+    ) -> Result<Vec<PathBuf>, Error> {
+        assert!(
+            max_modules_per_file > 0,
+            "Chunks must hold at least one module"
+        );
+
+        // Each chunk starts at a module id, preferably at the head of an image.
+        let mut starts = Vec::new();
+        let mut chunk_start = 0;
+        for (id, module) in self.modules.iter().enumerate() {
+            let image_len = 1 + self.modules[id + 1..]
+                .iter()
+                .take_while(|m| m.continuation)
+                .count();
+            let full = id - chunk_start == max_modules_per_file;
+            let image_fits = id - chunk_start + image_len <= max_modules_per_file;
+            if id == 0 || full || (!module.continuation && !image_fits) {
+                starts.push(id);
+                chunk_start = id;
+            }
+        }
+
+        starts
+            .iter()
+            .enumerate()
+            .map(|(index, &start)| {
+                let end = starts.get(index + 1).copied().unwrap_or(self.modules.len());
+                let path = dir.as_ref().join(format!("chunk-{index}.log"));
+                self.filtered_modules(|id, _| (start..end).contains(&id))
+                    .save(&path)?;
+                Ok(path)
+            })
+            .collect()
+    }
+
+    /// Move the entries of each module `id` onto the module `target[id]`.
+    ///
+    /// Modules that are not their own target are removed and the rest renumbered.
+    /// Each target must be its own target.
+    fn relabel_modules(&mut self, target: &[usize]) {
+        let mut new_ids = vec![0; target.len()];
+        let mut next = 0;
+        for (id, &to) in target.iter().enumerate() {
+            if to == id {
+                new_ids[id] = next;
+                next += 1;
+            }
+        }
+
+        self.active = None;
+        self.modules = std::mem::take(&mut self.modules)
+            .into_iter()
+            .enumerate()
+            .filter(|(id, _)| target[*id] == *id)
+            .map(|(_, module)| module)
+            .collect();
+
+        let relabel = |entry: BlockEntry| BlockEntry {
+            mod_id: new_ids[target[usize::from(entry.mod_id)]] as u16,
+            ..entry
+        };
+        // Entries that become identical keep the lowest of their tags.
+        let mut tags = HashMap::new();
+        for (entry, tag) in std::mem::take(&mut self.tags) {
+            tags.entry(relabel(entry))
+                .and_modify(|t: &mut u32| *t = (*t).min(tag))
+                .or_insert(tag);
+        }
+        self.tags = tags;
+        self.provisional
+            .relabel(|mod_id| Some(new_ids[target[mod_id]]));
+        match self.hits.take() {
+            None => {
+                for entry in &mut self.entries {
+                    *entry = relabel(*entry);
+                }
+            }
+            Some(hits) => {
+                // Relabelling can make entries identical, so record them again to merge their counts.
+                let entries = std::mem::take(&mut self.entries);
+                self.hits = Some(Default::default());
+                for (entry, count) in entries.into_iter().zip(hits.counts) {
+                    self.record(relabel(entry), count);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    #[test]
+    fn alias_modules_renumbers() {
+        let mut trace = Trace::new(&[
+            Module::new("kernel32.dll", 0x1000, 0x2000),
+            Module::new("target.exe", 0x4000, 0x5000),
+            Module::new("kernel32.dll", 0x8000, 0x9000),
+        ]);
+        trace.add(0x8010, 4);
+        trace.add(0x4020, 4);
+
+        trace.alias_modules(0, &[2]);
+
+        assert_eq!(trace.modules().len(), 2);
+        assert_eq!(trace.modules()[1].name(), "target.exe");
+        let entries = trace
+            .entries()
+            .iter()
+            .map(|e| (e.mod_id(), e.start()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [(0, 0x10), (1, 0x20)]);
+    }
+
+    #[test]
+    fn coalesce_by_name_merges_hit_counts() {
+        let mut trace = Trace::new(&[
+            Module::new("kernel32.dll", 0x1000, 0x2000),
+            Module::new("kernel32.dll", 0x8000, 0x9000),
+        ]);
+        trace.track_hit_counts();
+        trace.add(0x1010, 4);
+        trace.add(0x8010, 4);
+        trace.add(0x8010, 4);
+
+        trace.coalesce_by_name();
+
+        assert_eq!(trace.modules().len(), 1);
+        assert_eq!(trace.entries().len(), 1);
+        assert_eq!(trace.hit_counts(), Some([3].as_slice()));
+    }
+
+    #[test]
+    fn remap_module_ids_validates_mapping() {
+        let mut modules = vec![Module::new("target.exe", 0x4000, 0x5000)];
+        modules.extend(Module::with_segments(
+            "libc.so",
+            0x8000,
+            &[0..0x100, 0x200..0x300],
+        ));
+        let mut trace = Trace::new(&modules);
+        trace.track_hit_counts();
+        trace.add(0x4010, 4);
+        trace.add(0x8210, 4);
+
+        assert_eq!(
+            trace.remap_module_ids(&[(0, 1), (1, 1), (2, 0)]),
+            Err(MappingError::DuplicateModuleId { mod_id: 1 })
+        );
+        assert_eq!(
+            trace.remap_module_ids(&[(0, 3), (1, 1), (2, 0)]),
+            Err(MappingError::InvalidModuleId { mod_id: 3 })
+        );
+        assert_eq!(
+            trace.remap_module_ids(&[(0, 0)]),
+            Err(MappingError::WrongLength {
+                expected: 3,
+                actual: 1
+            })
+        );
+
+        trace.remap_module_ids(&[(0, 2), (1, 0), (2, 1)]).unwrap();
+        let names = trace.modules().iter().map(|m| m.name()).collect::<Vec<_>>();
+        assert_eq!(names, ["libc.so", "libc.so", "target.exe"]);
+        assert!(trace.modules()[1].continuation);
+        let entries = trace
+            .entries()
+            .iter()
+            .map(|e| (e.mod_id(), e.start()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [(2, 0x10), (1, 0x10)]);
+
+        trace.add(0x4010, 4);
+        assert_eq!(trace.hit_counts(), Some([2, 1].as_slice()));
+
+        trace.remap_module_ids(&[(0, 1), (1, 0), (2, 2)]).unwrap();
+        assert!(!trace.modules()[0].continuation);
+        assert!(!trace.modules()[1].continuation);
+    }
+
+    #[test]
+    fn rebase_all_shifts_modules() {
+        let mut trace = Trace::new(&[
+            Module::new("target.exe", 0x4000, 0x5000).with_entry(0x4100),
+            Module::new("libc.so", 0x8000, 0x9000),
+        ]);
+        trace.add(0x4010, 4);
+
+        trace.rebase_all(0x7ff0_0000_0000).unwrap();
+        assert_eq!(trace.modules()[0].base(), 0x7ff0_0000_4000);
+        assert_eq!(trace.modules()[0].entry(), 0x7ff0_0000_4100);
+        assert_eq!(trace.modules()[1].entry(), 0);
+        assert_eq!(trace.blocks().next().unwrap().address(), 0x7ff0_0000_4010);
+
+        let before = trace.modules().to_vec();
+        assert_eq!(
+            trace.rebase_all(-0x7ff0_0000_5000),
+            Err(ModuleError::RebaseOverflow {
+                base: 0x7ff0_0000_4000,
+                end: 0x7ff0_0000_5000,
+                delta: -0x7ff0_0000_5000
+            })
+        );
+        assert_eq!(trace.modules(), before);
+
+        trace.rebase_all(-0x7ff0_0000_0000).unwrap();
+        assert_eq!(trace.modules()[1].base(), 0x8000);
+    }
+
+    #[test]
+    fn retain_modules_in_place() {
+        let mut trace = Trace::new(&[
+            Module::new("target.exe", 0x4000, 0x5000),
+            Module::new("libc.so", 0x8000, 0x9000),
+            Module::new("libm.so", 0xa000, 0xb000),
+        ]);
+        trace.track_hit_counts();
+        trace.add(0x4010, 4);
+        trace.add(0x8020, 4);
+        trace.add(0xa030, 4);
+        trace.add(0xa030, 4);
+
+        let mut calls = 0;
+        trace.retain_modules(|module| {
+            calls += 1;
+            module.name() != "libc.so"
+        });
+        assert_eq!(calls, 3);
+        let names = trace.modules().iter().map(|m| m.name()).collect::<Vec<_>>();
+        assert_eq!(names, ["target.exe", "libm.so"]);
+        let entries = trace
+            .entries()
+            .iter()
+            .map(|e| (e.mod_id(), e.start()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [(0, 0x10), (1, 0x30)]);
+        assert_eq!(trace.hit_counts(), Some([1, 2].as_slice()));
+    }
+
+    #[test]
+    fn write_chunked_limits_modules() {
+        let mut modules = Module::with_segments("image", 0x10000, &[0..0x1000, 0x2000..0x3000]);
+        modules.insert(0, Module::new("target.exe", 0x4000, 0x5000));
+        modules.push(Module::new("libc.so", 0x80000, 0x90000));
+        let mut trace = Trace::new(&modules);
+        trace.add(0x4010, 4);
+        trace.add(0x12010, 4);
+        trace.add(0x80020, 4);
+
+        let dir = std::env::temp_dir().join(format!("drcov-chunked-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = trace.write_chunked(&dir, 2).unwrap();
+        let chunks = paths
+            .iter()
+            .map(|path| {
+                let trace = Trace::load(path).unwrap();
+                let names = trace.modules().iter().map(|m| m.name().to_owned());
+                (names.collect::<Vec<_>>(), trace.entries().len())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chunks,
+            [
+                (vec!["target.exe".to_owned()], 1),
+                (vec!["image".to_owned(), "image".to_owned()], 1),
+                (vec!["libc.so".to_owned()], 1),
+            ]
+        );
+        assert!(paths[1].ends_with("chunk-1.log"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn split_by_module() {
+        let mut trace = Trace::new(&[
+            Module::new("target.exe", 0x4000, 0x5000),
+            Module::new("libc.so", 0x8000, 0x9000),
+            Module::new("libm.so", 0xa000, 0xb000),
+        ]);
+        trace.track_hit_counts();
+        trace.add(0x8020, 8);
+        trace.add(0x4010, 4);
+        trace.add(0x8010, 4);
+        trace.add(0x8020, 8);
+
+        let traces = trace.split_by_module();
+        assert_eq!(traces.len(), 3);
+        assert_eq!(traces[&1].modules(), &trace.modules()[1..2]);
+        let blocks = traces[&1]
+            .entries()
+            .iter()
+            .map(|e| (e.mod_id(), e.start()))
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, [(0, 0x20), (0, 0x10)]);
+        assert_eq!(traces[&1].hit_counts(), Some(&[2, 1][..]));
+        assert_eq!(traces[&0].entries().len(), 1);
+        assert!(traces[&2].entries().is_empty());
+    }
+
+    #[test]
+    fn map_addresses_relocates_entries() {
+        let mut trace = Trace::new(&[
+            Module::new("target.exe", 0x4000, 0x5000),
+            Module::new("libc.so", 0x8000, 0x9000),
+        ]);
+        trace.add(0x4010, 4);
+        trace.add(0x4ff0, 4);
+        trace.add(0x8020, 8);
+
+        assert_eq!(trace.map_addresses(|address| address + 0x4000), 1);
+
+        let entries = trace
+            .entries()
+            .iter()
+            .map(|e| (e.mod_id(), e.start()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [(1, 0x10), (1, 0xff0)]);
+
+        let mut trace = Trace::new(&[Module::new(
+            "top",
+            0xffff_ffff_ffff_f000,
+            0xffff_ffff_ffff_ffff,
+        )]);
+        trace.push_raw(0, 0x10, 4).unwrap();
+        trace.push_raw(0, 0x10_0000, 4).unwrap();
+        assert_eq!(trace.map_addresses(|address| address), 1);
+        assert_eq!(trace.entries().len(), 1);
+    }
+}
@@ -0,0 +1,195 @@
+use std::sync::Arc;
+
+use crate::index::ModuleLookup;
+use crate::{dropped, AddError, BlockEntry, Module, Trace};
+
+/// An immutable module table shared by several [`ThreadTrace`] recorders.
+///
+/// Cloning is cheap, as the table and its lookup index are reference counted.
+#[derive(Clone, Debug)]
+pub struct SharedModules {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    modules: Vec<Module>,
+    /// The module containing each address, for binary search lookups.
+    lookup: ModuleLookup,
+}
+
+/// A lightweight per-thread recorder over a [`SharedModules`] table.
+///
+/// Created with [`SharedModules::recorder`] and combined into a [`Trace`] with
+/// [`SharedModules::collect`].
+#[derive(Clone, Debug)]
+pub struct ThreadTrace {
+    modules: SharedModules,
+    entries: Vec<BlockEntry>,
+}
+
+impl SharedModules {
+    /// Create a shared module table from the given modules.
+    pub fn new(modules: &[Module]) -> SharedModules {
+        SharedModules {
+            inner: Arc::new(Inner {
+                modules: modules.to_vec(),
+                lookup: ModuleLookup::new(modules),
+            }),
+        }
+    }
+
+    /// Returns the modules in the table, indexed by module id.
+    pub fn modules(&self) -> &[Module] {
+        &self.inner.modules
+    }
+
+    /// Create an empty recorder that uses this module table.
+    pub fn recorder(&self) -> ThreadTrace {
+        ThreadTrace {
+            modules: self.clone(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Combine the entries of the given recorders into a single trace.
+    ///
+    /// The entries of each recorder are appended in the order the recorders are given.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if a recorder was created from a different table.
+    pub fn collect(&self, recorders: impl IntoIterator<Item = ThreadTrace>) -> Trace {
+        let mut trace = Trace::new(self.modules());
+        for recorder in recorders {
+            assert!(
+                Arc::ptr_eq(&self.inner, &recorder.modules.inner),
+                "Recorder uses a different module table"
+            );
+            for entry in recorder.entries {
+                trace.record(entry, 1);
+            }
+        }
+        trace
+    }
+
+    /// Returns the id of the module containing the address.
+    ///
+    /// If modules overlap, the one with the lowest id is chosen, as by [`Trace::try_add`].
+    fn resolve(&self, address: u64) -> Option<usize> {
+        self.inner.lookup.find(address)
+    }
+}
+
+impl ThreadTrace {
+    /// Add a new coverage entry, as by [`Trace::add`].
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the entry cannot be recorded.
+    pub fn add(&mut self, address: u64, size: usize) {
+        if let Err(err) = self.try_add(address, size) {
+            panic!("{err}");
+        }
+    }
+
+    /// Add a new coverage entry, returning an error if it cannot be recorded.
+    ///
+    /// The module is found with a binary search over the shared table. This
+    /// function never panics, regardless of its input.
+    pub fn try_add(&mut self, address: u64, size: usize) -> Result<(), AddError> {
+        let mod_id = self
+            .modules
+            .resolve(address)
+            .ok_or_else(|| dropped(AddError::NoModule { address }))?;
+        let start = self.modules.modules()[mod_id]
+            .relative_offset(address)
+            .ok_or_else(|| dropped(AddError::OffsetTooLarge { address }))?;
+        let size = u16::try_from(size).map_err(|_| dropped(AddError::SizeTooLarge { size }))?;
+        let mod_id =
+            u16::try_from(mod_id).map_err(|_| dropped(AddError::InvalidModuleId { mod_id }))?;
+
+        self.entries.push(BlockEntry {
+            start,
+            size,
+            mod_id,
+        });
+        Ok(())
+    }
+
+    /// Returns the entries recorded by this recorder, in recording order.
+    pub fn entries(&self) -> &[BlockEntry] {
+        &self.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threads_share_modules() {
+        let modules = SharedModules::new(&[
+            Module::new("libc.so", 0x5000, 0x6000),
+            Module::new("abcd", 0x1000, 0x2000),
+        ]);
+
+        let recorders = std::thread::scope(|scope| {
+            let threads = (0..4u64)
+                .map(|thread| {
+                    let mut recorder = modules.recorder();
+                    scope.spawn(move || {
+                        recorder.add(0x1000 + thread * 0x10, 4);
+                        recorder.add(0x5000 + thread * 0x10, 4);
+                        recorder
+                    })
+                })
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(
+            recorders[0].clone().try_add(0x3000, 4),
+            Err(AddError::NoModule { address: 0x3000 })
+        );
+
+        let trace = modules.collect(recorders);
+        let blocks = trace
+            .entries()
+            .iter()
+            .map(|e| (e.mod_id(), e.start()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            blocks,
+            [
+                (1, 0),
+                (0, 0),
+                (1, 0x10),
+                (0, 0x10),
+                (1, 0x20),
+                (0, 0x20),
+                (1, 0x30),
+                (0, 0x30)
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_modules_resolve_as_trace() {
+        let table = [
+            Module::new("abcd", 0x1000, 0x4000),
+            Module::new("libc.so", 0x2000, 0x3000),
+        ];
+        let modules = SharedModules::new(&table);
+        let mut recorder = modules.recorder();
+        let mut trace = Trace::new(&table);
+        for address in [0x1010, 0x2010, 0x3010] {
+            recorder.add(address, 4);
+            trace.add(address, 4);
+        }
+
+        assert_eq!(modules.collect([recorder]).entries(), trace.entries());
+    }
+}
@@ -0,0 +1,102 @@
+use std::path::Path;
+
+use rusqlite::{params, Connection};
+
+use crate::Trace;
+
+impl Trace {
+    /// Export the coverage information to an SQLite database at the given path.
+    ///
+    /// The database is created if needed and receives two tables:
+    /// `modules(id, name, base, end)` and `blocks(module_id, offset, size, count)`.
+    /// Each distinct block is stored once in the order it was first recorded, with
+    /// its hit count if hit counting is enabled and otherwise the number of times it
+    /// was recorded. Fails if either table already exists.
+    pub fn to_sqlite(&self, path: impl AsRef<Path>) -> Result<(), rusqlite::Error> {
+        let mut connection = Connection::open(path)?;
+        let transaction = connection.transaction()?;
+
+        transaction.execute_batch(
+            "CREATE TABLE modules (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                base INTEGER NOT NULL,
+                \"end\" INTEGER NOT NULL
+            );
+            CREATE TABLE blocks (
+                module_id INTEGER NOT NULL REFERENCES modules(id),
+                offset INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                count INTEGER NOT NULL
+            );",
+        )?;
+
+        {
+            // SQLite integers are signed, so addresses are stored by their bit pattern.
+            let mut insert = transaction
+                .prepare("INSERT INTO modules (id, name, base, \"end\") VALUES (?1, ?2, ?3, ?4)")?;
+            for (id, module) in self.modules.iter().enumerate() {
+                insert.execute(params![
+                    id,
+                    module.name(),
+                    module.base as i64,
+                    module.end as i64
+                ])?;
+            }
+
+            let mut insert = transaction.prepare(
+                "INSERT INTO blocks (module_id, offset, size, count) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (entry, count) in self.distinct_counts() {
+                insert.execute(params![entry.mod_id, entry.start, entry.size, count as i64])?;
+            }
+        }
+
+        transaction.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    #[test]
+    fn sqlite_export() {
+        let path = std::env::temp_dir().join(format!("drcov-sqlite-{}.db", std::process::id()));
+        let mut trace = Trace::new(&[
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("libc.so", 0x7f0000000000, 0x7f0000200000),
+        ]);
+        trace.add(0x1010, 4);
+        trace.add(0x7f0000000020, 8);
+        trace.add(0x1010, 4);
+        trace.to_sqlite(&path).unwrap();
+
+        let connection = Connection::open(&path).unwrap();
+        let base: i64 = connection
+            .query_row(
+                "SELECT base FROM modules WHERE name = 'libc.so'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(base as u64, 0x7f0000000000);
+
+        let mut query = connection
+            .prepare("SELECT module_id, offset, size, count FROM blocks ORDER BY rowid")
+            .unwrap();
+        let blocks = query
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<Result<Vec<(u16, u32, u16, u32)>, _>>()
+            .unwrap();
+        assert_eq!(blocks, [(0, 0x10, 4, 2), (1, 0x20, 8, 1)]);
+
+        drop(query);
+        drop(connection);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
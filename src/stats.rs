@@ -0,0 +1,421 @@
+use std::borrow::Cow;
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
+use std::mem::size_of;
+
+use crate::index::merge_ranges;
+use crate::{BlockEntry, Module, Trace};
+
+impl Trace {
+    /// Returns the number of distinct bytes of the module `mod_id` covered by recorded blocks.
+    ///
+    /// Overlapping and repeated blocks are only counted once, and parts of blocks beyond
+    /// the end of the module are ignored. Returns 0 for an unknown module id.
+    pub fn covered_bytes(&self, mod_id: usize) -> u64 {
+        let Some(module) = self.modules.get(mod_id) else {
+            return 0;
+        };
+        let size = module.size();
+        let ranges = self
+            .entries
+            .iter()
+            .filter(|entry| usize::from(entry.mod_id) == mod_id)
+            .map(|entry| {
+                let start = u64::from(entry.start).min(size);
+                start..(start + u64::from(entry.size)).min(size)
+            })
+            .collect();
+
+        merge_ranges(ranges)
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum()
+    }
+
+    /// Returns a bitmap of the bytes of the module `mod_id` covered by recorded blocks.
+    ///
+    /// Bit `i % 8` of byte `i / 8` is set if the byte at `base + i` is covered, so the
+    /// bitmap has `module.size().div_ceil(8)` bytes. Parts of blocks beyond the end
+    /// of the module are ignored. Returns an empty bitmap for an unknown module id.
+    ///
+    /// The bitmap is allocated up front, so it takes one byte of memory per eight
+    /// bytes of the module's address range regardless of how much is covered. The
+    /// module table of a parsed file can declare ranges of any size, so check
+    /// [`Module::size`] before calling this on a trace from an untrusted source.
+    pub fn byte_coverage(&self, mod_id: usize) -> Vec<u8> {
+        let Some(module) = self.modules.get(mod_id) else {
+            return Vec::new();
+        };
+        let size = module.size();
+        let mut bitmap = vec![0u8; size.div_ceil(8) as usize];
+
+        let ranges = self
+            .entries
+            .iter()
+            .filter(|entry| usize::from(entry.mod_id) == mod_id)
+            .map(|entry| {
+                let start = u64::from(entry.start);
+                start..(start + u64::from(entry.size)).min(size)
+            })
+            .collect();
+        for range in merge_ranges(ranges) {
+            for byte in range {
+                bitmap[(byte / 8) as usize] |= 1 << (byte % 8);
+            }
+        }
+        bitmap
+    }
+
+    /// Returns the fraction of each module's address range covered by recorded blocks, by module id.
+    ///
+    /// The covered bytes are counted as by [`Trace::covered_bytes`]. Modules with an
+    /// empty address range have no meaningful ratio and are skipped.
+    pub fn module_coverage_ratio(&self) -> Vec<(usize, f64)> {
+        self.modules
+            .iter()
+            .enumerate()
+            .filter(|(_, module)| module.size() > 0)
+            .map(|(id, module)| (id, self.covered_bytes(id) as f64 / module.size() as f64))
+            .collect()
+    }
+
+    /// Returns the modules without any recorded entries, in module id order.
+    ///
+    /// These are the modules that were loaded but never executed.
+    pub fn uncovered_modules(&self) -> Vec<&Module> {
+        let mut covered = vec![false; self.modules.len()];
+        for entry in &self.entries {
+            covered[usize::from(entry.mod_id)] = true;
+        }
+
+        self.modules
+            .iter()
+            .zip(covered)
+            .filter(|(_, covered)| !covered)
+            .map(|(module, _)| module)
+            .collect()
+    }
+
+    /// Returns the distinct pairs of consecutively recorded blocks, as an approximation of edge coverage.
+    ///
+    /// Each pair is a block and the block recorded directly after it. This relies on
+    /// the recording order, so the result is only meaningful for traces that record
+    /// every block execution. [`Trace::dedup`], [`Trace::canonicalize`] and hit
+    /// counting store each block once and lose the order, as do traces read from
+    /// files written that way.
+    pub fn approx_edge_set(&self) -> HashSet<(BlockEntry, BlockEntry)> {
+        self.entries
+            .windows(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect()
+    }
+
+    /// Returns the number of distinct pairs of consecutively recorded blocks.
+    ///
+    /// See [`Trace::approx_edge_set`] for how the edges are approximated.
+    pub fn approx_edges(&self) -> usize {
+        self.approx_edge_set().len()
+    }
+
+    /// Returns an estimate of the memory used by this trace in bytes.
+    ///
+    /// This counts the trace itself and the allocated capacity of its module table,
+    /// including owned names and build ids, its entries, its hit count index,
+    /// metadata and pending provisional blocks. Allocator overhead is not included,
+    /// so the real usage is somewhat higher.
+    pub fn approx_memory_usage(&self) -> usize {
+        let modules = self.modules.capacity() * size_of::<Module>()
+            + self
+                .modules
+                .iter()
+                .map(|module| {
+                    let name = match &module.name {
+                        Cow::Borrowed(_) => 0,
+                        Cow::Owned(name) => name.capacity(),
+                    };
+                    name + module.build_id.as_ref().map_or(0, Vec::capacity)
+                })
+                .sum::<usize>();
+        let hits = self.hits.as_ref().map_or(0, |hits| {
+            hits.counts.capacity() * size_of::<u32>() + map_heap_size(&hits.index)
+        });
+        let metadata = self.metadata.capacity() * size_of::<(String, String)>()
+            + self
+                .metadata
+                .iter()
+                .map(|(key, value)| key.capacity() + value.capacity())
+                .sum::<usize>();
+
+        size_of::<Trace>()
+            + modules
+            + self.entries.capacity() * size_of::<BlockEntry>()
+            + hits
+            + self.flavor.capacity()
+            + metadata
+            + self.provisional.heap_size()
+    }
+
+    /// Returns the distinct blocks with their hit counts, sorted by descending count.
+    ///
+    /// Each block is given as its absolute address, size and count. The count is the
+    /// hit count if hit counting is enabled and otherwise the number of times the
+    /// block was recorded, saturating at `u32::MAX`. Addresses past the end of the
+    /// address space wrap around, as by [`CoveredBlock::address`](crate::CoveredBlock::address).
+    /// Blocks with equal counts are sorted by address.
+    pub fn to_weighted_blocks(&self) -> Vec<(u64, u16, u32)> {
+        let mut blocks = self.weighted_blocks();
+        blocks.sort_by_key(weight_order);
+        blocks
+    }
+
+    /// Returns the `n` most executed distinct blocks, in the order of [`Trace::to_weighted_blocks`].
+    ///
+    /// Only the selected blocks are sorted, so this takes O(m + n log n) time for
+    /// m distinct blocks.
+    pub fn hottest_blocks(&self, n: usize) -> Vec<(u64, u16, u32)> {
+        let mut blocks = self.weighted_blocks();
+        if n < blocks.len() {
+            blocks.select_nth_unstable_by_key(n, weight_order);
+            blocks.truncate(n);
+        }
+        blocks.sort_by_key(weight_order);
+        blocks
+    }
+
+    /// Returns the distinct blocks with their counts as absolute address, size and count, unsorted.
+    fn weighted_blocks(&self) -> Vec<(u64, u16, u32)> {
+        self.distinct_counts()
+            .into_iter()
+            .map(|(entry, count)| {
+                let base = self.modules[usize::from(entry.mod_id)].base;
+                let count = u32::try_from(count).unwrap_or(u32::MAX);
+                (base.wrapping_add(u64::from(entry.start)), entry.size, count)
+            })
+            .collect()
+    }
+
+    /// Returns each distinct entry with its total count, in the order it was first recorded.
+    ///
+    /// The count is the hit count if hit counting is enabled and otherwise the number
+    /// of times the entry was recorded.
+    pub(crate) fn distinct_counts(&self) -> Vec<(BlockEntry, u64)> {
+        let mut blocks: Vec<(BlockEntry, u64)> = Vec::new();
+        let mut index = HashMap::<BlockEntry, usize>::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            let count = u64::from(self.hit_counts().map_or(1, |counts| counts[i]));
+            match index.entry(*entry) {
+                Entry::Occupied(slot) => blocks[*slot.get()].1 += count,
+                Entry::Vacant(slot) => {
+                    slot.insert(blocks.len());
+                    blocks.push((*entry, count));
+                }
+            }
+        }
+        blocks
+    }
+
+    /// Returns a trace keeping a random subset of the distinct blocks of this trace.
+    ///
+    /// Each distinct block is kept with probability `fraction`, clamped to `0.0..=1.0`,
+    /// and is listed once in the order it was first recorded. The module table is
+    /// kept as is. The same seed always selects the same blocks.
+    pub fn sample(&self, fraction: f64, seed: u64) -> Trace {
+        let fraction = fraction.clamp(0.0, 1.0);
+        let mut state = seed;
+        let mut seen = HashSet::new();
+
+        let entries = self
+            .entries
+            .iter()
+            .filter(|&&entry| seen.insert(entry))
+            // The top 53 bits of the random number give a uniform float in 0..1.
+            .filter(|_| (splitmix64(&mut state) >> 11) as f64 / ((1u64 << 53) as f64) < fraction)
+            .copied()
+            .collect();
+
+        self.with_entries(entries)
+    }
+}
+
+/// Returns the approximate number of heap bytes held by a hash map, with one control byte per slot.
+pub(crate) fn map_heap_size<K, V>(map: &HashMap<K, V>) -> usize {
+    map.capacity() * (size_of::<(K, V)>() + 1)
+}
+
+/// Orders weighted blocks by descending count, then by address and size.
+fn weight_order(&(address, size, count): &(u64, u16, u32)) -> (std::cmp::Reverse<u32>, u64, u16) {
+    (std::cmp::Reverse(count), address, size)
+}
+
+/// Generate the next number of a SplitMix64 sequence.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn covered_bytes_unions_blocks() {
+        let mut trace = Trace::new(&[
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("libc.so", 0x5000, 0x6000),
+        ]);
+        trace.add(0x1010, 0x10);
+        trace.add(0x1018, 0x10);
+        trace.add(0x1028, 0x8);
+        trace.add(0x1010, 0x10);
+        trace.add(0x1100, 0x4);
+        trace.add(0x5000, 0x2);
+
+        assert_eq!(trace.covered_bytes(0), 0x24);
+        assert_eq!(trace.covered_bytes(1), 0x2);
+        assert_eq!(trace.covered_bytes(2), 0);
+
+        let bitmap = trace.byte_coverage(0);
+        assert_eq!(bitmap.len(), 0x200);
+        assert_eq!(bitmap[1..7], [0, 0xff, 0xff, 0xff, 0xff, 0]);
+        assert_eq!(bitmap[0x20], 0x0f);
+        let set = bitmap
+            .iter()
+            .map(|b| u64::from(b.count_ones()))
+            .sum::<u64>();
+        assert_eq!(set, trace.covered_bytes(0));
+        assert!(trace.byte_coverage(2).is_empty());
+        assert_eq!(trace.modules()[0].size(), 0x1000);
+
+        assert_eq!(
+            trace.module_coverage_ratio(),
+            [
+                (0, 0x24 as f64 / 0x1000 as f64),
+                (1, 0x2 as f64 / 0x1000 as f64)
+            ]
+        );
+    }
+
+    #[test]
+    fn module_coverage_ratio_ignores_overhang() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x1010)]);
+        trace.add(0x1000, 0x8);
+        trace.add(0x100c, 0x40);
+
+        assert_eq!(trace.covered_bytes(0), 0xc);
+        assert_eq!(trace.module_coverage_ratio(), [(0, 0.75)]);
+    }
+
+    #[test]
+    fn module_coverage_ratio_skips_empty_modules() {
+        let mut trace = Trace::new(&[
+            Module::new("empty", 0x1000, 0x2000),
+            Module::new("abcd", 0x4000, 0x4010),
+        ]);
+        trace.modules[0].end = 0x1000;
+        trace.add(0x4000, 0x4);
+
+        assert_eq!(trace.module_coverage_ratio(), [(1, 0.25)]);
+    }
+
+    #[test]
+    fn uncovered_modules() {
+        let mut trace = Trace::new(&[
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("libc.so", 0x5000, 0x6000),
+            Module::new("libm.so", 0x8000, 0x9000),
+        ]);
+        trace.add(0x5010, 4);
+
+        let names = trace
+            .uncovered_modules()
+            .into_iter()
+            .map(Module::name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["abcd", "libm.so"]);
+    }
+
+    #[test]
+    fn approx_memory_usage_grows_with_entries() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x100000)]);
+        let empty = trace.approx_memory_usage();
+        assert!(empty >= size_of::<Trace>() + size_of::<Module>());
+
+        for i in 0..1000 {
+            trace.add(0x1000 + i * 8, 8);
+        }
+        let recorded = trace.approx_memory_usage();
+        assert!(recorded >= empty + 1000 * BlockEntry::SIZE);
+
+        trace.track_hit_counts();
+        assert!(trace.approx_memory_usage() > recorded);
+        trace.rename_module(0, "a".repeat(100));
+        assert!(trace.approx_memory_usage() > recorded + 100);
+    }
+
+    #[test]
+    fn approx_edges_from_recording_order() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x2000)]);
+        for address in [0x1000, 0x1010, 0x1000, 0x1010, 0x1020, 0x1000] {
+            trace.add(address, 4);
+        }
+        assert_eq!(trace.approx_edges(), 4);
+
+        let edges = trace.approx_edge_set();
+        let (first, second) = (trace.entries()[0], trace.entries()[1]);
+        assert!(edges.contains(&(first, second)));
+        assert!(edges.contains(&(second, first)));
+        assert!(!edges.contains(&(first, first)));
+
+        trace.dedup();
+        assert_eq!(trace.approx_edges(), 2);
+    }
+
+    #[test]
+    fn weighted_blocks_by_count() {
+        let mut trace = Trace::new(&[
+            Module::new("abcd", 0x1000, 0x2000),
+            Module::new("libc.so", 0x5000, 0x6000),
+        ]);
+        trace.add(0x1010, 4);
+        trace.add(0x5020, 8);
+        trace.add(0x1000, 2);
+        trace.add(0x5020, 8);
+
+        assert_eq!(
+            trace.to_weighted_blocks(),
+            [(0x5020, 8, 2), (0x1000, 2, 1), (0x1010, 4, 1)]
+        );
+        assert_eq!(trace.hottest_blocks(2), [(0x5020, 8, 2), (0x1000, 2, 1)]);
+        assert_eq!(trace.hottest_blocks(5), trace.to_weighted_blocks());
+        assert!(trace.hottest_blocks(0).is_empty());
+
+        let mut trace = Trace::new(&[Module::new(
+            "top",
+            0xffff_ffff_ffff_f000,
+            0xffff_ffff_ffff_ffff,
+        )]);
+        trace.push_raw(0, 0x10_0000, 4).unwrap();
+        assert_eq!(trace.to_weighted_blocks(), [(0xf_f000, 4, 1)]);
+    }
+
+    #[test]
+    fn sample_is_deterministic() {
+        let mut trace = Trace::new(&[Module::new("abcd", 0x1000, 0x100000)]);
+        for i in 0..10000 {
+            trace.add(0x1000 + i * 8, 8);
+            trace.add(0x1000 + i * 8, 8);
+        }
+
+        let sample = trace.sample(0.25, 42);
+        assert_eq!(sample.modules(), trace.modules());
+        assert_eq!(sample.entries(), trace.sample(0.25, 42).entries());
+        assert_ne!(sample.entries(), trace.sample(0.25, 43).entries());
+        assert!((2200..2800).contains(&sample.entries().len()));
+
+        assert_eq!(trace.sample(1.0, 0).entries().len(), 10000);
+        assert!(trace.sample(0.0, 0).entries().is_empty());
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+use std::ops::Range;
+use std::path::Path;
+
+use object::{Object, ObjectSection, SectionKind};
+
+use crate::Trace;
+
+impl Trace {
+    /// Returns the offsets of the blocks of the module `mod_id` that are not within an executable section of the binary.
+    ///
+    /// Block offsets are taken relative to the start of the image, so the module's
+    /// image offset is added for later segments. Each offset is listed once, in the
+    /// order it was first recorded. An empty result means that the recorded blocks
+    /// are consistent with the binary, while a mis-based module typically reports
+    /// most of its blocks.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if there is no module with the given id.
+    pub fn validate_against_binary(
+        &self,
+        mod_id: usize,
+        binary_path: impl AsRef<Path>,
+    ) -> Result<Vec<u32>, Error> {
+        let module = &self.modules[mod_id];
+        let data = std::fs::read(binary_path)?;
+        let file =
+            object::File::parse(&*data).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+        let base = file.relative_address_base();
+        let executable = file
+            .sections()
+            .filter(|section| section.kind() == SectionKind::Text)
+            .map(|section| {
+                let start = section.address().wrapping_sub(base);
+                start..start.saturating_add(section.size())
+            })
+            .collect::<Vec<Range<u64>>>();
+
+        let mut seen = HashSet::new();
+        let invalid = self
+            .entries
+            .iter()
+            .filter(|entry| usize::from(entry.mod_id) == mod_id)
+            .filter(|entry| {
+                let start = module.offset + u64::from(entry.start);
+                let end = start + u64::from(entry.size);
+                !executable
+                    .iter()
+                    .any(|section| section.start <= start && end <= section.end)
+            })
+            .map(|entry| entry.start)
+            .filter(|&start| seen.insert(start))
+            .collect();
+
+        Ok(invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    #[test]
+    fn validate_against_test_binary() {
+        let path = std::env::current_exe().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        let file = object::File::parse(&*data).unwrap();
+        let base = file.relative_address_base();
+        let offset = |kind| {
+            let section = file.sections().find(|s| s.kind() == kind).unwrap();
+            section.address() - base
+        };
+
+        let (text, data) = (offset(SectionKind::Text), offset(SectionKind::Data));
+        let mut trace = Trace::new(&[Module::new("test", 0x10000, 0x10000 + (1 << 31))]);
+        trace.add(0x10000 + text, 4);
+        trace.add(0x10000 + data, 4);
+        trace.add(0x10000 + data, 4);
+
+        assert_eq!(
+            trace.validate_against_binary(0, &path).unwrap(),
+            [data as u32]
+        );
+        assert!(trace.validate_against_binary(0, "/nonexistent").is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use crate::{index, Trace};
+
+/// A single internal consistency problem found by [`Trace::verify`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Issue {
+    /// The entry at `entry` refers to a module id that is not in the module table.
+    InvalidModuleId {
+        /// Index of the offending entry.
+        entry: usize,
+        /// The module id stored in the entry.
+        mod_id: u16,
+    },
+    /// The entry at `entry` extends past the end of its module.
+    EntryOutOfBounds {
+        /// Index of the offending entry.
+        entry: usize,
+        /// The module id stored in the entry.
+        mod_id: u16,
+    },
+    /// The address ranges of two modules overlap.
+    OverlappingModules {
+        /// Id of the first module.
+        first: usize,
+        /// Id of the second module.
+        second: usize,
+    },
+    /// The module has an empty name.
+    EmptyModuleName {
+        /// Id of the offending module.
+        mod_id: usize,
+    },
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Issue::InvalidModuleId { entry, mod_id } => {
+                write!(f, "entry {entry} refers to unknown module id {mod_id}")
+            }
+            Issue::EntryOutOfBounds { entry, mod_id } => {
+                write!(f, "entry {entry} extends past the end of module {mod_id}")
+            }
+            Issue::OverlappingModules { first, second } => {
+                write!(f, "modules {first} and {second} overlap")
+            }
+            Issue::EmptyModuleName { mod_id } => write!(f, "module {mod_id} has an empty name"),
+        }
+    }
+}
+
+impl Trace {
+    /// Check the internal consistency of the trace.
+    ///
+    /// Every problem found is reported rather than stopping at the first one.
+    pub fn verify(&self) -> Result<(), Vec<Issue>> {
+        let mut issues = Vec::new();
+
+        for (mod_id, module) in self.modules.iter().enumerate() {
+            if module.name.is_empty() {
+                issues.push(Issue::EmptyModuleName { mod_id });
+            }
+
+            for (other_id, other) in self.modules.iter().enumerate().skip(mod_id + 1) {
+                if module.base < other.end && other.base < module.end {
+                    issues.push(Issue::OverlappingModules {
+                        first: mod_id,
+                        second: other_id,
+                    });
+                }
+            }
+        }
+
+        for (index, entry) in self.entries.iter().enumerate() {
+            match self.modules.get(usize::from(entry.mod_id)) {
+                None => issues.push(Issue::InvalidModuleId {
+                    entry: index,
+                    mod_id: entry.mod_id,
+                }),
+                Some(module) => {
+                    let end = u64::from(entry.start) + u64::from(entry.size);
+                    if end > module.end - module.base {
+                        issues.push(Issue::EntryOutOfBounds {
+                            entry: index,
+                            mod_id: entry.mod_id,
+                        });
+                    }
+                }
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+
+    /// Returns true if every address in `addresses` is covered by a recorded block.
+    ///
+    /// An address is covered if it falls within `[start, start + size)` of a
+    /// recorded block, with the start taken relative to the block's module.
+    pub fn covers_all(&self, addresses: &[u64]) -> bool {
+        self.uncovered(addresses).is_empty()
+    }
+
+    /// Assert that every address in `addresses` is covered by a recorded block, as by [`Trace::covers_all`].
+    ///
+    /// # Panics
+    ///
+    /// This function will panic with a list of the uncovered addresses if any
+    /// address is not covered.
+    #[track_caller]
+    pub fn assert_covers(&self, addresses: &[u64]) {
+        let uncovered = self.uncovered(addresses);
+        if !uncovered.is_empty() {
+            let list = uncovered
+                .iter()
+                .map(|address| format!("{address:#x}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            panic!(
+                "{} of {} addresses are not covered: {list}",
+                uncovered.len(),
+                addresses.len()
+            );
+        }
+    }
+
+    /// Returns the addresses in `addresses` that are not covered by a recorded block, in order.
+    fn uncovered(&self, addresses: &[u64]) -> Vec<u64> {
+        let covered = index::merge_ranges(
+            self.entries
+                .iter()
+                .filter_map(|entry| {
+                    let module = self.modules.get(usize::from(entry.mod_id))?;
+                    let start = module.base.checked_add(u64::from(entry.start))?;
+                    Some(start..start.saturating_add(u64::from(entry.size)))
+                })
+                .collect(),
+        );
+        addresses
+            .iter()
+            .copied()
+            .filter(|&address| {
+                let index = covered.partition_point(|range| range.start <= address);
+                index == 0 || address >= covered[index - 1].end
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BlockEntry, Module};
+
+    #[test]
+    fn covers_addresses() {
+        let mut trace = Trace::new(&[Module::new("abcd.so", 0x1000, 0x2000)]);
+        trace.add(0x1010, 8);
+        trace.add(0x1018, 8);
+        trace.add(0x1100, 4);
+
+        assert!(trace.covers_all(&[0x1010, 0x101f, 0x1103]));
+        assert!(!trace.covers_all(&[0x1010, 0x1020]));
+        trace.assert_covers(&[0x1100]);
+
+        let message = std::panic::catch_unwind(|| trace.assert_covers(&[0x1000, 0x1014, 0x1104]))
+            .unwrap_err()
+            .downcast::<String>()
+            .unwrap();
+        assert_eq!(*message, "2 of 3 addresses are not covered: 0x1000, 0x1104");
+    }
+
+    #[test]
+    fn verify_valid_trace() {
+        let mut trace = Trace::new(&[Module::new("abcd.so", 0x1000, 0x2000)]);
+        trace.add(0x1234, 16);
+
+        assert_eq!(trace.verify(), Ok(()));
+    }
+
+    #[test]
+    fn verify_reports_every_issue() {
+        let mut trace = Trace::new(&[
+            Module::new("", 0x1000, 0x2000),
+            Module::new("libc.so", 0x1800, 0x3000),
+        ]);
+        trace.entries.push(BlockEntry {
+            start: 0xff0,
+            size: 0x20,
+            mod_id: 0,
+        });
+        trace.entries.push(BlockEntry {
+            start: 0,
+            size: 1,
+            mod_id: 7,
+        });
+
+        assert_eq!(
+            trace.verify(),
+            Err(vec![
+                Issue::EmptyModuleName { mod_id: 0 },
+                Issue::OverlappingModules {
+                    first: 0,
+                    second: 1
+                },
+                Issue::EntryOutOfBounds {
+                    entry: 0,
+                    mod_id: 0
+                },
+                Issue::InvalidModuleId {
+                    entry: 1,
+                    mod_id: 7
+                },
+            ])
+        );
+    }
+}